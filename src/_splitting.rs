@@ -14,18 +14,70 @@
 //!   - `Bam`: Emit BAM files as output.
 //!   - `Fastq`: Emit FASTQ files as output.
 //!   - `Fasta`: Emit FASTA files as output.
+//!   - `Sam`: Emit SAM files as output.
+//!   - `Cram`: Emit CRAM files as output, compressed against a reference.
 //!
 //! - [`Wrapper`](enum.Wrapper.html): Enum representing different output wrappers for split files.
-//!   - `Bam`: Wrapper for BAM output.
-//!   - `Fastx`: Wrapper for generic FASTX output.
+//!   - `Bam`: Wrapper for BAM output, and its index if one is built.
+//!   - `Fastx`: Wrapper for FASTX output, compressed as chosen.
+//!   - `Sam`: Wrapper for SAM output, compressed as chosen.
+//!   - `Cram`: Wrapper for CRAM output.
+//!
+//! - [`UnblockSource`](enum.UnblockSource.html): Enum representing where the unblocked read IDs come from.
+//!   - `ReadIds`: An unblocked_read_ids.txt file, or an index built by `index-ids`.
+//!   - `Summary`: A MinKNOW sequencing summary, using the `end_reason` column.
+//!   - `Decisions`: A readfish chunk log, using the final `decision` for each read.
+//!
+//! # Structs
+//!
+//! - [`LabelledSource`](struct.LabelledSource.html): An `UnblockSource` with the label reported for it.
+//! - [`UnblockArgs`](struct.UnblockArgs.html): Command line arguments choosing the unblocked read IDs, shared by the split commands.
+//! - [`IdListReport`](struct.IdListReport.html): Counts of the lines read from an unblocked read IDs file.
 //!
 //! # Functions
 //!
-//! - [`read_unblocked_read_ids`](fn.read_unblocked_read_ids.html): Read unblocked read IDs from a file into a HashSet.
+//! - [`open_compressed`](fn.open_compressed.html): Open a text file that may be gzip, BGZF or zstd compressed.
+//!   - Arguments:
+//!     - `path`: The path to the (possibly compressed) file.
+//!   - Returns:
+//!     - `Result`: A `Result` containing a boxed `BufRead` or an `io::Error`.
+//!
+//! - [`open_input`](fn.open_input.html): Open the input of a split command, which may be `-` for standard input.
+//!   - Arguments:
+//!     - `path`: The path to the (possibly compressed) file, or `-`.
+//!   - Returns:
+//!     - `Result`: A `Result` containing a boxed `BufRead` or an `io::Error`.
+//!
+//! - [`is_stdio`](fn.is_stdio.html): Whether a path given on the command line is `-`, for standard input.
+//!
+//! - [`value_name`](fn.value_name.html): The command line name of an option value, e.g. `sequenced-only`.
+//!
+//! - [`decompress`](fn.decompress.html): Wrap a reader in a gzip or zstd decoder if its contents are compressed.
+//!
+//! - [`parse_read_id_lines`](fn.parse_read_id_lines.html): Validate the lines of an unblocked read IDs file.
+//!   - Arguments:
+//!     - `reader`: The (decompressed) file contents.
+//!     - `path`: The path to the file, for diagnostics.
+//!     - `lenient`: Skip and count bad lines, rather than failing.
+//!     - `insert`: Called with every read ID, packed into a `u128`, and may fail.
+//!   - Returns:
+//!     - `Result`: A `Result` containing an `IdListReport` or an `io::Error` describing the bad lines.
+//!
+//! - [`read_unblocked_read_ids`](fn.read_unblocked_read_ids.html): Read unblocked read IDs from a file or index.
 //!   - Arguments:
-//!     - `path`: The path to the unblocked_read_ids.txt file.
+//!     - `path`: The path to the unblocked_read_ids.txt file, or an index built by `rftools index-ids`.
+//!     - `lenient`: Skip and count lines which are not read IDs, rather than failing.
 //!   - Returns:
-//!     - `Result`: A `Result` containing a `FnvHashSet<String>` or an `io::Error`.
+//!     - `Result`: A `Result` containing `ReadIds` or an `io::Error`.
+//!
+//! - [`read_unblocked_from_summary`](fn.read_unblocked_from_summary.html): Read unblocked read IDs from a sequencing summary.
+//!   - Arguments:
+//!     - `path`: The path to the sequencing_summary.txt file.
+//!     - `end_reasons`: The end reasons which count as an unblock.
+//!   - Returns:
+//!     - `Result`: A `Result` containing a `ReadIdSet` or an `io::Error`.
+//!
+//! - [`get_key_col`](fn.get_key_col.html): Find the index of a named column in a TSV header.
 //!
 //! - [`_ave_qual`](fn._ave_qual.html): Calculate the average quality of a read.
 //!   - Arguments:
@@ -33,20 +85,34 @@
 //!   - Returns:
 //!     - `f64`: Average quality of the read.
 //!
-use clap::ValueEnum;
-use flate2::write::GzEncoder;
-use fnv::FnvHashSet;
-use noodles::bam;
+use crate::bam_index::BamIndexer;
+use crate::compress::CompressedWriter;
+use crate::decisions::read_decision_log;
+use crate::id_index::{is_index, ReadIdIndex};
+use crate::output::OutputFile;
+use crate::read_ids::{ReadIdSet, ReadIds, UnblockedReads, MAX_LISTS};
+use clap::{Args, ValueEnum};
+use flate2::read::MultiGzDecoder;
+use noodles::{bam, cram, sam};
 use noodles_bgzf as bgzf;
 
 use std::{
+    fmt,
     fs::File,
     io,
-    io::{BufRead, BufReader, BufWriter},
-    path::PathBuf,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::{self, FromStr},
 };
 
-/// Enum representing the type of reads output after splitting, (BAM file splitting only ATM).
+/// Magic bytes at the start of a gzip member. BGZF blocks are gzip members too.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Magic bytes at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Path which stands for standard input on the command line.
+pub const STDIO: &str = "-";
+
+/// Enum representing the type of reads output after splitting.
 #[derive(Debug, ValueEnum, Clone, Default, PartialEq)]
 pub enum SplitType {
     /// Output all reads.
@@ -68,45 +134,396 @@ pub enum EmitType {
     Fastq,
     /// Emit FASTA files as output.
     Fasta,
+    /// Emit SAM files as output.
+    Sam,
+    /// Emit CRAM files as output, compressed against the reference if the reads are aligned.
+    Cram,
 }
 
 /// Enum representing different file writers for BAM file output.
 pub enum Wrapper {
-    /// Wrapper for BAM output.
-    Bam(bam::io::Writer<bgzf::Writer<File>>),
-    /// Wrapper for generic FASTX output.
-    Fastx(BufWriter<File>),
-    /// Wrapper for a gzipped Fastx output
-    GzFastx(GzEncoder<BufWriter<File>>),
+    /// Wrapper for BAM output, and its index if one is built while it is written.
+    Bam(
+        bam::io::Writer<bgzf::Writer<OutputFile>>,
+        Option<BamIndexer>,
+    ),
+    /// Wrapper for FASTX output, compressed as chosen.
+    Fastx(CompressedWriter),
+    /// Wrapper for SAM output, compressed as chosen.
+    Sam(sam::io::Writer<CompressedWriter>),
+    /// Wrapper for CRAM output.
+    Cram(cram::io::Writer<OutputFile>),
 }
 
-/// Enum for the compression type, currently only Gzipped or Uncompressed
+/// Enum for the compression type of the outputs, other than BAM
 #[derive(Debug, ValueEnum, Clone, Default, PartialEq)]
 pub enum CompressionType {
     /// Gzip files if they are FASTX
     #[default]
     Gzipped,
-    /// Bgzipped
+    /// BGZF compress FASTX files, so they can be indexed with samtools faidx or fqidx
     Bgzipped,
-    /// Emit FASTQ files as output.
+    /// Leave the outputs uncompressed
     Uncompressed,
+    /// zstd compress the outputs
+    Zstd,
+    /// xz compress the outputs
+    Xz,
+}
+
+/// Where the set of unblocked read IDs is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnblockSource {
+    /// An unblocked_read_ids.txt file from readfish, or an index built by `index-ids`.
+    ReadIds(PathBuf),
+    /// A sequencing_summary.txt file from MinKNOW, unblocks are found from the `end_reason` column.
+    Summary(PathBuf),
+    /// A readfish chunk log, reads whose final `decision` was unblock are unblocked.
+    Decisions(PathBuf),
+}
+
+impl FromStr for UnblockSource {
+    type Err = String;
+
+    /// Parse `ids:<path>`, `summary:<path>` or `decisions:<path>`. A bare path is an unblocked read IDs file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("unblock source must not be empty".to_owned());
+        }
+        match s.split_once(':') {
+            Some(("ids", path)) => Ok(UnblockSource::ReadIds(PathBuf::from(path))),
+            Some(("summary", path)) => Ok(UnblockSource::Summary(PathBuf::from(path))),
+            Some(("decisions", path)) => Ok(UnblockSource::Decisions(PathBuf::from(path))),
+            _ => Ok(UnblockSource::ReadIds(PathBuf::from(s))),
+        }
+    }
+}
+
+/// An [`UnblockSource`] together with the label used to report which list a rejection came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelledSource {
+    /// Label reported for the source, e.g. the flowcell position.
+    pub label: String,
+    /// Where the unblocked read IDs are read from.
+    pub source: UnblockSource,
+}
+
+/// Split an optional `LABEL=` prefix from a command line value. A prefix containing a path
+/// separator is part of the path, not a label.
+fn split_label(s: &str) -> (Option<&str>, &str) {
+    match s.split_once('=') {
+        Some((label, rest)) if !label.is_empty() && !label.contains(std::path::is_separator) => {
+            (Some(label), rest)
+        }
+        _ => (None, s),
+    }
+}
+
+impl FromStr for LabelledSource {
+    type Err = String;
+
+    /// Parse `[LABEL=]<source>`, where `<source>` is parsed as an [`UnblockSource`].
+    /// Without a label the whole value is used as the label.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, source) = split_label(s);
+        Ok(LabelledSource {
+            label: label.unwrap_or(s).to_owned(),
+            source: source.parse()?,
+        })
+    }
+}
+
+/// Parse `[LABEL=]<path>` for `--unblocked-read-ids`. The path is always a read IDs file.
+fn parse_labelled_read_ids(s: &str) -> Result<LabelledSource, String> {
+    let (label, path) = split_label(s);
+    if path.is_empty() {
+        return Err("unblocked read ids path must not be empty".to_owned());
+    }
+    Ok(LabelledSource {
+        label: label.unwrap_or(s).to_owned(),
+        source: UnblockSource::ReadIds(PathBuf::from(path)),
+    })
+}
+
+// Each command requires one of these through an `ArgGroup`, which `split-fq` and `split-ss` extend
+// with their positional unblocked read IDs file. A second doc paragraph would replace the about of
+// the commands flattening this.
+/// Command line arguments choosing the unblocked read IDs, shared by all of the split commands.
+#[derive(Debug, Args, Clone, PartialEq)]
+pub struct UnblockArgs {
+    /// Unblocked read ids file from readfish, or an index built by index-ids. Can be repeated,
+    /// optionally labelled as LABEL=<path> to report which list each rejection came from
+    #[clap(short, long, parse(try_from_str = parse_labelled_read_ids), multiple_occurrences = true)]
+    pub unblocked_read_ids: Vec<LabelledSource>,
+    /// Alternative source of the unblocked read ids, one of ids:<path>, summary:<path> or decisions:<path>.
+    /// summary:<path> takes the reads with an unblock end_reason from a MinKNOW sequencing summary,
+    /// decisions:<path> takes the final decision for each read from a readfish chunk log.
+    /// Can be repeated, and labelled as LABEL=<source>
+    #[clap(long, multiple_occurrences = true)]
+    pub unblock_source: Vec<LabelledSource>,
+    /// end_reason values which count as an unblock when using summary:<path>. Can be repeated
+    #[clap(long, default_value = "data_service_unblock_mux_change", multiple_occurrences = true)]
+    pub unblock_end_reason: Vec<String>,
+    /// Skip and count lines of the unblocked read ids files which are not read ids, rather
+    /// than stopping with an error
+    #[clap(long)]
+    pub lenient: bool,
+}
+
+impl UnblockArgs {
+    /// All of the chosen sources of unblocked read IDs, `--unblocked-read-ids` first.
+    pub fn sources(&self) -> impl Iterator<Item = &LabelledSource> {
+        self.unblocked_read_ids.iter().chain(&self.unblock_source)
+    }
+
+    /// Use the positional unblocked read IDs file of `split-fq` and `split-ss` if neither
+    /// `--unblocked-read-ids` nor `--unblock-source` was given. If one was, the positional
+    /// argument is really the first input, as the file is optional.
+    ///
+    /// # Returns
+    ///
+    /// Returns the positional argument back if it is an input rather than the read IDs file.
+    pub fn take_positional(&mut self, path: Option<PathBuf>) -> Option<PathBuf> {
+        match path {
+            Some(path) if self.sources().next().is_none() => {
+                self.unblocked_read_ids.push(LabelledSource {
+                    label: path.display().to_string(),
+                    source: UnblockSource::ReadIds(path),
+                });
+                None
+            }
+            path => path,
+        }
+    }
+
+    /// Load the unblocked read IDs from a single source.
+    fn load_source(&self, source: &UnblockSource) -> Result<ReadIds, io::Error> {
+        match source.clone() {
+            UnblockSource::ReadIds(path) => read_unblocked_read_ids(path, self.lenient),
+            UnblockSource::Summary(path) => Ok(ReadIds::InMemory(read_unblocked_from_summary(
+                path,
+                &self.unblock_end_reason,
+            )?)),
+            UnblockSource::Decisions(path) => Ok(ReadIds::Decisions(read_decision_log(path)?)),
+        }
+    }
+
+    /// Load the unblocked read IDs from every chosen source, keeping each list separate.
+    pub fn load(&self) -> Result<UnblockedReads, io::Error> {
+        let mut lists = vec![];
+        for LabelledSource { label, source } in self.sources() {
+            if lists.len() == MAX_LISTS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("at most {} unblocked read id lists can be given", MAX_LISTS),
+                ));
+            }
+            lists.push((label.clone(), self.load_source(source)?));
+        }
+        Ok(UnblockedReads::new(lists))
+    }
+
+    /// Load the unblocked read IDs as [`load`](#method.load) does, reporting the memory they
+    /// take, putting a Bloom filter with false positive rate `bloom_fpr` in front of them if it
+    /// is set, and warning if none were loaded. The report and warning go to standard error.
+    pub fn load_reported(&self, bloom_fpr: Option<f64>) -> Result<UnblockedReads, io::Error> {
+        let mut unblocked = self.load()?;
+        eprintln!("{}", unblocked.memory_report());
+        if let Some(fpr) = bloom_fpr {
+            unblocked.build_prefilter(fpr)?;
+        }
+        if unblocked.is_empty() {
+            eprintln!("Warning: no unblocked read ids were loaded, every read will be sequenced");
+        }
+        Ok(unblocked)
+    }
+}
+
+/// Open a text file for buffered reading, transparently decompressing it if required.
+///
+/// The compression is detected from the magic bytes rather than the file extension, so
+/// gzip (`.gz`), BGZF (`.bgz`) and zstd (`.zst`) files are all handled, as is plain text.
+/// BGZF is a series of concatenated gzip members, so it is read with a `MultiGzDecoder`.
+///
+/// # Arguments
+///
+/// * `path` - The path to the file to open.
+///
+/// # Returns
+///
+/// Returns a `Result` containing a boxed `BufRead` over the decompressed contents or an `io::Error`.
+pub fn open_compressed(path: &Path) -> Result<Box<dyn BufRead + Send>, io::Error> {
+    decompress(BufReader::new(File::open(path)?))
+}
+
+/// Open the input of a split command, a file, or standard input if the path is `-`,
+/// transparently decompressing it as [`open_compressed`] does.
+pub fn open_input(path: &Path) -> Result<Box<dyn BufRead + Send>, io::Error> {
+    if is_stdio(path) {
+        decompress(BufReader::new(io::stdin()))
+    } else {
+        open_compressed(path)
+    }
+}
+
+/// Whether a path given on the command line is `-`, which stands for standard input.
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+/// The name of an option value as it is given on the command line, e.g. `sequenced-only`.
+pub fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_owned())
+}
+
+/// Wrap a reader in a decoder if it starts with the magic bytes of gzip or zstd.
+pub fn decompress<R: BufRead + Send + 'static>(
+    mut reader: R,
+) -> Result<Box<dyn BufRead + Send>, io::Error> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        )))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Number of bad lines shown in diagnostics.
+const MAX_BAD_LINES_SHOWN: usize = 5;
+
+/// Counts of the lines read from an unblocked read IDs file by [`parse_read_id_lines`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IdListReport {
+    /// Lines holding a read ID.
+    pub ids: usize,
+    /// Empty, or whitespace only, lines.
+    pub blank: usize,
+    /// Lines which are not a read ID.
+    pub bad: usize,
+    /// Line number and contents of the first few bad lines.
+    examples: Vec<(usize, String)>,
+}
+
+impl fmt::Display for IdListReport {
+    /// Describe the bad lines, e.g. `2 lines are not read ids, line 1: "read_id", ...`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bad {
+            1 => write!(f, "1 line is not a read id")?,
+            n => write!(f, "{} lines are not read ids", n)?,
+        }
+        for (line_number, line) in &self.examples {
+            write!(f, ", line {}: {:?}", line_number, line)?;
+        }
+        if self.bad > self.examples.len() {
+            write!(f, ", ...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Validate the lines of an unblocked read IDs file, passing each read ID to `insert`.
+///
+/// Leading and trailing whitespace, including the `\r` of Windows line endings, is trimmed,
+/// and blank lines are skipped. Every other line must be a read ID: valid UTF-8 without
+/// whitespace or control characters, and not a `read_id` header, so that header lines, stray
+/// text or invalid UTF-8 can't silently end up in the set and never match. Read IDs which are
+/// not UUIDs, e.g. from `rftools ids` on reads not basecalled by ONT, are passed on as they are.
+///
+/// # Arguments
+///
+/// * `reader` - The (decompressed) contents of the file.
+/// * `path` - The path of the file, used in diagnostics.
+/// * `lenient` - If `true`, bad lines are skipped and counted, with a warning. If `false`, any bad
+///   line is an error.
+/// * `insert` - Called with each read ID. An error stops parsing and is returned.
+///
+/// # Returns
+///
+/// Returns a `Result` containing an `IdListReport`, or an `io::Error` which gives the line numbers
+/// of the bad lines.
+pub fn parse_read_id_lines<R: BufRead, F: FnMut(&[u8]) -> Result<(), io::Error>>(
+    mut reader: R,
+    path: &Path,
+    lenient: bool,
+    mut insert: F,
+) -> Result<IdListReport, io::Error> {
+    let mut report = IdListReport::default();
+    let mut line = Vec::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let id = line.trim_ascii();
+        if id.is_empty() {
+            report.blank += 1;
+            continue;
+        }
+        if is_read_id(id) {
+            report.ids += 1;
+            insert(id)?;
+        } else {
+            report.bad += 1;
+            if report.examples.len() < MAX_BAD_LINES_SHOWN {
+                let id = String::from_utf8_lossy(id).into_owned();
+                report.examples.push((line_number, id));
+            }
+        }
+    }
+    if report.bad > 0 {
+        if !lenient {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: {}. Use --lenient to skip them",
+                    path.display(),
+                    report
+                ),
+            ));
+        }
+        eprintln!("Warning: skipped {}: {}", path.display(), report);
+    }
+    Ok(report)
 }
 
-/// Read unblocked_read_ids.txt into a HashSet.
+/// Whether a trimmed line can be a read ID: valid UTF-8 without whitespace or control
+/// characters, and not the `read_id` header of a list written with metadata columns.
+fn is_read_id(id: &[u8]) -> bool {
+    id != b"read_id"
+        && str::from_utf8(id)
+            .is_ok_and(|id| !id.chars().any(|c| c.is_whitespace() || c.is_control()))
+}
+
+/// Read unblocked_read_ids.txt into a [`ReadIdSet`].
+///
+/// The file may be plain text or gzip, BGZF or zstd compressed, see [`open_compressed`].
+/// If it is an index built by `rftools index-ids` it is opened for lookups on disk instead,
+/// so memory use stays bounded however many read IDs there are.
+///
+/// Lines are validated by [`parse_read_id_lines`].
 ///
 /// # Arguments
 ///
-/// * `path` - The path to the unblocked_read_ids.txt file.
+/// * `path` - The path to the unblocked_read_ids.txt file, or read ID index.
+/// * `lenient` - Skip and count lines which are not read IDs, rather than failing.
 ///
 /// # Returns
 ///
-/// Returns a `Result` containing a `FnvHashSet<String>` or an `io::Error`.
+/// Returns a `Result` containing `ReadIds` or an `io::Error`.
 ///
 /// /// # Example
 ///
 /// ```rust,ignore
 ///    let unblocked_read_ids = PathBuf::from("tests/test_unblocked.txt");
-///    match read_unblocked_read_ids(unblocked_read_ids) {
+///    match read_unblocked_read_ids(unblocked_read_ids, false) {
 ///        Ok(unb_set) => {
 ///            assert_eq!(unb_set.len(), 2);
 ///        }
@@ -115,21 +532,94 @@ pub enum CompressionType {
 ///        }
 ///    }
 /// ````
-pub fn read_unblocked_read_ids(path: PathBuf) -> Result<FnvHashSet<String>, io::Error> {
-    // let file = File::open(&path);
-    match File::open(path) {
-        Ok(file) => {
-            let reader = BufReader::new(file);
-            let rejected_reads: FnvHashSet<String> = reader
-                .lines()
-                .map(|l| l.expect("Couldn't read line"))
-                .collect();
-            Ok(rejected_reads)
+pub fn read_unblocked_read_ids(path: PathBuf, lenient: bool) -> Result<ReadIds, io::Error> {
+    if is_index(&path)? {
+        return Ok(ReadIds::OnDisk(ReadIdIndex::open(&path)?));
+    }
+    match open_compressed(&path) {
+        Ok(reader) => {
+            let mut rejected_reads = ReadIdSet::new();
+            parse_read_id_lines(reader, &path, lenient, |id| {
+                rejected_reads.insert(id);
+                Ok(())
+            })?;
+            Ok(ReadIds::InMemory(rejected_reads))
         }
         Err(err) => Err(err),
     }
 }
 
+/// Read the unblocked read IDs from a MinKNOW sequencing summary.
+///
+/// Every read whose `end_reason` is one of `end_reasons` counts as unblocked. This recovers the
+/// unblocked set if readfish's unblocked_read_ids.txt has been lost. The summary may be compressed.
+///
+/// # Arguments
+///
+/// * `path` - The path to the sequencing_summary.txt file.
+/// * `end_reasons` - The end reasons which count as an unblock, e.g. `data_service_unblock_mux_change`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing a `ReadIdSet` or an `io::Error`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let end_reasons = vec![String::from("data_service_unblock_mux_change")];
+/// let unb_set = read_unblocked_from_summary(PathBuf::from("sequencing_summary.txt"), &end_reasons)?;
+/// ```
+pub fn read_unblocked_from_summary(
+    path: PathBuf,
+    end_reasons: &[String],
+) -> Result<ReadIdSet, io::Error> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(open_compressed(&path)?);
+    let headers = rdr.byte_headers()?;
+    let column = |name: &str| {
+        get_key_col(headers, name.as_bytes()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    };
+    let (read_id_col, end_reason_col) = (column("read_id")?, column("end_reason")?);
+
+    let mut rejected_reads = ReadIdSet::new();
+    let mut row = csv::ByteRecord::new();
+    while rdr.read_byte_record(&mut row)? {
+        let end_reason = &row[end_reason_col];
+        if end_reasons.iter().any(|r| r.as_bytes() == end_reason) {
+            rejected_reads.insert(&row[read_id_col]);
+        }
+    }
+    Ok(rejected_reads)
+}
+
+/// Find the index of the column called `key` in the header of a TSV file.
+///
+/// # Arguments
+///
+/// * `first_line` - The header row.
+/// * `key` - The name of the column to find, e.g. `read_id`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the column index, or an error message if there is no such column.
+pub fn get_key_col(first_line: &csv::ByteRecord, key: &[u8]) -> Result<usize, String> {
+    for (i, field) in first_line.iter().enumerate() {
+        if field == key {
+            return Ok(i);
+        }
+    }
+    Err(format!(
+        "Could not find field {}",
+        String::from_utf8_lossy(key)
+    ))
+}
+
 /// This function calculates the average quality of a read, and does this correctly
 /// First the Phred scores are converted to probabilities (10^(q)/-10) and summed
 /// and then divided by the number of bases/scores and converted to Phred again -10*log10(average)
@@ -170,7 +660,7 @@ mod tests {
     #[test]
     fn test_read_unblocked_read_ids() {
         let unblocked_read_ids = PathBuf::from("tests/test_unblocked.txt");
-        match read_unblocked_read_ids(unblocked_read_ids) {
+        match read_unblocked_read_ids(unblocked_read_ids, false) {
             Ok(unb_set) => {
                 assert_eq!(unb_set.len(), 2);
             }
@@ -183,7 +673,7 @@ mod tests {
     #[test]
     fn test_empty_read_unblocked_read_ids() {
         let unblocked_read_ids = PathBuf::from("tests/test_empty_unb_ids.txt");
-        match read_unblocked_read_ids(unblocked_read_ids) {
+        match read_unblocked_read_ids(unblocked_read_ids, false) {
             Ok(unb_set) => {
                 assert_eq!(unb_set.len(), 0);
             }
@@ -192,6 +682,119 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn test_read_compressed_unblocked_read_ids() {
+        for ext in ["gz", "bgz", "zst"] {
+            let unblocked_read_ids = PathBuf::from(format!("tests/test_unblocked.txt.{}", ext));
+            let unb_set = read_unblocked_read_ids(unblocked_read_ids, false).unwrap();
+            assert_eq!(unb_set.len(), 2);
+            assert!(unb_set.contains("0afabd54-d901-473f-94ba-c7a6e69716f5"));
+        }
+    }
+
+    #[test]
+    fn test_parse_read_id_lines() {
+        let path = Path::new("ids.txt");
+        let contents = "read_id\n0afabd54-d901-473f-94ba-c7a6e69716f5\r\n\n  2f50d29d-af9a-4acb-948d-c2b93ad310dd \nnot a read\nSRR1234.1\nread\x07\n";
+
+        let err = parse_read_id_lines(contents.as_bytes(), path, false, |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "ids.txt: 3 lines are not read ids, line 1: \"read_id\", line 5: \"not a read\", line 7: \"read\\u{7}\". Use --lenient to skip them"
+        );
+
+        let mut ids = vec![];
+        let report = parse_read_id_lines(contents.as_bytes(), path, true, |id| {
+            ids.push(String::from_utf8(id.to_vec()).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!((report.ids, report.blank, report.bad), (3, 1, 3));
+        // Read ids which aren't UUIDs are kept
+        assert_eq!(
+            ids,
+            [
+                "0afabd54-d901-473f-94ba-c7a6e69716f5",
+                "2f50d29d-af9a-4acb-948d-c2b93ad310dd",
+                "SRR1234.1"
+            ]
+        );
+
+        // Invalid UTF-8 is a bad line, not an I/O error
+        let report = parse_read_id_lines(&b"\xff\xfe\n"[..], path, true, |_| Ok(())).unwrap();
+        assert_eq!(report.bad, 1);
+    }
+
+    #[test]
+    fn test_read_unblocked_from_summary() {
+        let summary = PathBuf::from("tests/test_sequencing_summary.txt");
+        let end_reasons = vec![String::from("data_service_unblock_mux_change")];
+        let unb_set = read_unblocked_from_summary(summary.clone(), &end_reasons).unwrap();
+        assert_eq!(unb_set.len(), 2);
+        assert!(unb_set.contains("0afabd54-d901-473f-94ba-c7a6e69716f5"));
+
+        let end_reasons = vec![
+            String::from("data_service_unblock_mux_change"),
+            String::from("unblock_mux_change"),
+        ];
+        let unb_set = read_unblocked_from_summary(summary, &end_reasons).unwrap();
+        assert_eq!(unb_set.len(), 3);
+        assert!(unb_set.contains("8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e"));
+    }
+
+    #[test]
+    fn test_unblock_source_from_str() {
+        assert_eq!(
+            "summary:seq summary.txt".parse(),
+            Ok(UnblockSource::Summary(PathBuf::from("seq summary.txt")))
+        );
+        assert_eq!(
+            "decisions:readfish_chunks.tsv".parse(),
+            Ok(UnblockSource::Decisions(PathBuf::from("readfish_chunks.tsv")))
+        );
+        assert_eq!(
+            "ids:unblocked.txt".parse(),
+            Ok(UnblockSource::ReadIds(PathBuf::from("unblocked.txt")))
+        );
+        assert_eq!(
+            "unblocked.txt".parse(),
+            Ok(UnblockSource::ReadIds(PathBuf::from("unblocked.txt")))
+        );
+        assert!("".parse::<UnblockSource>().is_err());
+    }
+
+    #[test]
+    fn test_labelled_sources() {
+        assert_eq!(
+            parse_labelled_read_ids("P1=a.txt"),
+            Ok(LabelledSource {
+                label: String::from("P1"),
+                source: UnblockSource::ReadIds(PathBuf::from("a.txt"))
+            })
+        );
+        // An `=` inside a path is not a label
+        assert_eq!(
+            parse_labelled_read_ids("runs/x=1/a.txt"),
+            Ok(LabelledSource {
+                label: String::from("runs/x=1/a.txt"),
+                source: UnblockSource::ReadIds(PathBuf::from("runs/x=1/a.txt"))
+            })
+        );
+        assert!(parse_labelled_read_ids("P1=").is_err());
+        assert_eq!(
+            "P2=summary:seq.txt".parse(),
+            Ok(LabelledSource {
+                label: String::from("P2"),
+                source: UnblockSource::Summary(PathBuf::from("seq.txt"))
+            })
+        );
+        assert_eq!(
+            "summary:seq.txt".parse::<LabelledSource>().unwrap().label,
+            "summary:seq.txt"
+        );
+    }
+
     #[test]
     fn test_ave_qual_empty() {
         // Test when the input slice is empty
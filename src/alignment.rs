@@ -0,0 +1,333 @@
+//! Module for reading SAM, BAM and CRAM files alike.
+//!
+//! The format of an input is detected from its first bytes, rather than its extension: CRAM
+//! files start with `CRAM`, BAM files are BGZF compressed and start with `BAM\1` once
+//! decompressed, and anything else is taken to be SAM, which may be gzip, BGZF or zstd
+//! compressed. Records of every format are handed on as [`sam::alignment::Record`]s, so the
+//! commands reading them don't need to know which format they came from.
+//!
+//! Nothing is read twice, so the input can be `-` for standard input, e.g. straight from the
+//! basecaller.
+//!
+//! A BAM file with a BAI or CSI index can instead be read only in target regions, querying the
+//! index for each region in turn.
+//!
+//! # Enums
+//!
+//! - [`AlignmentFormat`](enum.AlignmentFormat.html): The format of an alignment file.
+//! - [`AlignmentReader`](enum.AlignmentReader.html): A reader of a SAM, BAM or CRAM file.
+//!
+//! # Functions
+//!
+//! - [`open_bam`](fn.open_bam.html): Open a BAM file, decompressing it on several threads.
+//!
+use crate::_splitting::{decompress, is_stdio};
+use crate::reference::ReferenceArgs;
+use crate::targets::{Target, TargetArgs};
+use flate2::read::MultiGzDecoder;
+use noodles::{
+    bam, cram,
+    sam::{self, alignment::Record, Header},
+};
+use noodles_bgzf as bgzf;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    num::NonZeroUsize,
+    path::Path,
+    thread,
+};
+
+/// Magic bytes at the start of a CRAM file.
+const CRAM_MAGIC: &[u8] = b"CRAM";
+/// Magic bytes at the start of a decompressed BAM file.
+const BAM_MAGIC: &[u8] = b"BAM\x01";
+/// Magic bytes at the start of a gzip member. BGZF blocks are gzip members too.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Bytes read from the start of an input to detect its format, enough for a whole BGZF block.
+const DETECT_LEN: u64 = 1 << 16;
+
+/// A SAM, BAM or CRAM input, a file or standard input.
+type Input = Box<dyn Read + Send>;
+
+/// The format of an alignment file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlignmentFormat {
+    /// SAM, possibly compressed.
+    Sam,
+    /// BAM.
+    Bam,
+    /// CRAM.
+    Cram,
+}
+
+impl AlignmentFormat {
+    /// Detect the format of an alignment file from its first bytes, which should include the
+    /// whole of the first BGZF block of a BAM.
+    pub fn detect(start: &[u8]) -> Self {
+        if start.starts_with(CRAM_MAGIC) {
+            AlignmentFormat::Cram
+        } else if start.starts_with(GZIP_MAGIC) {
+            // A BAM, or a compressed SAM
+            let mut decompressed = Vec::with_capacity(BAM_MAGIC.len());
+            let _ = MultiGzDecoder::new(start)
+                .take(BAM_MAGIC.len() as u64)
+                .read_to_end(&mut decompressed);
+            match decompressed.as_slice() {
+                BAM_MAGIC => AlignmentFormat::Bam,
+                _ => AlignmentFormat::Sam,
+            }
+        } else {
+            AlignmentFormat::Sam
+        }
+    }
+}
+
+/// Open a BAM file, decompressing it on as many threads as are available.
+pub fn open_bam(
+    bam_file: &Path,
+) -> Result<bam::io::Reader<bgzf::MultithreadedReader<File>>, io::Error> {
+    let file = File::open(bam_file)?;
+    Ok(bam::io::Reader::from(multithreaded_reader(file)))
+}
+
+/// A BGZF reader decompressing on as many threads as are available.
+fn multithreaded_reader<R: Read + Send + 'static>(reader: R) -> bgzf::MultithreadedReader<R> {
+    let worker_count = thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    bgzf::MultithreadedReader::with_worker_count(worker_count, reader)
+}
+
+/// A reader of a SAM, BAM or CRAM file.
+pub enum AlignmentReader {
+    /// A SAM file, decompressed if it was compressed.
+    Sam(sam::io::Reader<Box<dyn BufRead + Send>>),
+    /// A BAM file, decompressed on several threads.
+    Bam(bam::io::Reader<bgzf::MultithreadedReader<Input>>),
+    /// A CRAM file, decoded against the reference.
+    Cram(cram::io::Reader<Input>),
+    /// A BAM file read only in the target regions, through its index.
+    BamTargets(bam::io::IndexedReader<bgzf::Reader<File>>, Vec<Target>),
+}
+
+impl AlignmentReader {
+    /// Open a SAM, BAM or CRAM file, or standard input if the path is `-`, detecting which it
+    /// is, and read its header. A CRAM file is decoded against the reference chosen by
+    /// `reference`, which it needs if its reads are aligned.
+    pub fn open(path: &Path, reference: &ReferenceArgs) -> Result<(Self, Header), io::Error> {
+        let mut input: Input = match is_stdio(path) {
+            true => Box::new(io::stdin()),
+            false => Box::new(File::open(path)?),
+        };
+        // Read the start to detect the format, then put it back in front of the rest
+        let mut start = Vec::new();
+        (&mut input).take(DETECT_LEN).read_to_end(&mut start)?;
+        let format = AlignmentFormat::detect(&start);
+        let input: Input = Box::new(Cursor::new(start).chain(input));
+        match format {
+            AlignmentFormat::Sam => {
+                let mut reader = sam::io::Reader::new(decompress(BufReader::new(input))?);
+                let header = reader.read_header()?;
+                Ok((AlignmentReader::Sam(reader), header))
+            }
+            AlignmentFormat::Bam => {
+                let mut reader = bam::io::Reader::from(multithreaded_reader(input));
+                let header = reader.read_header()?;
+                Ok((AlignmentReader::Bam(reader), header))
+            }
+            AlignmentFormat::Cram => {
+                // A reference cache is looked up by the checksums in the header, so the header
+                // is read before the reader that decodes the records is built
+                let mut reader = cram::io::Reader::new(input);
+                let header = reader.read_header()?;
+                let reader = cram::io::reader::Builder::default()
+                    .set_reference_sequence_repository(reference.repository(&header)?)
+                    .build_from_reader(reader.into_inner());
+                Ok((AlignmentReader::Cram(reader), header))
+            }
+        }
+    }
+
+    /// Open a BAM file to be read only in the target regions chosen by `targets`, through its
+    /// `.bai` or `.csi` index, and read its header.
+    ///
+    /// # Returns
+    ///
+    /// Returns the reader and header, or an `io::Error` if the input is standard input or isn't
+    /// a BAM, has no index, or a region isn't on one of its reference sequences.
+    pub fn open_targets(path: &Path, targets: &TargetArgs) -> Result<(Self, Header), io::Error> {
+        if is_stdio(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--region and --bed need an indexed BAM file, they can't read standard input",
+            ));
+        }
+        let mut start = Vec::new();
+        File::open(path)?.take(DETECT_LEN).read_to_end(&mut start)?;
+        if AlignmentFormat::detect(&start) != AlignmentFormat::Bam {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--region and --bed need a BAM file, {} is not one",
+                    path.display()
+                ),
+            ));
+        }
+        let mut reader = bam::io::indexed_reader::Builder::default()
+            .build_from_path(path)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => io::Error::new(
+                    err.kind(),
+                    format!(
+                        "no .bai or .csi index for {}, index it with samtools index",
+                        path.display()
+                    ),
+                ),
+                _ => err,
+            })?;
+        let header = reader.read_header()?;
+        let targets = targets.targets(&header)?;
+        Ok((AlignmentReader::BamTargets(reader, targets), header))
+    }
+
+    /// Call `f` with every record, in the order they are in the file, stopping at the first
+    /// error. One record is reused for SAM and BAM input, while CRAM records are decoded a
+    /// container at a time. Only the records overlapping the targets are read from a BAM read
+    /// through its index, each once, even if it overlaps several.
+    pub fn for_each_record<F>(&mut self, header: &Header, mut f: F) -> Result<(), io::Error>
+    where
+        F: FnMut(&dyn Record) -> Result<(), io::Error>,
+    {
+        match self {
+            AlignmentReader::Sam(reader) => {
+                let mut record = sam::Record::default();
+                while reader.read_record(&mut record)? != 0 {
+                    f(&record)?;
+                }
+            }
+            AlignmentReader::Bam(reader) => {
+                let mut record = bam::Record::default();
+                while reader.read_record(&mut record)? != 0 {
+                    f(&record)?;
+                }
+            }
+            AlignmentReader::Cram(reader) => {
+                for record in reader.records(header) {
+                    // Converting keeps the read group, which CRAM stores apart from the tags
+                    let record = record?.try_into_alignment_record(header)?;
+                    f(&record)?;
+                }
+            }
+            AlignmentReader::BamTargets(reader, targets) => {
+                let mut previous: Option<&Target> = None;
+                for target in targets.iter() {
+                    for record in reader.query(header, &target.region(header))? {
+                        let record = record?;
+                        // Records starting in the target before were found in it
+                        match previous {
+                            Some(previous) if previous.holds_start(header, &record)? => {}
+                            _ => f(&record)?,
+                        }
+                    }
+                    previous = Some(target);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use noodles::sam::alignment::record::data::field::{Tag, Value};
+    use std::fs;
+
+    /// Write the records of test_reads.bam to another format, returning the path.
+    fn convert(format: AlignmentFormat) -> TempPath {
+        let mut reader = bam::io::Reader::new(File::open("tests/test_reads.bam").unwrap());
+        let header = reader.read_header().unwrap();
+        let path = TempPath::new(&format!("alignment_{:?}", format));
+        let file = File::create(&path).unwrap();
+        let mut writer: Box<dyn sam::alignment::io::Write> = match format {
+            AlignmentFormat::Sam => Box::new(sam::io::Writer::new(file)),
+            AlignmentFormat::Bam => Box::new(bam::io::Writer::new(file)),
+            AlignmentFormat::Cram => Box::new(cram::io::Writer::new(file)),
+        };
+        writer.write_alignment_header(&header).unwrap();
+        for record in reader.records() {
+            writer
+                .write_alignment_record(&header, &record.unwrap())
+                .unwrap();
+        }
+        writer.finish(&header).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_all_formats() {
+        let mut expected = Vec::new();
+        for format in [
+            AlignmentFormat::Bam,
+            AlignmentFormat::Sam,
+            AlignmentFormat::Cram,
+        ] {
+            let path = convert(format);
+            assert_eq!(AlignmentFormat::detect(&fs::read(&path).unwrap()), format);
+            let (mut reader, header) =
+                AlignmentReader::open(&path, &ReferenceArgs::default()).unwrap();
+            let mut records = Vec::new();
+            reader
+                .for_each_record(&header, |record| {
+                    let name = record.name().unwrap().as_bytes().to_vec();
+                    let data = record.data();
+                    let read_group = match data.get(&Tag::READ_GROUP) {
+                        Some(Ok(Value::String(rg))) => Some(rg.to_vec()),
+                        _ => None,
+                    };
+                    records.push((name, record.sequence().len(), read_group));
+                    Ok(())
+                })
+                .unwrap();
+            if expected.is_empty() {
+                expected = records;
+                assert!(!expected.is_empty());
+            } else {
+                assert_eq!(records, expected, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_targets() {
+        use crate::bam_index::{tests::write_sorted_bam, IndexFormat};
+
+        // 100 base reads every 40 bases along sq0, in a sorted and indexed BAM
+        let path = TempPath::new("targets.bam");
+        let bai = path.with_suffix(".bai");
+        let (_, index) = write_sorted_bam(&path, 200, IndexFormat::Bai);
+        fs::write(&bai, index).unwrap();
+
+        // read0 and read1 overlap both of the first two regions, but are only read once
+        let targets = TargetArgs {
+            region: ["sq0:120-130", "sq0:1-50", "sq0:4001-4040"]
+                .iter()
+                .map(|region| region.parse().unwrap())
+                .collect(),
+            bed: None,
+        };
+        let (mut reader, header) = AlignmentReader::open_targets(&path, &targets).unwrap();
+        let mut names = Vec::new();
+        reader
+            .for_each_record(&header, |record| {
+                names.push(String::from_utf8(record.name().unwrap().as_bytes().to_vec()).unwrap());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            names,
+            ["read0", "read1", "read2", "read3", "read98", "read99", "read100"]
+        );
+        assert!(AlignmentReader::open_targets(Path::new("-"), &targets).is_err());
+    }
+}
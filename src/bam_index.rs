@@ -0,0 +1,396 @@
+//! Module for indexing the BAM outputs of `split-bam` while they are written.
+//!
+//! The outputs of a coordinate-sorted input are coordinate-sorted too, as the records are
+//! written in the order they are read, so each can be indexed as it is written rather than in
+//! another pass with `samtools index`. The index records the BGZF virtual offsets each record
+//! starts and ends at in the output, along with where it is aligned.
+//!
+//! BAI indexes can only cover reference sequences up to 2^29 - 1 bases long. CSI indexes have
+//! as many levels as the longest reference sequence needs, as samtools makes them.
+//!
+//! # Structs
+//!
+//! - [`BamIndexer`](struct.BamIndexer.html): Builds the index of a BAM output as its records are written.
+//!
+//! # Enums
+//!
+//! - [`IndexFormat`](enum.IndexFormat.html): The format of the index written next to each BAM output.
+//!
+//! # Functions
+//!
+//! - [`is_coordinate_sorted`](fn.is_coordinate_sorted.html): Whether a header declares its records sorted by coordinate.
+//!
+use clap::ValueEnum;
+use noodles::{
+    bam::bai,
+    core::Position,
+    csi::{
+        self,
+        binning_index::{
+            index::reference_sequence::{
+                bin::Chunk,
+                index::{BinnedIndex, LinearIndex},
+            },
+            BinningIndex, Indexer,
+        },
+    },
+    sam::{
+        alignment::Record,
+        header::record::value::map::header::{sort_order, tag},
+        Header,
+    },
+};
+use std::io::{self, BufWriter, Write};
+
+/// Size of the smallest bins, as a power of two, as used by samtools.
+const MIN_SHIFT: u8 = 14;
+/// Levels of bins in a BAI index.
+const BAI_DEPTH: u8 = 5;
+/// Longest reference sequence a BAI index can cover.
+const BAI_MAX_LENGTH: usize = (1 << (MIN_SHIFT + 3 * BAI_DEPTH)) - 1;
+
+/// The format of the index written next to each BAM output.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq)]
+pub enum IndexFormat {
+    /// BAI index, `.bam.bai`, for reference sequences up to 2^29 - 1 bases.
+    Bai,
+    /// CSI index, `.bam.csi`, for reference sequences of any length.
+    Csi,
+}
+
+impl IndexFormat {
+    /// The suffix added to the path of the BAM to name its index.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            IndexFormat::Bai => ".bai",
+            IndexFormat::Csi => ".csi",
+        }
+    }
+
+    /// Check that BAM outputs with `header` can be indexed in this format: they must be sorted
+    /// by coordinate, and a BAI index can't cover very long reference sequences.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `io::Error` naming the problem if they can't be.
+    pub fn check(&self, header: &Header) -> Result<(), io::Error> {
+        if !is_coordinate_sorted(header) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--index needs a coordinate-sorted input, with SO:coordinate in its @HD line",
+            ));
+        }
+        let too_long = header
+            .reference_sequences()
+            .iter()
+            .find(|(_, sq)| sq.length().get() > BAI_MAX_LENGTH);
+        match (self, too_long) {
+            (IndexFormat::Bai, Some((name, _))) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "reference sequence {} is too long for a BAI index, use --index csi",
+                    name
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether a header declares its records sorted by coordinate, with `SO:coordinate`.
+pub fn is_coordinate_sorted(header: &Header) -> bool {
+    header
+        .header()
+        .and_then(|hd| hd.other_fields().get(&tag::SORT_ORDER))
+        .is_some_and(|sort_order| sort_order == sort_order::COORDINATE)
+}
+
+/// The levels of bins a CSI index needs for the longest reference sequence of `header`,
+/// computed as samtools does.
+fn csi_depth(header: &Header) -> u8 {
+    let max_length = header
+        .reference_sequences()
+        .values()
+        .map(|sq| sq.length().get())
+        .max()
+        .unwrap_or(0)
+        + 256;
+    let mut depth = 0;
+    let mut size = 1usize << MIN_SHIFT;
+    while max_length > size {
+        depth += 1;
+        size <<= 3;
+    }
+    depth
+}
+
+/// The indexer for one format.
+enum FormatIndexer {
+    /// A BAI index, with a linear index per reference sequence.
+    Bai(Indexer<LinearIndex>),
+    /// A CSI index, with the offset of the first record in each bin, and its depth.
+    Csi(Indexer<BinnedIndex>, u8),
+}
+
+/// Builds the index of a BAM output as its records are written. Each record is added with the
+/// virtual offsets of the output before and after it was written.
+pub struct BamIndexer {
+    /// The indexer.
+    indexer: FormatIndexer,
+    /// Reference sequence and start of the last record added, unplaced records coming last.
+    last: Option<(usize, Position)>,
+}
+
+impl BamIndexer {
+    /// An indexer for a BAM output with `header`, which must have passed
+    /// [`IndexFormat::check`].
+    pub fn new(format: IndexFormat, header: &Header) -> Self {
+        let indexer = match format {
+            IndexFormat::Bai => FormatIndexer::Bai(Indexer::new(MIN_SHIFT, BAI_DEPTH)),
+            IndexFormat::Csi => {
+                let depth = csi_depth(header);
+                FormatIndexer::Csi(Indexer::new(MIN_SHIFT, depth), depth)
+            }
+        };
+        BamIndexer {
+            indexer,
+            last: None,
+        }
+    }
+
+    /// The format of the index.
+    pub fn format(&self) -> IndexFormat {
+        match self.indexer {
+            FormatIndexer::Bai(_) => IndexFormat::Bai,
+            FormatIndexer::Csi(..) => IndexFormat::Csi,
+        }
+    }
+
+    /// Add a record, written to the output between the virtual offsets of `chunk`.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `io::Error` if the record can't be read, or comes before the last record added.
+    pub fn add_record(
+        &mut self,
+        header: &Header,
+        record: &dyn Record,
+        chunk: Chunk,
+    ) -> Result<(), io::Error> {
+        let context = alignment_context(header, record)?;
+        let key = context.map_or((usize::MAX, Position::MAX), |(id, start, ..)| (id, start));
+        if self.last.is_some_and(|last| key < last) {
+            let name = record.name().map_or_else(
+                || String::from("*"),
+                |name| name.as_bytes().escape_ascii().to_string(),
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "--index needs coordinate-sorted records, but {} is before the record before it",
+                    name
+                ),
+            ));
+        }
+        self.last = Some(key);
+        match &mut self.indexer {
+            FormatIndexer::Bai(indexer) => indexer.add_record(context, chunk),
+            FormatIndexer::Csi(indexer, _) => indexer.add_record(context, chunk),
+        }
+    }
+
+    /// Build the index, once every record is written, and write it to `writer`.
+    pub fn write<W: Write>(self, header: &Header, writer: W) -> Result<(), io::Error> {
+        let reference_sequence_count = header.reference_sequences().len();
+        let mut writer = BufWriter::new(writer);
+        match self.indexer {
+            FormatIndexer::Bai(indexer) => bai::Writer::new(&mut writer)
+                .write_index(&indexer.build(reference_sequence_count))?,
+            FormatIndexer::Csi(indexer, depth) => {
+                // The indexer bins records by its depth, but builds an index with the default
+                // depth, so the index is rebuilt with the depth it was binned by
+                let index = indexer.build(reference_sequence_count);
+                let index = csi::Index::builder()
+                    .set_min_shift(MIN_SHIFT)
+                    .set_depth(depth)
+                    .set_reference_sequences(index.reference_sequences().to_vec())
+                    .set_unplaced_unmapped_record_count(
+                        index.unplaced_unmapped_record_count().unwrap_or(0),
+                    )
+                    .build();
+                // The CSI writer only writes the end of its BGZF stream when it is dropped, where
+                // an error would be lost, so the index is compressed in memory and written from
+                // there
+                let mut csi = Vec::new();
+                csi::Writer::new(&mut csi).write_index(&index)?;
+                writer.write_all(&csi)?
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Where a record is aligned, as the reference sequence, start, end and whether it is mapped,
+/// or `None` if it is unplaced.
+fn alignment_context(
+    header: &Header,
+    record: &dyn Record,
+) -> Result<Option<(usize, Position, Position, bool)>, io::Error> {
+    let reference_sequence_id = record.reference_sequence_id(header).transpose()?;
+    let start = record.alignment_start().transpose()?;
+    let end = record.alignment_end().transpose()?;
+    Ok(match (reference_sequence_id, start, end) {
+        (Some(id), Some(start), Some(end)) => {
+            Some((id, start, end, !record.flags()?.is_unmapped()))
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use noodles::{
+        bam,
+        core::Region,
+        sam::{
+            alignment::{
+                io::Write as _,
+                record::cigar::{op::Kind, Op},
+                record_buf::{Name, QualityScores, Sequence},
+                RecordBuf,
+            },
+            header::record::value::{
+                map::{self, ReferenceSequence},
+                Map,
+            },
+        },
+    };
+    use std::{fs::File, num::NonZeroUsize, path::Path};
+
+    fn sorted_header(length: usize) -> Header {
+        let hd = Map::<map::Header>::builder()
+            .insert(tag::SORT_ORDER, sort_order::COORDINATE)
+            .build()
+            .unwrap();
+        Header::builder()
+            .set_header(hd)
+            .add_reference_sequence(
+                "sq0",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(length).unwrap()),
+            )
+            .build()
+    }
+
+    /// A 100 base read on `sq0` starting at `start`.
+    fn read(name: &str, start: usize) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name(Name::from(name.as_bytes()))
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(start).unwrap())
+            .set_cigar([Op::new(Kind::Match, 100)].into_iter().collect())
+            .set_sequence(Sequence::from(vec![b'A'; 100]))
+            .set_quality_scores(QualityScores::from(vec![20; 100]))
+            .build()
+    }
+
+    /// Write `n` 100 base reads, `read0` onwards one every 40 bases along `sq0`, to a
+    /// coordinate-sorted BAM at `path`, indexing it as it is written. Returns the header and
+    /// the index.
+    pub(crate) fn write_sorted_bam(
+        path: &Path,
+        n: usize,
+        format: IndexFormat,
+    ) -> (Header, Vec<u8>) {
+        let header = sorted_header(100_000);
+        let mut writer = bam::io::Writer::new(File::create(path).unwrap());
+        writer.write_header(&header).unwrap();
+        let mut indexer = BamIndexer::new(format, &header);
+        for i in 0..n {
+            let record = read(&format!("read{}", i), 1 + i * 40);
+            let start = writer.get_ref().virtual_position();
+            writer.write_alignment_record(&header, &record).unwrap();
+            let end = writer.get_ref().virtual_position();
+            indexer
+                .add_record(&header, &record, Chunk::new(start, end))
+                .unwrap();
+        }
+        writer.try_finish().unwrap();
+        let mut index = Vec::new();
+        indexer.write(&header, &mut index).unwrap();
+        (header, index)
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(IndexFormat::Bai.check(&sorted_header(1000)).is_ok());
+        assert!(IndexFormat::Bai.check(&sorted_header(1 << 30)).is_err());
+        assert!(IndexFormat::Csi.check(&sorted_header(1 << 30)).is_ok());
+        assert!(IndexFormat::Csi.check(&Header::default()).is_err());
+        assert_eq!(csi_depth(&sorted_header(100_000)), 1);
+        assert_eq!(csi_depth(&sorted_header(250_000_000)), 5);
+        assert_eq!(csi_depth(&sorted_header(1 << 30)), 6);
+    }
+
+    #[test]
+    fn test_index_while_writing() {
+        for format in [IndexFormat::Bai, IndexFormat::Csi] {
+            let path = TempPath::new(&format!("index_{:?}.bam", format));
+            // Enough 100 base reads for several BGZF blocks
+            let (header, index) = write_sorted_bam(&path, 2000, format);
+            // Ends with the BGZF end of file block
+            if format == IndexFormat::Csi {
+                assert!(index.ends_with(&[
+                    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, 0x42, 0x43, 2, 0, 0x1b, 0, 3, 0,
+                    0, 0, 0, 0, 0, 0, 0, 0
+                ]));
+            }
+
+            let reader = File::open(&path).unwrap();
+            let mut reader = match format {
+                IndexFormat::Bai => bam::io::IndexedReader::new(
+                    reader,
+                    bai::Reader::new(index.as_slice()).read_index().unwrap(),
+                ),
+                IndexFormat::Csi => bam::io::IndexedReader::new(
+                    reader,
+                    csi::Reader::new(index.as_slice()).read_index().unwrap(),
+                ),
+            };
+            reader.read_header().unwrap();
+            let region: Region = "sq0:50001-50100".parse().unwrap();
+            let names: Vec<Vec<u8>> = reader
+                .query(&header, &region)
+                .unwrap()
+                .map(|record| record.unwrap().name().unwrap().as_bytes().to_vec())
+                .collect();
+            // Reads starting from 49902 to 50100 overlap the region
+            let expected: Vec<Vec<u8>> = (1248..=1252)
+                .map(|i| format!("read{}", i).into_bytes())
+                .collect();
+            assert_eq!(names, expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_unsorted_records() {
+        let header = sorted_header(100_000);
+        let record = |start: usize| read(&format!("read{}", start), start);
+        let mut indexer = BamIndexer::new(IndexFormat::Bai, &header);
+        let chunk = Chunk::new(Default::default(), Default::default());
+        indexer.add_record(&header, &record(100), chunk).unwrap();
+        indexer.add_record(&header, &record(100), chunk).unwrap();
+        let unplaced = RecordBuf::builder()
+            .set_name(Name::from(&b"unplaced"[..]))
+            .build();
+        indexer.add_record(&header, &unplaced, chunk).unwrap();
+        let err = indexer
+            .add_record(&header, &record(500), chunk)
+            .unwrap_err();
+        assert!(err.to_string().contains("read500"), "{}", err);
+        let mut indexer = BamIndexer::new(IndexFormat::Csi, &header);
+        indexer.add_record(&header, &record(500), chunk).unwrap();
+        assert!(indexer.add_record(&header, &record(100), chunk).is_err());
+    }
+}
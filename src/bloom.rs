@@ -0,0 +1,259 @@
+//! Module for a Bloom filter used as a prefilter in front of the unblocked read IDs.
+//!
+//! Most reads in a run are not unblocked, so most lookups miss. A Bloom filter answers
+//! "definitely not unblocked" for almost all of those without touching the exact set, which
+//! is much cheaper for a large set, and avoids a block read for an on disk index. Reads the
+//! filter says may be unblocked are confirmed against the exact set, so the result of a
+//! lookup never changes.
+//!
+//! # Structs
+//!
+//! - [`BloomFilter`](struct.BloomFilter.html): A Bloom filter over read IDs.
+//! - [`Prefilter`](struct.Prefilter.html): A Bloom filter which counts the exact checks it avoids.
+//! - [`PrefilterStats`](struct.PrefilterStats.html): Counts of the lookups made through a `Prefilter`.
+//!
+//! # Functions
+//!
+//! - [`hash_uuid`](fn.hash_uuid.html): Hash a read ID packed into a `u128`.
+//! - [`hash_id`](fn.hash_id.html): Hash a read ID.
+//! - [`parse_fpr`](fn.parse_fpr.html): Parse a false positive rate from the command line.
+//!
+use crate::read_ids::parse_uuid;
+use std::{cell::Cell, f64::consts::LN_2, fmt, mem};
+
+/// FNV-1a 64 bit offset basis.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+/// FNV-1a 64 bit prime.
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// SplitMix64 finaliser, spreads the bits of `x` over the whole word.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Hash a read ID that has been packed into a `u128` by [`parse_uuid`].
+pub fn hash_uuid(uuid: u128) -> u64 {
+    mix((uuid as u64) ^ mix((uuid >> 64) as u64))
+}
+
+/// Hash a read ID. Canonical UUIDs hash the same as through [`hash_uuid`].
+pub fn hash_id(id: &[u8]) -> u64 {
+    match parse_uuid(id) {
+        Some(uuid) => hash_uuid(uuid),
+        None => mix(id.iter().fold(FNV_OFFSET, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+        })),
+    }
+}
+
+/// Parse a false positive rate, which must be between 0 and 1 exclusive.
+pub fn parse_fpr(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fpr) if fpr > 0.0 && fpr < 1.0 => Ok(fpr),
+        _ => Err(format!(
+            "false positive rate must be between 0 and 1, got {}",
+            s
+        )),
+    }
+}
+
+/// A Bloom filter over read IDs, using double hashing of a single 64 bit hash.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    /// The bit array.
+    bits: Vec<u64>,
+    /// Number of bits in the array.
+    n_bits: u64,
+    /// Number of hash functions.
+    k: u32,
+}
+
+impl BloomFilter {
+    /// Create a Bloom filter sized for `n` read IDs at the false positive rate `fpr`.
+    pub fn new(n: usize, fpr: f64) -> Self {
+        let n = n.max(1) as f64;
+        let n_bits = (-n * fpr.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let k = ((n_bits as f64 / n) * LN_2).round().clamp(1.0, 32.0) as u32;
+        BloomFilter {
+            bits: vec![0; n_bits.div_ceil(64) as usize],
+            n_bits,
+            k,
+        }
+    }
+
+    /// Bit positions for a hash.
+    fn positions(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let h1 = hash;
+        let h2 = mix(hash) | 1;
+        let n_bits = self.n_bits;
+        (0..self.k as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % n_bits)
+    }
+
+    /// Add a hash from [`hash_uuid`] or [`hash_id`].
+    pub fn insert_hash(&mut self, hash: u64) {
+        for bit in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Check a hash, `false` means the read ID is definitely not in the filter.
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Heap memory used by the filter, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.bits.capacity() * mem::size_of::<u64>()
+    }
+}
+
+/// A Bloom filter in front of an exact set of read IDs, counting the exact checks it avoids.
+#[derive(Debug)]
+pub struct Prefilter {
+    /// The filter.
+    filter: BloomFilter,
+    /// Number of lookups.
+    lookups: Cell<u64>,
+    /// Lookups the filter answered without an exact check.
+    avoided: Cell<u64>,
+    /// Lookups the filter passed on which the exact check then rejected.
+    false_positives: Cell<u64>,
+}
+
+impl Prefilter {
+    /// Wrap a filled Bloom filter.
+    pub fn new(filter: BloomFilter) -> Self {
+        Prefilter {
+            filter,
+            lookups: Cell::new(0),
+            avoided: Cell::new(0),
+            false_positives: Cell::new(0),
+        }
+    }
+
+    /// Check a read ID, `false` means it definitely needs no exact check.
+    pub fn may_contain(&self, id: &[u8]) -> bool {
+        self.lookups.set(self.lookups.get() + 1);
+        let may_contain = self.filter.may_contain_hash(hash_id(id));
+        if !may_contain {
+            self.avoided.set(self.avoided.get() + 1);
+        }
+        may_contain
+    }
+
+    /// Record that the exact check rejected a read the filter passed.
+    pub fn false_positive(&self) {
+        self.false_positives.set(self.false_positives.get() + 1);
+    }
+
+    /// The counts so far.
+    pub fn stats(&self) -> PrefilterStats {
+        PrefilterStats {
+            lookups: self.lookups.get(),
+            avoided: self.avoided.get(),
+            false_positives: self.false_positives.get(),
+            bytes: self.filter.bytes(),
+        }
+    }
+}
+
+/// Counts of the lookups made through a [`Prefilter`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefilterStats {
+    /// Number of lookups.
+    pub lookups: u64,
+    /// Lookups answered without an exact check.
+    pub avoided: u64,
+    /// Lookups the filter passed on which the exact check then rejected.
+    pub false_positives: u64,
+    /// Heap memory used by the filter, in bytes.
+    pub bytes: usize,
+}
+
+impl fmt::Display for PrefilterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = if self.lookups == 0 {
+            0.0
+        } else {
+            self.avoided as f64 * 100.0 / self.lookups as f64
+        };
+        write!(
+            f,
+            "Bloom filter: {} lookups, {} exact checks avoided ({:.1}%), {} false positives, using ~{:.1} MiB",
+            self.lookups,
+            self.avoided,
+            percent,
+            self.false_positives,
+            self.bytes as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid_string(i: u128) -> String {
+        let hex = format!("{:032x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c834));
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    #[test]
+    fn test_hash_id_matches_hash_uuid() {
+        let id = "0afabd54-d901-473f-94ba-c7a6e69716f5";
+        assert_eq!(
+            hash_id(id.as_bytes()),
+            hash_uuid(parse_uuid(id.as_bytes()).unwrap())
+        );
+        assert_ne!(hash_id(b"read_1"), hash_id(b"read_2"));
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let fpr = 0.01;
+        let mut filter = BloomFilter::new(10_000, fpr);
+        for i in 0..10_000 {
+            filter.insert_hash(hash_id(uuid_string(i).as_bytes()));
+        }
+        filter.insert_hash(hash_id(b"read_1"));
+        // No false negatives
+        for i in 0..10_000 {
+            assert!(filter.may_contain_hash(hash_id(uuid_string(i).as_bytes())));
+        }
+        assert!(filter.may_contain_hash(hash_id(b"read_1")));
+        // False positives close to the requested rate
+        let false_positives = (10_000..110_000)
+            .filter(|i| filter.may_contain_hash(hash_id(uuid_string(*i).as_bytes())))
+            .count();
+        assert!((false_positives as f64 / 100_000.0) < fpr * 2.0);
+    }
+
+    #[test]
+    fn test_prefilter_stats() {
+        let mut filter = BloomFilter::new(10, 0.001);
+        filter.insert_hash(hash_id(b"read_1"));
+        let prefilter = Prefilter::new(filter);
+        assert!(prefilter.may_contain(b"read_1"));
+        assert!(!prefilter.may_contain(b"read_2"));
+        let stats = prefilter.stats();
+        assert_eq!((stats.lookups, stats.avoided, stats.false_positives), (2, 1, 0));
+    }
+
+    #[test]
+    fn test_parse_fpr() {
+        assert_eq!(parse_fpr("0.01"), Ok(0.01));
+        assert!(parse_fpr("0").is_err());
+        assert!(parse_fpr("1").is_err());
+        assert!(parse_fpr("x").is_err());
+    }
+}
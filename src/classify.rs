@@ -0,0 +1,1129 @@
+//! Module for classifying reads into categories, and routing them to one writer per category.
+//!
+//! Each split command reads records, describes each one as a [`ReadInfo`], and hands it to a
+//! [`Router`]. The router asks each of its [`ReadClassifier`]s for a label, e.g. `sequenced`
+//! or `unblocked`, and the labels together pick the output the record is written to. Outputs
+//! are named after their labels, so adding a category is a matter of adding a classifier.
+//!
+//! # Traits
+//!
+//! - [`ReadClassifier`](trait.ReadClassifier.html): Maps a read to a label.
+//!
+//! # Enums
+//!
+//! - [`SplitBy`](enum.SplitBy.html): A way of splitting reads beyond sequenced and unblocked.
+//!
+//! # Structs
+//!
+//! - [`ClassifyArgs`](struct.ClassifyArgs.html): Command line arguments choosing the classifiers, shared by the split commands.
+//! - [`ReadInfo`](struct.ReadInfo.html): A read from any input format, as seen by a classifier.
+//! - [`UnblockClassifier`](struct.UnblockClassifier.html): Labels reads `sequenced` or `unblocked`.
+//! - [`DecisionClassifier`](struct.DecisionClassifier.html): Labels reads by their final readfish decision.
+//! - [`BarcodeClassifier`](struct.BarcodeClassifier.html): Labels reads by their barcode.
+//! - [`ConditionClassifier`](struct.ConditionClassifier.html): Labels reads by the readfish condition of their channel.
+//! - [`TimeClassifier`](struct.TimeClassifier.html): Labels reads by the time bin they started in, and drops reads outside a time range.
+//! - [`Router`](struct.Router.html): Routes reads to one writer per combination of labels.
+//! - [`Output`](struct.Output.html): A writer created by a `Router`, and the reads written to it.
+//!
+use crate::_splitting::SplitType;
+use crate::conditions::{ChannelConditions, Flowcell};
+use crate::decisions::{Decision, DecisionTally, ReadDecisions, DECISIONS};
+use crate::read_ids::{ListTally, UnblockedReads};
+use crate::time_bins::{
+    bin_label, parse_duration, parse_run_start, parse_time_range, RunClock, TimeRange,
+};
+use clap::{Args, ValueEnum};
+use fnv::FnvHashMap;
+use std::{fmt, io, path::PathBuf};
+
+/// Label for reads which were not unblocked.
+pub const SEQUENCED: &str = "sequenced";
+/// Label for reads which were unblocked.
+pub const UNBLOCKED: &str = "unblocked";
+/// Label for reads readfish never evaluated.
+pub const NEVER_EVALUATED: &str = "never_evaluated";
+/// Label for reads without a barcode.
+pub const UNCLASSIFIED: &str = "unclassified";
+/// Label for reads whose channel is not in any condition.
+pub const UNASSIGNED: &str = "unassigned";
+/// Label for reads without a start time.
+pub const NO_START_TIME: &str = "no_start_time";
+/// Separator between the labels of an output name.
+pub const LABEL_SEPARATOR: char = '.';
+
+/// A read from any of the input formats, as seen by a [`ReadClassifier`].
+#[derive(Debug, Clone, Copy)]
+pub struct ReadInfo<'a> {
+    /// The read ID.
+    pub read_id: &'a [u8],
+    /// Whether this is a duplex read, whose ID is the `;` separated IDs of its parents.
+    pub duplex: bool,
+    /// The barcode the read was classified as, if any.
+    pub barcode: Option<&'a [u8]>,
+    /// The channel the read was sequenced on, if known.
+    pub channel: Option<u32>,
+    /// When the read started, as written in the input, if known.
+    pub start_time: Option<&'a [u8]>,
+}
+
+impl<'a> ReadInfo<'a> {
+    /// A simplex read, with no barcode, channel or start time.
+    pub fn new(read_id: &'a [u8]) -> Self {
+        ReadInfo {
+            read_id,
+            duplex: false,
+            barcode: None,
+            channel: None,
+            start_time: None,
+        }
+    }
+
+    /// The IDs to look the read up by: its own ID, or the IDs of the parents of a duplex read.
+    pub fn lookup_ids(&self) -> impl Iterator<Item = &'a [u8]> {
+        let duplex = self.duplex;
+        self.read_id.split(move |b| duplex && *b == b';')
+    }
+}
+
+/// Maps a read to a label, which names the category it is written to.
+pub trait ReadClassifier {
+    /// The label for a read, `None` if reads in its category are not written. An empty label
+    /// adds nothing to the output name.
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str>;
+
+    /// The field of an `--output-template` its labels fill, `None` if its labels are empty.
+    fn field(&self) -> Option<&'static str>;
+
+    /// The labels written, if they are all known before any read is seen. Outputs are created
+    /// up front for these, so that a category with no reads still gets an (empty) output.
+    fn labels(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// A report of what was classified, printed at the end of the split.
+    fn report(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Labels reads `sequenced` or `unblocked`, from one or more lists of unblocked read IDs.
+///
+/// Tallies the reads by the list they were found in, and by their final readfish decision,
+/// when the lists allow it.
+#[derive(Debug)]
+pub struct UnblockClassifier {
+    /// The unblocked read IDs.
+    unblocked: UnblockedReads,
+    /// Which of the categories are written.
+    split_type: SplitType,
+    /// Unblocked reads by the list they were found in.
+    list_tally: Option<ListTally>,
+    /// Reads by final readfish decision.
+    tally: Option<DecisionTally>,
+}
+
+impl UnblockClassifier {
+    /// Classify reads against `unblocked`, writing the categories chosen by `split_type`.
+    pub fn new(unblocked: UnblockedReads, split_type: SplitType) -> Self {
+        UnblockClassifier {
+            list_tally: unblocked.tally(),
+            tally: unblocked.decisions().map(|decisions| decisions.tally()),
+            unblocked,
+            split_type,
+        }
+    }
+
+    /// The final readfish decisions, if one of the unblock sources was a readfish chunk log.
+    pub fn decisions(&self) -> Option<&ReadDecisions> {
+        self.unblocked.decisions()
+    }
+}
+
+impl ReadClassifier for UnblockClassifier {
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str> {
+        let hits = read
+            .lookup_ids()
+            .fold(0, |hits, id| hits | self.unblocked.find(id));
+        if let Some(list_tally) = self.list_tally.as_mut() {
+            list_tally.add(read.read_id, hits);
+        }
+        if let (Some(decisions), Some(tally)) = (self.unblocked.decisions(), self.tally.as_mut()) {
+            tally.add(read.lookup_ids().find_map(|id| decisions.get(id)));
+        }
+        match (hits != 0, &self.split_type) {
+            (true, SplitType::SequencedOnly) | (false, SplitType::UnblockedOnly) => None,
+            (true, _) => Some(UNBLOCKED),
+            (false, _) => Some(SEQUENCED),
+        }
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        Some("category")
+    }
+
+    fn labels(&self) -> Vec<String> {
+        let labels: &[&str] = match self.split_type {
+            SplitType::All => &[SEQUENCED, UNBLOCKED],
+            SplitType::SequencedOnly => &[SEQUENCED],
+            SplitType::UnblockedOnly => &[UNBLOCKED],
+        };
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    fn report(&self) -> Option<String> {
+        let reports: Vec<String> = [
+            self.list_tally.as_ref().map(ToString::to_string),
+            self.tally.as_ref().map(ToString::to_string),
+            self.unblocked
+                .prefilter_stats()
+                .map(|stats| stats.to_string()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        (!reports.is_empty()).then(|| reports.join("\n"))
+    }
+}
+
+/// Labels reads by their final readfish decision (`unblock`, `stop_receiving` or `proceed`), or
+/// `never_evaluated` if readfish never saw them, from a readfish chunk log.
+#[derive(Debug)]
+pub struct DecisionClassifier {
+    /// The unblocked read IDs, which include the decisions from the chunk log.
+    unblocked: UnblockedReads,
+    /// Which of the categories are written, unblock counting as unblocked.
+    split_type: SplitType,
+    /// Reads by condition and final readfish decision.
+    tally: DecisionTally,
+}
+
+impl DecisionClassifier {
+    /// Classify reads by the decisions in `unblocked`, writing the categories chosen by
+    /// `split_type`. Fails if none of the unblock sources was a readfish chunk log.
+    pub fn new(unblocked: UnblockedReads, split_type: SplitType) -> Result<Self, io::Error> {
+        let tally = match unblocked.decisions() {
+            Some(decisions) => decisions.tally(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--split-by decision needs the readfish chunk log, use --unblock-source decisions:<path>",
+                ))
+            }
+        };
+        Ok(DecisionClassifier {
+            unblocked,
+            split_type,
+            tally,
+        })
+    }
+}
+
+impl ReadClassifier for DecisionClassifier {
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str> {
+        let decisions = self.unblocked.decisions()?;
+        let read_decision = read.lookup_ids().find_map(|id| decisions.get(id));
+        self.tally.add(read_decision);
+        let decision = read_decision.map(|read| read.decision);
+        match (decision == Some(Decision::Unblock), &self.split_type) {
+            (true, SplitType::SequencedOnly) | (false, SplitType::UnblockedOnly) => None,
+            _ => Some(decision.map_or(NEVER_EVALUATED, |decision| decision.as_str())),
+        }
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        Some("category")
+    }
+
+    fn labels(&self) -> Vec<String> {
+        DECISIONS
+            .iter()
+            .map(|decision| decision.as_str())
+            .chain([NEVER_EVALUATED])
+            .filter(|label| match self.split_type {
+                SplitType::All => true,
+                SplitType::SequencedOnly => *label != Decision::Unblock.as_str(),
+                SplitType::UnblockedOnly => *label == Decision::Unblock.as_str(),
+            })
+            .map(String::from)
+            .collect()
+    }
+
+    fn report(&self) -> Option<String> {
+        Some(self.tally.to_string())
+    }
+}
+
+/// Labels reads by their barcode, or `unclassified`. Barcodes are only known once reads are
+/// seen, so their outputs are created as they are needed.
+#[derive(Debug)]
+pub struct BarcodeClassifier {
+    /// Label for each barcode seen, with any characters unsafe in a file name replaced.
+    labels: FnvHashMap<Vec<u8>, String>,
+    /// Keeps the labels of barcodes which differ only in unsafe characters apart.
+    unique: UniqueLabels,
+}
+
+impl Default for BarcodeClassifier {
+    fn default() -> Self {
+        BarcodeClassifier {
+            labels: FnvHashMap::default(),
+            unique: UniqueLabels::new("barcode", UNCLASSIFIED),
+        }
+    }
+}
+
+impl ReadClassifier for BarcodeClassifier {
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str> {
+        let barcode = match read.barcode {
+            Some(barcode) if !barcode.is_empty() && barcode != UNCLASSIFIED.as_bytes() => barcode,
+            _ => return Some(UNCLASSIFIED),
+        };
+        if !self.labels.contains_key(barcode) {
+            let label = self.unique.label(barcode);
+            self.labels.insert(barcode.to_vec(), label);
+        }
+        self.labels.get(barcode).map(String::as_str)
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        Some("barcode")
+    }
+}
+
+/// Labels reads by the readfish condition their channel is in, or `unassigned`.
+#[derive(Debug)]
+pub struct ConditionClassifier {
+    /// The condition of each channel.
+    conditions: ChannelConditions,
+    /// Label for each condition, with any characters unsafe in a file name replaced.
+    labels: Vec<String>,
+}
+
+impl ConditionClassifier {
+    /// Classify reads by the conditions of a readfish TOML.
+    pub fn new(conditions: ChannelConditions) -> Self {
+        let mut unique = UniqueLabels::new("condition", UNASSIGNED);
+        let labels = conditions
+            .names()
+            .iter()
+            .map(|name| unique.label(name.as_bytes()))
+            .collect();
+        ConditionClassifier { conditions, labels }
+    }
+}
+
+impl ReadClassifier for ConditionClassifier {
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str> {
+        match read
+            .channel
+            .and_then(|channel| self.conditions.condition(channel))
+        {
+            Some(idx) => Some(&self.labels[idx]),
+            None => Some(UNASSIGNED),
+        }
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        Some("condition")
+    }
+
+    fn labels(&self) -> Vec<String> {
+        let mut labels = self.labels.clone();
+        labels.push(UNASSIGNED.to_owned());
+        labels
+    }
+}
+
+/// Labels reads by the time bin they started in, e.g. `0-1h`, and drops reads which started
+/// outside a time range. With a time range but no bins, reads in the range are not labelled.
+#[derive(Debug)]
+pub struct TimeClassifier {
+    /// Converts start times to seconds since the start of the run.
+    clock: RunClock,
+    /// Width of the bins in seconds, `None` to not bin reads.
+    bin_width: Option<u64>,
+    /// Reads outside this range are dropped.
+    range: Option<TimeRange>,
+    /// Label for each bin seen.
+    labels: FnvHashMap<u64, String>,
+    /// Reads dropped for starting outside `range`.
+    out_of_range: usize,
+    /// Reads starting before the run, binned with the first bin.
+    before_start: usize,
+    /// Reads without a start time, or with one that could not be read.
+    no_start_time: usize,
+}
+
+impl TimeClassifier {
+    /// Classify reads by their start time.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - Converts start times to seconds since the start of the run.
+    /// * `bin_width` - Width of the bins in seconds, `None` to not bin reads.
+    /// * `range` - Reads outside this range are dropped, along with reads without a start time.
+    pub fn new(clock: RunClock, bin_width: Option<u64>, range: Option<TimeRange>) -> Self {
+        TimeClassifier {
+            clock,
+            bin_width,
+            range,
+            labels: FnvHashMap::default(),
+            out_of_range: 0,
+            before_start: 0,
+            no_start_time: 0,
+        }
+    }
+}
+
+impl ReadClassifier for TimeClassifier {
+    fn classify(&mut self, read: &ReadInfo<'_>) -> Option<&str> {
+        let elapsed = match read.start_time.and_then(|st| self.clock.elapsed(st)) {
+            Some(elapsed) => elapsed,
+            None => {
+                self.no_start_time += 1;
+                return match self.range {
+                    Some(_) => None,
+                    None => Some(NO_START_TIME),
+                };
+            }
+        };
+        if self.range.is_some_and(|range| !range.contains(elapsed)) {
+            self.out_of_range += 1;
+            return None;
+        }
+        let bin_width = match self.bin_width {
+            Some(bin_width) => bin_width,
+            None => return Some(""),
+        };
+        // A start of the run given a little late shouldn't make bins before the first
+        if elapsed < 0.0 {
+            self.before_start += 1;
+        }
+        let bin = (elapsed.max(0.0) / bin_width as f64).floor() as u64;
+        Some(
+            self.labels
+                .entry(bin)
+                .or_insert_with(|| bin_label(bin, bin_width)),
+        )
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        self.bin_width.map(|_| "bin")
+    }
+
+    fn labels(&self) -> Vec<String> {
+        match self.bin_width {
+            Some(_) => Vec::new(),
+            None => vec![String::new()],
+        }
+    }
+
+    fn report(&self) -> Option<String> {
+        let mut report = format!("Reads without a start time: {}", self.no_start_time);
+        if self.range.is_some() {
+            report.push_str(&format!(
+                "\nReads outside the time range: {}",
+                self.out_of_range
+            ));
+        }
+        if self.before_start > 0 {
+            report.push_str(&format!(
+                "\nReads starting before the run, in the first bin: {}",
+                self.before_start
+            ));
+        }
+        Some(report)
+    }
+}
+
+/// A label made safe to use in a file name, by replacing anything other than ASCII letters,
+/// digits, `-` and `_`.
+fn file_label(name: &[u8]) -> String {
+    name.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => b as char,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Labels made safe to use in a file name by [`file_label`], kept apart by adding `-2`, `-3`
+/// and so on when names would otherwise share one, e.g. `bc.01` and `bc 01`.
+#[derive(Debug)]
+struct UniqueLabels {
+    /// What is labelled, e.g. `barcode`, for the warning when a label is changed.
+    kind: &'static str,
+    /// The name each label was given to, `None` for the reserved label.
+    names: FnvHashMap<String, Option<Vec<u8>>>,
+}
+
+impl UniqueLabels {
+    /// No labels given yet, with `reserved`, e.g. `unclassified`, not given to any name.
+    fn new(kind: &'static str, reserved: &str) -> Self {
+        let mut names = FnvHashMap::default();
+        names.insert(reserved.to_owned(), None);
+        UniqueLabels { kind, names }
+    }
+
+    /// The label for a name not labelled before, warning if it had to be changed.
+    fn label(&mut self, name: &[u8]) -> String {
+        let base = file_label(name);
+        let mut label = base.clone();
+        for n in 2.. {
+            if !self.names.contains_key(&label) {
+                break;
+            }
+            label = format!("{}-{}", base, n);
+        }
+        if label != base {
+            let taken = match &self.names[&base] {
+                Some(other) => format!("is the label of {:?}", String::from_utf8_lossy(other)),
+                None => String::from("is reserved"),
+            };
+            eprintln!(
+                "Warning: {} {:?} is labelled {}, as {} {}",
+                self.kind,
+                String::from_utf8_lossy(name),
+                label,
+                base,
+                taken
+            );
+        }
+        self.names.insert(label.clone(), Some(name.to_vec()));
+        label
+    }
+}
+
+/// A way of splitting reads beyond sequenced and unblocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SplitBy {
+    /// One output per final readfish decision, plus reads readfish never evaluated.
+    Decision,
+    /// One output per barcode and category, plus unclassified reads.
+    Barcode,
+    /// One output per readfish condition and category, plus reads on channels the flowcell doesn't have.
+    Condition,
+}
+
+/// Command line arguments for binning and filtering reads by their start time.
+#[derive(Debug, Default, Args)]
+pub struct TimeArgs {
+    /// Split each category into bins of this width by the read start times, e.g. 1h or 30m
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    pub time_bins: Option<u64>,
+    /// Only write reads which started in this window of the run, e.g. 0-24h, 30m-2h or 12h-
+    #[clap(long, value_name = "START-END", parse(try_from_str = parse_time_range))]
+    pub time_range: Option<TimeRange>,
+    /// Start of the run, e.g. 2024-01-01T10:00:00Z, to measure the BAM and FASTQ start timestamps
+    /// from. Taken from the read group DT field of a BAM if not set
+    #[clap(long, value_name = "TIMESTAMP", parse(try_from_str = parse_run_start))]
+    pub run_start: Option<f64>,
+}
+
+/// Command line arguments choosing how reads are classified, shared by the split commands.
+#[derive(Debug, Args)]
+pub struct ClassifyArgs {
+    /// Split into more categories than sequenced and unblocked, comma separated. decision writes one
+    /// output per final readfish decision, and needs --unblock-source decisions:<path>. barcode
+    /// splits each category by barcode. condition splits each category by the readfish condition
+    /// of the read's channel, and needs --readfish-toml
+    #[clap(long, value_enum, use_value_delimiter = true)]
+    pub split_by: Vec<SplitBy>,
+    /// readfish TOML giving the conditions, for --split-by condition
+    #[clap(long, value_name = "TOML", parse(from_os_str))]
+    pub readfish_toml: Option<PathBuf>,
+    /// Flowcell the run used, which readfish splits between the conditions
+    #[clap(long, value_enum, default_value_t)]
+    pub flowcell: Flowcell,
+    #[clap(flatten)]
+    pub time: TimeArgs,
+}
+
+impl ClassifyArgs {
+    /// Whether reads are split by barcode, so the split commands need to find them.
+    pub fn by_barcode(&self) -> bool {
+        self.split_by.contains(&SplitBy::Barcode)
+    }
+
+    /// Whether reads are split by condition, so the split commands need their channels.
+    pub fn by_condition(&self) -> bool {
+        self.split_by.contains(&SplitBy::Condition)
+    }
+
+    /// Whether reads are binned or filtered by start time, so the split commands need them.
+    pub fn by_time(&self) -> bool {
+        self.time.time_bins.is_some() || self.time.time_range.is_some()
+    }
+
+    /// Build the classifiers for a split.
+    ///
+    /// # Arguments
+    ///
+    /// * `unblocked` - The unblocked read IDs.
+    /// * `split_type` - Which of the sequenced and unblocked categories are written.
+    ///
+    /// # Returns
+    ///
+    /// Returns the classifiers, or an `io::Error` if one of them can't be used with `unblocked`,
+    /// or the readfish TOML can't be read.
+    pub fn classifiers(
+        &self,
+        unblocked: UnblockedReads,
+        split_type: SplitType,
+    ) -> Result<Vec<Box<dyn ReadClassifier>>, io::Error> {
+        let category: Box<dyn ReadClassifier> = if self.split_by.contains(&SplitBy::Decision) {
+            Box::new(DecisionClassifier::new(unblocked, split_type)?)
+        } else {
+            Box::new(UnblockClassifier::new(unblocked, split_type))
+        };
+        let mut classifiers = vec![category];
+        if self.by_condition() {
+            let path = self.readfish_toml.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--split-by condition needs the readfish TOML, use --readfish-toml <path>",
+                )
+            })?;
+            let conditions = ChannelConditions::from_toml(path, self.flowcell)?;
+            classifiers.push(Box::new(ConditionClassifier::new(conditions)));
+        }
+        if self.by_barcode() {
+            classifiers.push(Box::<BarcodeClassifier>::default());
+        }
+        if self.by_time() {
+            classifiers.push(Box::new(TimeClassifier::new(
+                RunClock::new(self.time.run_start),
+                self.time.time_bins,
+                self.time.time_range,
+            )));
+        }
+        Ok(classifiers)
+    }
+}
+
+/// A writer created by a [`Router`], and the number of reads written to it.
+#[derive(Debug)]
+pub struct Output<W> {
+    /// The labels of the output, joined with `.`.
+    pub name: String,
+    /// The writer.
+    pub writer: W,
+    /// Number of reads routed to the writer.
+    pub count: usize,
+}
+
+/// Routes reads to one writer per combination of labels from its classifiers.
+///
+/// Writers are created when the first read with their labels is seen, or up front if the
+/// classifiers know all of their labels, and are named by joining the labels with `.`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut router = Router::new(vec![Box::new(UnblockClassifier::new(unblocked, SplitType::All))]);
+/// let create = |name: &str| File::create(format!("{}.txt", name));
+/// router.create_known(create)?;
+/// if let Some(file) = router.route(&ReadInfo::new(read_id), create)? {
+///     file.write_all(read_id)?;
+/// }
+/// println!("{}", router);
+/// ```
+pub struct Router<W> {
+    /// Classifiers, each contributing one label to the name of an output.
+    classifiers: Vec<Box<dyn ReadClassifier>>,
+    /// Outputs, in the order they were created.
+    outputs: Vec<Output<W>>,
+    /// Position in `outputs` of each output name.
+    index: FnvHashMap<String, usize>,
+    /// Buffer the name of the output for a read is built in.
+    key: String,
+    /// Reads in categories which are not written.
+    dropped: usize,
+}
+
+impl<W> Router<W> {
+    /// Create a router with no outputs yet.
+    pub fn new(classifiers: Vec<Box<dyn ReadClassifier>>) -> Self {
+        Router {
+            classifiers,
+            outputs: Vec::new(),
+            index: FnvHashMap::default(),
+            key: String::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Create an output for every combination of labels, if all of the classifiers know their
+    /// labels up front.
+    ///
+    /// # Arguments
+    ///
+    /// * `create` - Creates the writer for an output, given its name.
+    pub fn create_known<F>(&mut self, mut create: F) -> Result<(), io::Error>
+    where
+        F: FnMut(&str) -> Result<W, io::Error>,
+    {
+        for name in self.known_names().unwrap_or_default() {
+            self.add_output(name, &mut create)?;
+        }
+        Ok(())
+    }
+
+    /// The names of every output, if all of the labels are known up front.
+    fn known_names(&self) -> Option<Vec<String>> {
+        let mut names = vec![String::new()];
+        for classifier in &self.classifiers {
+            let labels = classifier.labels();
+            if labels.is_empty() {
+                return None;
+            }
+            names = names
+                .iter()
+                .flat_map(|name| labels.iter().map(move |label| join_label(name, label)))
+                .collect();
+        }
+        Some(names)
+    }
+
+    /// The number of outputs, if all of the labels are known up front, so that no more outputs
+    /// will be created than [`create_known`](#method.create_known) creates.
+    pub fn known_outputs(&self) -> Option<usize> {
+        self.known_names().map(|names| names.len())
+    }
+
+    /// Classify a read, and return the writer for its labels.
+    ///
+    /// Every classifier sees every read, so that their tallies are complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `read` - The read.
+    /// * `create` - Creates the writer for an output, given its name, if it doesn't exist yet.
+    ///
+    /// # Returns
+    ///
+    /// Returns the writer, `None` if a classifier dropped the read, or an `io::Error` if the
+    /// writer could not be created.
+    pub fn route<F>(&mut self, read: &ReadInfo<'_>, create: F) -> Result<Option<&mut W>, io::Error>
+    where
+        F: FnOnce(&str) -> Result<W, io::Error>,
+    {
+        self.key.clear();
+        let mut keep = true;
+        for classifier in &mut self.classifiers {
+            match classifier.classify(read) {
+                Some("") => {}
+                Some(label) => {
+                    if !self.key.is_empty() {
+                        self.key.push(LABEL_SEPARATOR);
+                    }
+                    self.key.push_str(label);
+                }
+                None => keep = false,
+            }
+        }
+        if !keep {
+            self.dropped += 1;
+            return Ok(None);
+        }
+        let idx = match self.index.get(self.key.as_str()) {
+            Some(idx) => *idx,
+            None => self.add_output(self.key.clone(), create)?,
+        };
+        let output = &mut self.outputs[idx];
+        output.count += 1;
+        Ok(Some(&mut output.writer))
+    }
+
+    /// Create a writer and add it to the outputs, returning its position.
+    fn add_output<F>(&mut self, name: String, create: F) -> Result<usize, io::Error>
+    where
+        F: FnOnce(&str) -> Result<W, io::Error>,
+    {
+        let writer = create(&name)?;
+        self.index.insert(name.clone(), self.outputs.len());
+        self.outputs.push(Output {
+            name,
+            writer,
+            count: 0,
+        });
+        Ok(self.outputs.len() - 1)
+    }
+
+    /// The `--output-template` field of each label in an output name, in order.
+    pub fn fields(&self) -> Vec<&'static str> {
+        self.classifiers
+            .iter()
+            .filter_map(|classifier| classifier.field())
+            .collect()
+    }
+
+    /// The labels each field of [`fields`](#method.fields) can take, empty for those only
+    /// known once the reads are seen, like barcodes.
+    pub fn field_labels(&self) -> Vec<Vec<String>> {
+        self.classifiers
+            .iter()
+            .filter(|classifier| classifier.field().is_some())
+            .map(|classifier| classifier.labels())
+            .collect()
+    }
+
+    /// Reads written as sequenced and as unblocked, whatever their other labels. With
+    /// `--split-by decision`, reads readfish unblocked are unblocked and the rest sequenced.
+    pub fn category_totals(&self) -> (usize, usize) {
+        self.outputs
+            .iter()
+            .fold((0, 0), |(sequenced, unblocked), output| {
+                match output.name.split(LABEL_SEPARATOR).next() {
+                    Some(UNBLOCKED) => (sequenced, unblocked + output.count),
+                    Some(label) if label == Decision::Unblock.as_str() => {
+                        (sequenced, unblocked + output.count)
+                    }
+                    _ => (sequenced + output.count, unblocked),
+                }
+            })
+    }
+
+    /// The outputs, in the order they were created, e.g. to finish the writers.
+    pub fn into_outputs(self) -> Vec<Output<W>> {
+        self.outputs
+    }
+}
+
+/// Append a label to an output name.
+fn join_label(name: &str, label: &str) -> String {
+    if label.is_empty() {
+        name.to_string()
+    } else if name.is_empty() {
+        label.to_string()
+    } else {
+        format!("{}{}{}", name, LABEL_SEPARATOR, label)
+    }
+}
+
+impl<W> fmt::Display for Router<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reads written:")?;
+        for output in &self.outputs {
+            write!(f, "\n\t{}: {}", output.name, output.count)?;
+        }
+        if self.dropped > 0 {
+            write!(f, "\n\tnot written: {}", self.dropped)?;
+        }
+        for report in self.classifiers.iter().filter_map(|c| c.report()) {
+            write!(f, "\n{}", report)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_ids::{ReadIdSet, ReadIds};
+
+    fn unblocked() -> UnblockedReads {
+        let mut set = ReadIdSet::new();
+        set.insert("read_1");
+        set.insert("read_3");
+        UnblockedReads::new(vec![(String::from("P1"), ReadIds::InMemory(set))])
+    }
+
+    #[test]
+    fn test_lookup_ids() {
+        let read = ReadInfo::new(b"read_1;read_2");
+        assert_eq!(
+            read.lookup_ids().collect::<Vec<_>>(),
+            vec![b"read_1;read_2"]
+        );
+        let read = ReadInfo {
+            duplex: true,
+            ..ReadInfo::new(b"read_1;read_2")
+        };
+        assert_eq!(
+            read.lookup_ids().collect::<Vec<_>>(),
+            vec![b"read_1".as_slice(), b"read_2".as_slice()]
+        );
+    }
+
+    #[test]
+    fn test_unblock_classifier() {
+        let mut classifier = UnblockClassifier::new(unblocked(), SplitType::All);
+        assert_eq!(
+            classifier.classify(&ReadInfo::new(b"read_1")),
+            Some(UNBLOCKED)
+        );
+        assert_eq!(
+            classifier.classify(&ReadInfo::new(b"read_2")),
+            Some(SEQUENCED)
+        );
+        let duplex = ReadInfo {
+            duplex: true,
+            ..ReadInfo::new(b"read_2;read_3")
+        };
+        assert_eq!(classifier.classify(&duplex), Some(UNBLOCKED));
+        assert_eq!(classifier.labels(), vec![SEQUENCED, UNBLOCKED]);
+
+        let mut classifier = UnblockClassifier::new(unblocked(), SplitType::SequencedOnly);
+        assert_eq!(classifier.classify(&ReadInfo::new(b"read_1")), None);
+        assert_eq!(
+            classifier.classify(&ReadInfo::new(b"read_2")),
+            Some(SEQUENCED)
+        );
+    }
+
+    #[test]
+    fn test_decision_classifier() {
+        use crate::decisions::read_decision_log;
+        use std::path::PathBuf;
+
+        assert!(DecisionClassifier::new(unblocked(), SplitType::All).is_err());
+        let decisions = || {
+            let path = PathBuf::from("tests/test_readfish_chunks.tsv");
+            UnblockedReads::new(vec![(
+                String::from("log"),
+                ReadIds::Decisions(read_decision_log(path).unwrap()),
+            )])
+        };
+        let mut classifier = DecisionClassifier::new(decisions(), SplitType::All).unwrap();
+        for (read_id, label) in [
+            ("0afabd54-d901-473f-94ba-c7a6e69716f5", "unblock"),
+            ("8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e", "stop_receiving"),
+            ("5b1e2c0a-3f7d-4e8a-9c61-2d4f8e7a1b93", "proceed"),
+            ("c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e", NEVER_EVALUATED),
+        ] {
+            assert_eq!(
+                classifier.classify(&ReadInfo::new(read_id.as_bytes())),
+                Some(label)
+            );
+        }
+        assert_eq!(
+            classifier.labels(),
+            vec!["unblock", "stop_receiving", "proceed", NEVER_EVALUATED]
+        );
+
+        let mut classifier =
+            DecisionClassifier::new(decisions(), SplitType::SequencedOnly).unwrap();
+        assert_eq!(
+            classifier.classify(&ReadInfo::new(b"0afabd54-d901-473f-94ba-c7a6e69716f5")),
+            None
+        );
+        assert_eq!(
+            classifier.labels(),
+            vec!["stop_receiving", "proceed", NEVER_EVALUATED]
+        );
+    }
+
+    #[test]
+    fn test_router() {
+        let classifier = UnblockClassifier::new(unblocked(), SplitType::All);
+        let mut router: Router<Vec<u8>> = Router::new(vec![Box::new(classifier)]);
+        router.create_known(|_| Ok(Vec::new())).unwrap();
+        for read_id in ["read_1", "read_2", "read_3", "read_4", "read_5"] {
+            let writer = router
+                .route(&ReadInfo::new(read_id.as_bytes()), |_| Ok(Vec::new()))
+                .unwrap()
+                .unwrap();
+            writer.extend_from_slice(read_id.as_bytes());
+        }
+        assert_eq!(
+            router.to_string(),
+            "Reads written:\n\tsequenced: 3\n\tunblocked: 2"
+        );
+        assert_eq!(router.category_totals(), (3, 2));
+        let outputs = router.into_outputs();
+        assert_eq!(outputs[0].name, SEQUENCED);
+        assert_eq!(outputs[0].writer, b"read_2read_4read_5");
+        assert_eq!(outputs[1].writer, b"read_1read_3");
+    }
+
+    #[test]
+    fn test_barcode_classifier() {
+        let mut classifier = BarcodeClassifier::default();
+        for (barcode, label) in [
+            (Some(b"barcode01".as_slice()), "barcode01"),
+            (Some(b"bar/code 02".as_slice()), "bar_code_02"),
+            // Barcodes which differ only in unsafe characters get their own outputs
+            (Some(b"bar_code_02".as_slice()), "bar_code_02-2"),
+            (Some(b"bar.code.02".as_slice()), "bar_code_02-3"),
+            (Some(b"bar/code 02".as_slice()), "bar_code_02"),
+            (Some(b"unclassified".as_slice()), UNCLASSIFIED),
+            (Some(b"".as_slice()), UNCLASSIFIED),
+            (None, UNCLASSIFIED),
+        ] {
+            let read = ReadInfo {
+                barcode,
+                ..ReadInfo::new(b"read_1")
+            };
+            assert_eq!(classifier.classify(&read), Some(label));
+        }
+        assert!(classifier.labels().is_empty());
+    }
+
+    #[test]
+    fn test_router_by_barcode() {
+        let classify = ClassifyArgs {
+            split_by: vec![SplitBy::Barcode],
+            readfish_toml: None,
+            flowcell: Flowcell::Minion,
+            time: TimeArgs::default(),
+        };
+        let classifiers = classify.classifiers(unblocked(), SplitType::All).unwrap();
+        let mut router: Router<usize> = Router::new(classifiers);
+        // Barcodes aren't known up front, so no outputs are created yet
+        assert_eq!(router.known_outputs(), None);
+        assert_eq!(
+            router.field_labels(),
+            [vec![SEQUENCED.to_owned(), UNBLOCKED.to_owned()], vec![]]
+        );
+        router.create_known(|_| Ok(0)).unwrap();
+        for (read_id, barcode) in [
+            ("read_1", Some(b"barcode01".as_slice())),
+            ("read_2", Some(b"barcode01".as_slice())),
+            ("read_3", None),
+        ] {
+            let read = ReadInfo {
+                barcode,
+                ..ReadInfo::new(read_id.as_bytes())
+            };
+            router.route(&read, |_| Ok(0)).unwrap();
+        }
+        assert_eq!(
+            router.to_string(),
+            "Reads written:\n\tunblocked.barcode01: 1\n\tsequenced.barcode01: 1\n\tunblocked.unclassified: 1"
+        );
+        assert_eq!(router.category_totals(), (1, 2));
+    }
+
+    #[test]
+    fn test_router_by_condition() {
+        let mut classify = ClassifyArgs {
+            split_by: vec![SplitBy::Condition],
+            readfish_toml: None,
+            flowcell: Flowcell::Minion,
+            time: TimeArgs::default(),
+        };
+        assert!(classify.classifiers(unblocked(), SplitType::All).is_err());
+        classify.readfish_toml = Some(PathBuf::from("tests/test_readfish.toml"));
+        let classifiers = classify.classifiers(unblocked(), SplitType::All).unwrap();
+        let mut router: Router<usize> = Router::new(classifiers);
+        assert_eq!(router.known_outputs(), Some(6));
+        router.create_known(|_| Ok(0)).unwrap();
+        for (read_id, channel) in [("read_1", Some(1)), ("read_3", Some(65)), ("read_4", None)] {
+            let read = ReadInfo {
+                channel,
+                ..ReadInfo::new(read_id.as_bytes())
+            };
+            router.route(&read, |_| Ok(0)).unwrap();
+        }
+        assert_eq!(
+            router.to_string(),
+            "Reads written:\n\tsequenced.adaptive: 0\n\tsequenced.control: 0\n\tsequenced.unassigned: 1\n\tunblocked.adaptive: 1\n\tunblocked.control: 1\n\tunblocked.unassigned: 0"
+        );
+    }
+
+    #[test]
+    fn test_condition_labels() {
+        let conditions = ChannelConditions::parse(
+            "[[regions]]\nname = \"a b\"\n[[regions]]\nname = \"a_b\"\n[[regions]]\nname = \"unassigned\"\n",
+            Flowcell::Minion,
+        )
+        .unwrap();
+        let classifier = ConditionClassifier::new(conditions);
+        assert_eq!(
+            classifier.labels(),
+            ["a_b", "a_b-2", "unassigned-2", UNASSIGNED]
+        );
+    }
+
+    #[test]
+    fn test_time_classifier() {
+        let range = parse_time_range("10s-1h").unwrap();
+        let mut classifier = TimeClassifier::new(RunClock::new(None), Some(1_800), Some(range));
+        for (start_time, label) in [
+            (Some(b"10.5".as_slice()), Some("0-30m")),
+            (Some(b"2000".as_slice()), Some("30-60m")),
+            (Some(b"5.0".as_slice()), None),
+            (Some(b"3600".as_slice()), None),
+            (Some(b"2024-01-01T10:00:10Z".as_slice()), None),
+            (None, None),
+        ] {
+            let read = ReadInfo {
+                start_time,
+                ..ReadInfo::new(b"read_1")
+            };
+            assert_eq!(classifier.classify(&read), label);
+        }
+        assert_eq!(
+            classifier.report().unwrap(),
+            "Reads without a start time: 2\nReads outside the time range: 2"
+        );
+        let mut classifier = TimeClassifier::new(RunClock::new(None), Some(86_400 + 60), None);
+        let read = ReadInfo {
+            start_time: Some(b"100000"),
+            ..ReadInfo::new(b"read_1")
+        };
+        assert_eq!(classifier.classify(&read), Some("1441-2882m"));
+        let read = ReadInfo {
+            start_time: Some(b"-5"),
+            ..ReadInfo::new(b"read_3")
+        };
+        assert_eq!(classifier.classify(&read), Some("0-1441m"));
+        assert_eq!(
+            classifier.classify(&ReadInfo::new(b"read_2")),
+            Some(NO_START_TIME)
+        );
+        assert_eq!(
+            classifier.report().unwrap(),
+            "Reads without a start time: 1\nReads starting before the run, in the first bin: 1"
+        );
+    }
+
+    #[test]
+    fn test_router_time_range_only() {
+        let classify = ClassifyArgs {
+            split_by: vec![],
+            readfish_toml: None,
+            flowcell: Flowcell::Minion,
+            time: TimeArgs {
+                time_range: Some(parse_time_range("0-1m").unwrap()),
+                ..TimeArgs::default()
+            },
+        };
+        let classifiers = classify.classifiers(unblocked(), SplitType::All).unwrap();
+        let mut router: Router<usize> = Router::new(classifiers);
+        router.create_known(|_| Ok(0)).unwrap();
+        for (read_id, start_time) in [("read_1", "1"), ("read_2", "2"), ("read_3", "99")] {
+            let read = ReadInfo {
+                start_time: Some(start_time.as_bytes()),
+                ..ReadInfo::new(read_id.as_bytes())
+            };
+            router.route(&read, |_| Ok(0)).unwrap();
+        }
+        assert!(router
+            .to_string()
+            .starts_with("Reads written:\n\tsequenced: 1\n\tunblocked: 1\n\tnot written: 1"));
+    }
+
+    #[test]
+    fn test_router_drops_and_creates_lazily() {
+        let classifier = UnblockClassifier::new(unblocked(), SplitType::UnblockedOnly);
+        let mut router: Router<usize> = Router::new(vec![Box::new(classifier)]);
+        assert!(router
+            .route(&ReadInfo::new(b"read_2"), |_| Ok(0))
+            .unwrap()
+            .is_none());
+        let mut created = Vec::new();
+        router
+            .route(&ReadInfo::new(b"read_1"), |name| {
+                created.push(name.to_string());
+                Ok(0)
+            })
+            .unwrap();
+        assert_eq!(created, vec![UNBLOCKED]);
+        assert_eq!(
+            router.to_string(),
+            "Reads written:\n\tunblocked: 1\n\tnot written: 1"
+        );
+    }
+}
@@ -1,8 +1,19 @@
 //! Module for defining the command-line interface (CLI) using the `clap` crate.
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
 use std::path::PathBuf;
 
-use crate::_splitting::{CompressionType, EmitType, SplitType};
+use crate::_splitting::{EmitType, SplitType, UnblockArgs};
+use crate::bam_index::IndexFormat;
+use crate::bloom::parse_fpr;
+use crate::classify::ClassifyArgs;
+use crate::conditions::Flowcell;
+use crate::compress::CompressionArgs;
+use crate::ids::{IdColumn, InputType};
+use crate::output::OutputArgs;
+use crate::reference::ReferenceArgs;
+use crate::tag_bam::parse_tag;
+use crate::targets::TargetArgs;
+use noodles::sam::alignment::record::data::field::Tag;
 
 /// Represents the command-line arguments for the application.
 #[derive(Debug, Parser)]
@@ -19,48 +30,88 @@ pub struct Cli {
 pub enum Commands {
     /// Command to split FASTQ into sequenced and unblocked reads.
     #[clap(about = "Split FASTQ into sequenced and unblocked")]
+    #[clap(group(
+        ArgGroup::new("unblock")
+            .required(true)
+            .multiple(true)
+            .args(&["unblocked-read-ids", "unblock-source", "unblocked-read-ids-file"]),
+    ))]
     SplitFQ {
-        #[clap(short, long, default_value = "")]
-        /// Output file prefix
-        prefix: String,
+        #[clap(flatten)]
+        output: OutputArgs,
+
+        #[clap(flatten)]
+        compression: CompressionArgs,
 
         #[clap(short = 'a', long)]
         /// Write rejected reads as well (default is false)
         write_unblocked: bool,
 
-        // TODO: Maybe accept ONT CSV as either or?
-        #[clap(parse(from_os_str))]
-        /// Unblocked read ids from readfish
-        unblocked_read_ids: PathBuf,
+        #[clap(flatten)]
+        unblock: UnblockArgs,
+
+        #[clap(flatten)]
+        classify: ClassifyArgs,
+
+        #[clap(long, value_name = "RATE", parse(try_from_str = parse_fpr))]
+        /// Check reads against a Bloom filter with this false positive rate before the exact unblocked set
+        bloom_fpr: Option<f64>,
+
+        #[clap(value_name = "UNBLOCKED_READ_IDS", parse(from_os_str))]
+        /// Unblocked read ids from readfish, when neither --unblocked-read-ids nor --unblock-source is given
+        unblocked_read_ids_file: Option<PathBuf>,
 
         #[clap(parse(from_os_str))]
-        /// Input FASTQ files from MinKNOW
+        /// Input FASTQ files from MinKNOW, or - for standard input
         input_fastq: Vec<PathBuf>,
     },
     #[clap(about = "Split Sequenecing summary into sequenced and unblocked")]
+    #[clap(group(
+        ArgGroup::new("unblock")
+            .required(true)
+            .multiple(true)
+            .args(&["unblocked-read-ids", "unblock-source", "unblocked-read-ids-file"]),
+    ))]
     SplitSS {
-        #[clap(short, long, default_value = "")]
-        /// Output file prefix
-        prefix: String,
+        #[clap(flatten)]
+        output: OutputArgs,
 
-        #[clap(parse(from_os_str))]
-        /// Unblocked read ids from readfish
-        unblocked_read_ids: PathBuf,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+
+        #[clap(flatten)]
+        unblock: UnblockArgs,
+
+        #[clap(flatten)]
+        classify: ClassifyArgs,
+
+        #[clap(value_name = "UNBLOCKED_READ_IDS", parse(from_os_str))]
+        /// Unblocked read ids from readfish, when neither --unblocked-read-ids nor --unblock-source is given
+        unblocked_read_ids_file: Option<PathBuf>,
 
         #[clap(parse(from_os_str))]
-        /// sequencing_summary.txt file from MinKNOW
-        sequencing_summary: PathBuf,
+        /// sequencing_summary.txt file from MinKNOW, or - for standard input
+        sequencing_summary: Option<PathBuf>,
     },
     #[clap(about = "Split BAM files into sequenced and unblocked")]
+    #[clap(group(
+        ArgGroup::new("unblock")
+            .required(true)
+            .multiple(true)
+            .args(&["unblocked-read-ids", "unblock-source"]),
+    ))]
     SplitBam {
-        #[clap(short, long, default_value = "")]
-        /// Output file prefix
-        prefix: String,
+        #[clap(flatten)]
+        output: OutputArgs,
+        #[clap(flatten)]
+        unblock: UnblockArgs,
+        #[clap(flatten)]
+        classify: ClassifyArgs,
+        /// Check reads against a Bloom filter with this false positive rate before the exact unblocked set
+        #[clap(long, value_name = "RATE", parse(try_from_str = parse_fpr))]
+        bloom_fpr: Option<f64>,
         #[clap(short, long, parse(from_os_str))]
-        /// Unblocked read ids file from readfish
-        unblocked_read_ids: PathBuf,
-        #[clap(short, long, parse(from_os_str))]
-        /// Bam file containing reads to be split.
+        /// SAM, BAM or CRAM file containing reads to be split, told apart by their contents, or - for standard input.
         bam_file: PathBuf,
         /// Write only sequenced reads, unblocked reads, or both. Default is sequenced only.
         #[clap(short, long, default_value_t, value_enum)]
@@ -71,11 +122,118 @@ pub enum Commands {
         /// minimum length threshold - If set reads shorter than this threshold will be filtered out..
         #[clap(short, long)]
         length_thresh: Option<usize>,
-        /// Write out FASTQ rather than a BAM
+        /// Format of the outputs. SAM is uncompressed unless --compression is set
         #[clap(long, default_value_t, value_enum)]
         emit_type: EmitType,
-        /// Compression type for FASTX output.
-        #[clap(short, long, default_value_t, value_enum)]
-        compression: CompressionType,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+        #[clap(flatten)]
+        reference: ReferenceArgs,
+        #[clap(flatten)]
+        targets: TargetArgs,
+        /// Add @CO lines to the BAM header with a checksum of each unblocked read ids list and the settings used
+        #[clap(long)]
+        header_comments: bool,
+        /// Index each BAM output as it is written, as BAI with --index, or CSI with --index=csi. Needs a coordinate-sorted input
+        #[clap(
+            long,
+            value_enum,
+            value_name = "FORMAT",
+            min_values = 0,
+            require_equals = true,
+            default_missing_value = "bai",
+            conflicts_with = "stdout"
+        )]
+        index: Option<IndexFormat>,
+    },
+    #[clap(about = "Tag the records of a BAM file as sequenced or unblocked")]
+    #[clap(group(
+        ArgGroup::new("unblock")
+            .required(true)
+            .multiple(true)
+            .args(&["unblocked-read-ids", "unblock-source"]),
+    ))]
+    TagBam {
+        #[clap(short, long, parse(from_os_str))]
+        /// Bam file containing the reads to be tagged.
+        bam_file: PathBuf,
+        #[clap(short, long, parse(from_os_str))]
+        /// Path to write the tagged BAM to
+        output: PathBuf,
+        /// Overwrite the output file if it already exists
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        unblock: UnblockArgs,
+        /// Check reads against a Bloom filter with this false positive rate before the exact unblocked set
+        #[clap(long, value_name = "RATE", parse(try_from_str = parse_fpr))]
+        bloom_fpr: Option<f64>,
+        /// readfish TOML giving the conditions, to tag reads readfish didn't evaluate with the condition of their channel
+        #[clap(long, value_name = "PATH", parse(from_os_str))]
+        readfish_toml: Option<PathBuf>,
+        /// Flowcell the run used, which readfish splits between the conditions
+        #[clap(long, value_enum, default_value_t)]
+        flowcell: Flowcell,
+        /// Tag holding whether the read was sequenced or unblocked
+        #[clap(long, default_value = "rf", parse(try_from_str = parse_tag))]
+        tag: Tag,
+        /// Tag holding the readfish condition of the read, when it is known
+        #[clap(long, default_value = "rc", parse(try_from_str = parse_tag))]
+        condition_tag: Tag,
+        /// Add @CO lines to the BAM header with a checksum of each unblocked read ids list and the settings used
+        #[clap(long)]
+        header_comments: bool,
+    },
+    #[clap(about = "Write the read ids in BAM, FASTX or sequencing summary files")]
+    Ids {
+        #[clap(short, long, parse(from_os_str))]
+        /// Path to write the read ids to, standard output if not set
+        output: Option<PathBuf>,
+
+        #[clap(long)]
+        /// Overwrite the output file if it already exists
+        force: bool,
+
+        #[clap(long, value_enum)]
+        /// Type of all of the input files, guessed from the file extensions if not set
+        input_type: Option<InputType>,
+
+        #[clap(short, long, value_enum, use_value_delimiter = true)]
+        /// Comma separated metadata columns to write after each read id, with a header line
+        columns: Vec<IdColumn>,
+
+        #[clap(short, long)]
+        /// Average read quality threshold. If set, reads below this threshold will be filtered out.
+        qual_thresh: Option<usize>,
+
+        #[clap(short, long)]
+        /// Minimum length threshold. If set, reads shorter than this threshold will be filtered out.
+        length_thresh: Option<usize>,
+
+        #[clap(parse(from_os_str), required = true)]
+        /// BAM, FASTA/Q or sequencing summary files
+        inputs: Vec<PathBuf>,
+    },
+    #[clap(about = "Build a sorted on disk index of unblocked read ids")]
+    IndexIds {
+        #[clap(short, long, parse(from_os_str))]
+        /// Path to write the index to
+        output: PathBuf,
+
+        #[clap(long)]
+        /// Overwrite the output file if it already exists
+        force: bool,
+
+        #[clap(long, default_value = "16000000")]
+        /// Number of read ids to sort in memory at once (16 bytes each)
+        chunk_size: usize,
+
+        #[clap(long)]
+        /// Skip and count lines which are not read ids, and read ids which are not UUIDs, rather than stopping with an error
+        lenient: bool,
+
+        #[clap(parse(from_os_str), required = true)]
+        /// Unblocked read ids files from readfish, may be compressed
+        unblocked_read_ids: Vec<PathBuf>,
     },
 }
@@ -0,0 +1,301 @@
+//! Module for compressing the FASTX and TSV outputs of the split commands.
+//!
+//! Outputs can be left uncompressed, or compressed with gzip, BGZF, zstd or xz. BGZF, zstd and
+//! xz compress on several threads, shared between the outputs, and zstd and xz, the formats
+//! usually used for archives, take a compression level. BAM output is always BGZF compressed,
+//! whatever is chosen here.
+//!
+//! # Structs
+//!
+//! - [`CompressionArgs`](struct.CompressionArgs.html): Command line arguments choosing the compression, shared by the split commands.
+//! - [`OutputCompression`](struct.OutputCompression.html): A checked choice of compression, level and threads.
+//!
+//! # Enums
+//!
+//! - [`CompressedWriter`](enum.CompressedWriter.html): A writer to an output file, compressing as chosen.
+//!
+use crate::_splitting::{value_name, CompressionType};
+use crate::output::OutputFile;
+use clap::Args;
+use flate2::{write::GzEncoder, Compression};
+use noodles_bgzf as bgzf;
+use std::{
+    io::{self, BufWriter, Write},
+    num::NonZeroUsize,
+    thread,
+};
+use xz2::{
+    stream::{Check, MtStreamBuilder},
+    write::XzEncoder,
+};
+
+/// Default gzip level, as used by `gzip` itself.
+const GZIP_LEVEL: u32 = 6;
+/// Default xz level, as used by `xz` itself.
+const XZ_LEVEL: u32 = 6;
+
+/// Command line arguments choosing how the outputs are compressed, shared by the split commands.
+#[derive(Debug, Default, Args)]
+pub struct CompressionArgs {
+    /// Compression type for FASTX and sequencing summary output. Default is gzipped for split-bam,
+    /// and uncompressed for split-fq and split-ss. BAM output is always BGZF compressed
+    #[clap(short, long, value_enum)]
+    pub compression: Option<CompressionType>,
+    /// Compression level, 0-9 for gzipped and xz, 1-22 for zstd. Default is 6 for gzipped and
+    /// xz, and 3 for zstd
+    #[clap(long, value_name = "LEVEL")]
+    pub compression_level: Option<u32>,
+    /// Threads to compress bgzipped, zstd or xz outputs on, shared between the outputs. Default is
+    /// all available
+    #[clap(long, value_name = "N")]
+    pub compression_threads: Option<NonZeroUsize>,
+}
+
+impl CompressionArgs {
+    /// Check the chosen compression, using `default` if none was chosen.
+    ///
+    /// # Returns
+    ///
+    /// Returns the compression, or an `io::Error` if the level is out of range for the
+    /// compression, or it doesn't take a level.
+    pub fn resolve(&self, default: CompressionType) -> Result<OutputCompression, io::Error> {
+        let compression = self.compression.clone().unwrap_or(default);
+        let levels = match compression {
+            CompressionType::Gzipped | CompressionType::Xz => Some(0..=9),
+            CompressionType::Zstd => Some(1..=22),
+            CompressionType::Bgzipped | CompressionType::Uncompressed => None,
+        };
+        match (self.compression_level, levels) {
+            (Some(level), Some(levels)) if !levels.contains(&level) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "--compression-level {} is out of range, {} levels are {} to {}",
+                        level,
+                        value_name(&compression),
+                        levels.start(),
+                        levels.end()
+                    ),
+                ))
+            }
+            (Some(_), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "--compression-level can't be set for {} output",
+                        value_name(&compression)
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        Ok(OutputCompression {
+            threads: self
+                .compression_threads
+                .unwrap_or_else(|| thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)),
+            level: self.compression_level,
+            compression,
+        })
+    }
+}
+
+/// A checked choice of compression, level and threads, from [`CompressionArgs::resolve`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputCompression {
+    /// The compression.
+    pub compression: CompressionType,
+    /// The level, `None` for the default of the compression.
+    level: Option<u32>,
+    /// Threads to compress each output on, if the compression can use more than one. All of
+    /// them until [`share`](#method.share) divides them between the outputs.
+    threads: NonZeroUsize,
+}
+
+impl OutputCompression {
+    /// Divide the threads between `outputs` outputs, from [`Router::known_outputs`]. Each
+    /// output is compressed on one thread if the number of outputs isn't known up front, e.g.
+    /// one per barcode, so that many outputs don't each start a pool of every thread.
+    ///
+    /// [`Router::known_outputs`]: crate::classify::Router::known_outputs
+    pub fn share(self, outputs: Option<usize>) -> Self {
+        let threads = outputs
+            .and_then(|outputs| NonZeroUsize::new(self.threads.get() / outputs.max(1)))
+            .unwrap_or(NonZeroUsize::MIN);
+        OutputCompression { threads, ..self }
+    }
+
+    /// Wrap an output file in a writer which compresses it.
+    pub fn writer(&self, file: OutputFile) -> Result<CompressedWriter, io::Error> {
+        let threads = self.threads.get() as u32;
+        Ok(match self.compression {
+            CompressionType::Uncompressed => CompressedWriter::Uncompressed(BufWriter::new(file)),
+            CompressionType::Gzipped => CompressedWriter::Gzipped(GzEncoder::new(
+                BufWriter::new(file),
+                Compression::new(self.level.unwrap_or(GZIP_LEVEL)),
+            )),
+            // Blocks are buffered by the writer, so the file needs no BufWriter
+            CompressionType::Bgzipped => CompressedWriter::Bgzipped(
+                bgzf::MultithreadedWriter::with_worker_count(self.threads, file),
+            ),
+            CompressionType::Zstd => {
+                let level = self
+                    .level
+                    .map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32);
+                let mut encoder = zstd::Encoder::new(BufWriter::new(file), level)?;
+                if threads > 1 {
+                    encoder.multithread(threads)?;
+                }
+                CompressedWriter::Zstd(encoder)
+            }
+            CompressionType::Xz => {
+                let stream = MtStreamBuilder::new()
+                    .threads(threads)
+                    .preset(self.level.unwrap_or(XZ_LEVEL))
+                    .check(Check::Crc64)
+                    .encoder()?;
+                CompressedWriter::Xz(XzEncoder::new_stream(BufWriter::new(file), stream))
+            }
+        })
+    }
+}
+
+/// A writer to an output file, compressing as chosen. [`finish`](#method.finish) must be called
+/// once everything is written, to write the end of the compressed stream.
+pub enum CompressedWriter {
+    /// Uncompressed output.
+    Uncompressed(BufWriter<OutputFile>),
+    /// Gzipped output.
+    Gzipped(GzEncoder<BufWriter<OutputFile>>),
+    /// BGZF compressed output, compressed on several threads.
+    Bgzipped(bgzf::MultithreadedWriter),
+    /// zstd compressed output.
+    Zstd(zstd::Encoder<'static, BufWriter<OutputFile>>),
+    /// xz compressed output.
+    Xz(XzEncoder<BufWriter<OutputFile>>),
+}
+
+impl CompressedWriter {
+    /// Write the end of the compressed stream, and flush everything to the file.
+    pub fn finish(self) -> Result<(), io::Error> {
+        match self {
+            CompressedWriter::Uncompressed(mut writer) => writer.flush(),
+            CompressedWriter::Gzipped(writer) => writer.finish()?.flush(),
+            CompressedWriter::Bgzipped(mut writer) => writer.finish(),
+            CompressedWriter::Zstd(writer) => writer.finish()?.flush(),
+            CompressedWriter::Xz(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Uncompressed(writer) => writer.write(buf),
+            CompressedWriter::Gzipped(writer) => writer.write(buf),
+            CompressedWriter::Bgzipped(writer) => writer.write(buf),
+            CompressedWriter::Zstd(writer) => writer.write(buf),
+            CompressedWriter::Xz(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Uncompressed(writer) => writer.flush(),
+            CompressedWriter::Gzipped(writer) => writer.flush(),
+            CompressedWriter::Bgzipped(writer) => writer.flush(),
+            CompressedWriter::Zstd(writer) => writer.flush(),
+            CompressedWriter::Xz(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_splitting::open_compressed;
+    use crate::test_util::TempPath;
+    use std::{fs::File, io::Read};
+
+    fn args(
+        compression: Option<CompressionType>,
+        compression_level: Option<u32>,
+    ) -> CompressionArgs {
+        CompressionArgs {
+            compression,
+            compression_level,
+            compression_threads: NonZeroUsize::new(2),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = "read_id\tchannel\n".repeat(1000);
+        for compression in [
+            CompressionType::Uncompressed,
+            CompressionType::Gzipped,
+            CompressionType::Bgzipped,
+            CompressionType::Zstd,
+            CompressionType::Xz,
+        ] {
+            let path = TempPath::new(&format!("compress_{:?}", compression));
+            let compression = args(Some(compression), None)
+                .resolve(CompressionType::Uncompressed)
+                .unwrap();
+            let mut writer = compression
+                .writer(File::create(&path).unwrap().into())
+                .unwrap();
+            writer.write_all(text.as_bytes()).unwrap();
+            writer.finish().unwrap();
+
+            let mut written = String::new();
+            match compression.compression {
+                CompressionType::Xz => xz2::read::XzDecoder::new(File::open(&path).unwrap())
+                    .read_to_string(&mut written)
+                    .unwrap(),
+                _ => open_compressed(&path)
+                    .unwrap()
+                    .read_to_string(&mut written)
+                    .unwrap(),
+            };
+            assert_eq!(written, text, "{:?}", compression.compression);
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            args(None, None)
+                .resolve(CompressionType::Gzipped)
+                .unwrap()
+                .compression,
+            CompressionType::Gzipped
+        );
+        assert!(args(Some(CompressionType::Zstd), Some(19))
+            .resolve(CompressionType::Gzipped)
+            .is_ok());
+        assert!(args(Some(CompressionType::Zstd), Some(0))
+            .resolve(CompressionType::Gzipped)
+            .is_err());
+        assert!(args(Some(CompressionType::Xz), Some(10))
+            .resolve(CompressionType::Gzipped)
+            .is_err());
+        assert!(args(None, Some(1))
+            .resolve(CompressionType::Uncompressed)
+            .is_err());
+        assert!(args(Some(CompressionType::Bgzipped), Some(1))
+            .resolve(CompressionType::Gzipped)
+            .is_err());
+    }
+
+    #[test]
+    fn test_share() {
+        let mut args = args(Some(CompressionType::Zstd), None);
+        args.compression_threads = NonZeroUsize::new(8);
+        let compression = args.resolve(CompressionType::Gzipped).unwrap();
+        let threads = |outputs| compression.clone().share(outputs).threads.get();
+        assert_eq!(threads(Some(1)), 8);
+        assert_eq!(threads(Some(3)), 2);
+        assert_eq!(threads(Some(12)), 1);
+        assert_eq!(threads(None), 1);
+    }
+}
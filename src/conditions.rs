@@ -0,0 +1,268 @@
+//! Module for the channel layout of the conditions in a readfish TOML.
+//!
+//! readfish divides the channels of a flowcell into conditions, e.g. a control half and an
+//! adaptive half, each with its own targets. The conditions are the `[[regions]]` tables of a
+//! readfish TOML, or the `[conditions.N]` tables of older configs, in order of `N`. A TOML
+//! doesn't list the channels of each condition: readfish lays the channels out in rows and
+//! columns as they are on the flowcell, and splits them into as many equal parts as there are
+//! conditions, side by side along the columns (`split_axis = 1`, the default) or one above
+//! the other along the rows (`split_axis = 0`, or `axis` in the `[conditions]` table of older
+//! configs). The first condition gets the first part, and so on.
+//!
+//! ```toml
+//! split_axis = 1
+//!
+//! [[regions]]
+//! name = "adaptive"
+//! targets = ["chr20", "chr21"]
+//! # ...
+//!
+//! [[regions]]
+//! name = "control"
+//! targets = []
+//! # ...
+//! ```
+//!
+//! # Enums
+//!
+//! - [`Flowcell`](enum.Flowcell.html): The layout of the channels of a flowcell.
+//!
+//! # Structs
+//!
+//! - [`ChannelConditions`](struct.ChannelConditions.html): The condition of each channel of a flowcell.
+//!
+use clap::ValueEnum;
+use std::{fs, io, path::Path};
+
+/// The layout of the channels of a flowcell, which readfish splits into conditions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Flowcell {
+    /// MinION and GridION flowcells, 512 channels in 16 rows of 32.
+    #[default]
+    Minion,
+    /// Flongle flowcells, 126 channels in 9 rows of 14.
+    Flongle,
+    /// PromethION flowcells, 3000 channels in 25 rows of 120.
+    Promethion,
+}
+
+impl Flowcell {
+    /// Number of channels.
+    fn channels(&self) -> u32 {
+        match self {
+            Flowcell::Minion => 512,
+            Flowcell::Flongle => 126,
+            Flowcell::Promethion => 3000,
+        }
+    }
+
+    /// Number of rows and columns of channels.
+    fn shape(&self) -> (usize, usize) {
+        match self {
+            Flowcell::Minion => (16, 32),
+            Flowcell::Flongle => (9, 14),
+            Flowcell::Promethion => (25, 120),
+        }
+    }
+
+    /// Row and column of a channel, counted from the top left of the flowcell.
+    fn position(&self, channel: u32) -> (usize, usize) {
+        let idx = (channel - 1) as usize;
+        match self {
+            // Four blocks of 128 channels one above the other, each filled with columns of four
+            // channels from the right
+            Flowcell::Minion => (idx / 128 * 4 + idx % 4, 31 - idx % 128 / 4),
+            // Columns of nine channels from the left
+            Flowcell::Flongle => (idx % 9, idx / 9),
+            // Twelve blocks of 250 channels side by side, each filled with rows of ten channels
+            Flowcell::Promethion => (idx % 250 / 10, idx / 250 * 10 + idx % 10),
+        }
+    }
+}
+
+/// The condition of each channel of a flowcell, from a readfish TOML.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelConditions {
+    /// Condition names, in the order they are given.
+    names: Vec<String>,
+    /// Index into `names` for each channel number.
+    by_channel: Vec<Option<u16>>,
+}
+
+impl ChannelConditions {
+    /// Read the conditions from a readfish TOML file, laid out on `flowcell`.
+    pub fn from_toml(path: &Path, flowcell: Flowcell) -> Result<Self, io::Error> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text, flowcell).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Parse the conditions from the text of a readfish TOML, laid out on `flowcell`.
+    pub fn parse(text: &str, flowcell: Flowcell) -> Result<Self, String> {
+        let config: toml::Table = text.parse().map_err(|err| format!("{}", err))?;
+        let (tables, axis) = match (config.get("regions"), config.get("conditions")) {
+            (Some(toml::Value::Array(regions)), _) => (
+                regions
+                    .iter()
+                    .map(|region| region.as_table().ok_or("regions must be tables"))
+                    .collect::<Result<Vec<_>, _>>()?,
+                config.get("split_axis"),
+            ),
+            (_, Some(toml::Value::Table(conditions))) => {
+                let mut numbered = Vec::new();
+                for (key, value) in conditions {
+                    if let toml::Value::Table(table) = value {
+                        let number: u32 = key.parse().map_err(|_| {
+                            format!("condition keys must be numbers, not {}", key)
+                        })?;
+                        numbered.push((number, table));
+                    }
+                }
+                numbered.sort_by_key(|(number, _)| *number);
+                (
+                    numbered.into_iter().map(|(_, table)| table).collect(),
+                    conditions.get("axis"),
+                )
+            }
+            _ => return Err("no [[regions]] or [conditions] tables".to_owned()),
+        };
+        if tables.is_empty() {
+            return Err("no conditions are defined".to_owned());
+        }
+        let axis = match axis {
+            None => 1,
+            Some(toml::Value::Integer(axis @ (0 | 1))) => *axis as usize,
+            Some(axis) => return Err(format!("the split axis must be 0 or 1, not {}", axis)),
+        };
+        let names = tables
+            .into_iter()
+            .enumerate()
+            .map(|(idx, table)| match table.get("name") {
+                Some(toml::Value::String(name)) => Ok(name.clone()),
+                _ => Err(format!("condition {} has no name", idx + 1)),
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(Self::split(names, flowcell, axis))
+    }
+
+    /// Split the channels of `flowcell` between the conditions along `axis`, 0 for the rows
+    /// and 1 for the columns, as readfish does.
+    fn split(names: Vec<String>, flowcell: Flowcell, axis: usize) -> Self {
+        let (rows, columns) = flowcell.shape();
+        let len = if axis == 0 { rows } else { columns };
+        let mut by_channel = vec![None; flowcell.channels() as usize + 1];
+        for channel in 1..=flowcell.channels() {
+            let (row, column) = flowcell.position(channel);
+            let position = if axis == 0 { row } else { column };
+            by_channel[channel as usize] = Some(section(position, len, names.len()) as u16);
+        }
+        ChannelConditions { names, by_channel }
+    }
+
+    /// The condition names, in the order they are given.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Index into [`names`](#method.names) of the condition a channel is in, `None` if it
+    /// isn't in any.
+    pub fn condition(&self, channel: u32) -> Option<usize> {
+        let idx = (*self.by_channel.get(channel as usize)?)?;
+        Some(usize::from(idx))
+    }
+}
+
+/// The part `position` falls in when `len` positions are split into `n` parts as evenly as
+/// possible, the first parts one larger than the rest, as numpy's `array_split` does.
+fn section(position: usize, len: usize, n: usize) -> usize {
+    let (size, larger) = (len / n, len % n);
+    let in_larger = larger * (size + 1);
+    if position < in_larger {
+        position / (size + 1)
+    } else {
+        larger + (position - in_larger) / size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        let conditions =
+            ChannelConditions::from_toml(Path::new("tests/test_readfish.toml"), Flowcell::Minion)
+                .unwrap();
+        assert_eq!(conditions.names(), ["adaptive", "control"]);
+        // The left half of the columns is the first condition
+        assert_eq!(conditions.condition(1), Some(1));
+        assert_eq!(conditions.condition(64), Some(1));
+        assert_eq!(conditions.condition(65), Some(0));
+        assert_eq!(conditions.condition(128), Some(0));
+        assert_eq!(conditions.condition(129), Some(1));
+        assert_eq!(conditions.condition(512), Some(0));
+        assert_eq!(conditions.condition(0), None);
+        assert_eq!(conditions.condition(513), None);
+    }
+
+    #[test]
+    fn test_layouts() {
+        let two = "[[regions]]\nname = \"a\"\n[[regions]]\nname = \"b\"\n";
+        let conditions = ChannelConditions::parse(two, Flowcell::Promethion).unwrap();
+        assert_eq!(conditions.condition(1), Some(0));
+        assert_eq!(conditions.condition(1500), Some(0));
+        assert_eq!(conditions.condition(1501), Some(1));
+        assert_eq!(conditions.condition(3000), Some(1));
+        // Split along the rows instead, the top half of a MinION is the first two blocks
+        let conditions =
+            ChannelConditions::parse(&format!("split_axis = 0\n{}", two), Flowcell::Minion)
+                .unwrap();
+        assert_eq!(conditions.condition(256), Some(0));
+        assert_eq!(conditions.condition(257), Some(1));
+        // 14 columns into three conditions of 5, 5 and 4 columns
+        let three = format!("{}[[regions]]\nname = \"c\"\n", two);
+        let conditions = ChannelConditions::parse(&three, Flowcell::Flongle).unwrap();
+        assert_eq!(conditions.condition(45), Some(0));
+        assert_eq!(conditions.condition(46), Some(1));
+        assert_eq!(conditions.condition(90), Some(1));
+        assert_eq!(conditions.condition(91), Some(2));
+        assert_eq!(conditions.condition(126), Some(2));
+        assert_eq!(conditions.condition(127), None);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let conditions = ChannelConditions::parse(
+            "[conditions]\nreference = \"hg38.mmi\"\naxis = 0\n[conditions.10]\nname = \"third\"\n[conditions.2]\nname = \"second\"\n[conditions.0]\nname = \"first\"\n",
+            Flowcell::Minion,
+        )
+        .unwrap();
+        assert_eq!(conditions.names(), ["first", "second", "third"]);
+        // 16 rows into three conditions of 6, 5 and 5 rows
+        assert_eq!(conditions.condition(1), Some(0));
+        assert_eq!(conditions.condition(130), Some(0));
+        assert_eq!(conditions.condition(131), Some(1));
+        assert_eq!(conditions.condition(512), Some(2));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for text in [
+            "",
+            "[[regions]]\ntargets = []\n",
+            "split_axis = 2\n[[regions]]\nname = \"a\"\n",
+            "[conditions]\n[conditions.first]\nname = \"a\"\n",
+            "[conditions]\nreference = \"hg38.mmi\"\n",
+        ] {
+            assert!(
+                ChannelConditions::parse(text, Flowcell::Minion).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
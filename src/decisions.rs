@@ -0,0 +1,316 @@
+//! Module for reading the per read decisions from a readfish chunk log.
+//!
+//! readfish writes a TSV line for every chunk of every read it evaluates, including the
+//! `decision` made for the chunk (`unblock`, `stop_receiving` or `proceed`) and the
+//! `condition` (region or barcode) the read was assigned to. A read is evaluated until it is
+//! unblocked or readfish stops receiving data for it, so the final decision tells apart reads
+//! which were rejected, accepted, never given a final decision, or never seen by readfish at all.
+//!
+//! # Enums
+//!
+//! - [`Decision`](enum.Decision.html): The action readfish took on a chunk.
+//!
+//! # Structs
+//!
+//! - [`ReadDecision`](struct.ReadDecision.html): The final decision and condition for a read.
+//! - [`ReadDecisions`](struct.ReadDecisions.html): Final decisions for every read in a chunk log.
+//! - [`DecisionTally`](struct.DecisionTally.html): Counts of split reads by condition and final decision.
+//!
+//! # Functions
+//!
+//! - [`read_decision_log`](fn.read_decision_log.html): Reduce a chunk log to the final decision for each read.
+//!
+use crate::_splitting::{get_key_col, open_compressed};
+use crate::read_ids::{MemoryReport, ReadIdMap};
+use fnv::FnvHashMap;
+use std::{fmt, io, path::PathBuf};
+
+/// All decisions, in the order they are reported.
+pub const DECISIONS: [Decision; 3] = [
+    Decision::Unblock,
+    Decision::StopReceiving,
+    Decision::Proceed,
+];
+
+/// The action readfish took on a chunk of a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Decision {
+    /// The read was rejected, and ejected from the pore.
+    Unblock,
+    /// The read was accepted, and readfish stopped receiving data for it.
+    StopReceiving,
+    /// No decision was made on the chunk, readfish waits for more data.
+    Proceed,
+}
+
+impl Decision {
+    /// Parse a decision as written in the chunk log.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"unblock" => Some(Decision::Unblock),
+            b"stop_receiving" => Some(Decision::StopReceiving),
+            b"proceed" => Some(Decision::Proceed),
+            _ => None,
+        }
+    }
+
+    /// The decision as written in the chunk log.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Unblock => "unblock",
+            Decision::StopReceiving => "stop_receiving",
+            Decision::Proceed => "proceed",
+        }
+    }
+
+    /// Position of the decision in [`DECISIONS`].
+    fn index(&self) -> usize {
+        match self {
+            Decision::Unblock => 0,
+            Decision::StopReceiving => 1,
+            Decision::Proceed => 2,
+        }
+    }
+
+    /// Whether readfish stops evaluating a read after this decision.
+    fn is_final(&self) -> bool {
+        !matches!(self, Decision::Proceed)
+    }
+}
+
+/// The final decision for a read, and the condition it was assigned to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadDecision {
+    /// The final decision.
+    pub decision: Decision,
+    /// Index of the condition name in `ReadDecisions::conditions`.
+    condition: u16,
+}
+
+/// The final decision for every read in a readfish chunk log.
+#[derive(Debug, Default)]
+pub struct ReadDecisions {
+    /// Final decision for each read.
+    reads: ReadIdMap<ReadDecision>,
+    /// Condition names, indexed by `ReadDecision::condition`.
+    conditions: Vec<String>,
+    /// Number of reads whose final decision was unblock.
+    n_unblocked: usize,
+}
+
+impl ReadDecisions {
+    /// The final decision for a read, `None` if readfish never evaluated it.
+    pub fn get<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> Option<&ReadDecision> {
+        self.reads.get(id)
+    }
+
+    /// The name of the condition a read was assigned to.
+    pub fn condition(&self, read: &ReadDecision) -> &str {
+        &self.conditions[usize::from(read.condition)]
+    }
+
+    /// Whether the final decision for a read was unblock.
+    pub fn is_unblocked<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> bool {
+        matches!(self.get(id), Some(read) if read.decision == Decision::Unblock)
+    }
+
+    /// Bloom filter hashes of the reads whose final decision was unblock.
+    pub fn unblocked_hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.reads
+            .hashes()
+            .filter(|(_, read)| read.decision == Decision::Unblock)
+            .map(|(hash, _)| hash)
+    }
+
+    /// The reads whose final decision was unblock, as UUIDs and other IDs, in no particular
+    /// order.
+    pub fn unblocked_ids(&self) -> (Vec<u128>, Vec<&[u8]>) {
+        self.reads.ids(|read| read.decision == Decision::Unblock)
+    }
+
+    /// Number of reads whose final decision was unblock.
+    pub fn n_unblocked(&self) -> usize {
+        self.n_unblocked
+    }
+
+    /// Estimate the heap memory used.
+    pub fn memory_report(&self) -> MemoryReport {
+        self.reads.memory_report()
+    }
+
+    /// An empty tally of reads by condition and final decision.
+    pub fn tally(&self) -> DecisionTally {
+        DecisionTally {
+            conditions: self.conditions.clone(),
+            counts: vec![[0; 3]; self.conditions.len()],
+            not_evaluated: 0,
+        }
+    }
+}
+
+/// Reduce a readfish chunk log to the final decision and condition for each read.
+///
+/// The log is read in order. A `proceed` never replaces an `unblock` or `stop_receiving`
+/// for the same read, so the final decision is the last terminal decision made, or `proceed`
+/// if readfish never reached one. The log may be compressed.
+///
+/// # Arguments
+///
+/// * `path` - The path to the readfish chunk log.
+///
+/// # Returns
+///
+/// Returns a `Result` containing `ReadDecisions` or an `io::Error`, which reports the line
+/// number of any unrecognised decision.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let decisions = read_decision_log(PathBuf::from("readfish_chunks.tsv"))?;
+/// if let Some(read) = decisions.get("0afabd54-d901-473f-94ba-c7a6e69716f5") {
+///     println!("{}", read.decision.as_str());
+/// }
+/// ```
+pub fn read_decision_log(path: PathBuf) -> Result<ReadDecisions, io::Error> {
+    let invalid = |msg: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(open_compressed(&path)?);
+    let headers = rdr.byte_headers()?;
+    let read_id_col = get_key_col(headers, b"read_id").map_err(invalid)?;
+    let decision_col = get_key_col(headers, b"decision").map_err(invalid)?;
+    let condition_col = get_key_col(headers, b"condition").map_err(invalid)?;
+
+    let mut decisions = ReadDecisions::default();
+    let mut condition_idx: FnvHashMap<Vec<u8>, u16> = FnvHashMap::default();
+    let mut row = csv::ByteRecord::new();
+    while rdr.read_byte_record(&mut row)? {
+        let decision = Decision::from_bytes(&row[decision_col]).ok_or_else(|| {
+            invalid(format!(
+                "unrecognised decision {:?} on line {}",
+                String::from_utf8_lossy(&row[decision_col]),
+                row.position().map_or(0, |p| p.line())
+            ))
+        })?;
+        let condition = match condition_idx.get(&row[condition_col]) {
+            Some(idx) => *idx,
+            None => {
+                let idx = u16::try_from(decisions.conditions.len())
+                    .map_err(|_| invalid("too many conditions".to_owned()))?;
+                let name = String::from_utf8_lossy(&row[condition_col]).into_owned();
+                decisions.conditions.push(name);
+                condition_idx.insert(row[condition_col].to_vec(), idx);
+                idx
+            }
+        };
+        let read = ReadDecision {
+            decision,
+            condition,
+        };
+        match decisions.reads.get_mut(&row[read_id_col]) {
+            Some(existing) => {
+                if decision.is_final() || !existing.decision.is_final() {
+                    *existing = read;
+                }
+            }
+            None => {
+                decisions.reads.insert(&row[read_id_col], read);
+            }
+        }
+    }
+    decisions.n_unblocked = decisions
+        .reads
+        .values()
+        .filter(|read| read.decision == Decision::Unblock)
+        .count();
+    Ok(decisions)
+}
+
+/// Counts of split reads by condition and final readfish decision, so that accepted reads
+/// can be told apart from reads readfish never evaluated. Created by [`ReadDecisions::tally`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecisionTally {
+    /// Condition names.
+    conditions: Vec<String>,
+    /// Counts for each condition, indexed by [`Decision::index`].
+    counts: Vec<[usize; 3]>,
+    /// Reads that readfish never evaluated.
+    not_evaluated: usize,
+}
+
+impl DecisionTally {
+    /// Count a read by its final decision, `None` if readfish never evaluated it.
+    pub fn add(&mut self, read: Option<&ReadDecision>) {
+        match read {
+            Some(read) => self.counts[read.condition as usize][read.decision.index()] += 1,
+            None => self.not_evaluated += 1,
+        }
+    }
+}
+
+impl fmt::Display for DecisionTally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Final readfish decisions:")?;
+        for (condition, counts) in self.conditions.iter().zip(&self.counts) {
+            write!(f, "\n\t{}:", condition)?;
+            for decision in DECISIONS {
+                write!(f, " {} {}", decision.as_str(), counts[decision.index()])?;
+            }
+        }
+        write!(f, "\n\tnever evaluated: {}", self.not_evaluated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_decision_log() {
+        let decisions = read_decision_log(PathBuf::from("tests/test_readfish_chunks.tsv")).unwrap();
+        assert_eq!(decisions.n_unblocked(), 1);
+
+        // proceed, then unblock
+        let read = decisions.get("0afabd54-d901-473f-94ba-c7a6e69716f5").unwrap();
+        assert_eq!(read.decision, Decision::Unblock);
+        assert_eq!(decisions.conditions[read.condition as usize], "adaptive");
+        assert!(decisions.is_unblocked("0afabd54-d901-473f-94ba-c7a6e69716f5"));
+
+        // stop_receiving, then a late proceed which must not replace it
+        let read = decisions.get("8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e").unwrap();
+        assert_eq!(read.decision, Decision::StopReceiving);
+        assert_eq!(decisions.conditions[read.condition as usize], "adaptive");
+
+        // only ever proceed
+        let read = decisions.get("5b1e2c0a-3f7d-4e8a-9c61-2d4f8e7a1b93").unwrap();
+        assert_eq!(read.decision, Decision::Proceed);
+        assert_eq!(decisions.conditions[read.condition as usize], "control");
+
+        // never evaluated
+        assert!(decisions.get("c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e").is_none());
+        assert!(!decisions.is_unblocked("c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e"));
+    }
+
+    #[test]
+    fn test_decision_tally() {
+        let decisions = read_decision_log(PathBuf::from("tests/test_readfish_chunks.tsv")).unwrap();
+        let mut tally = decisions.tally();
+        for read_id in [
+            "0afabd54-d901-473f-94ba-c7a6e69716f5",
+            "8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e",
+            "5b1e2c0a-3f7d-4e8a-9c61-2d4f8e7a1b93",
+            "c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e",
+        ] {
+            tally.add(decisions.get(read_id));
+        }
+        assert_eq!(
+            tally.to_string(),
+            "Final readfish decisions:\n\tadaptive: unblock 1 stop_receiving 1 proceed 0\n\tcontrol: unblock 0 stop_receiving 0 proceed 1\n\tnever evaluated: 1"
+        );
+    }
+}
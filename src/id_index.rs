@@ -0,0 +1,505 @@
+//! Module for the disk backed, sorted read ID index written by `rftools index-ids`.
+//!
+//! Some unblocked lists are too large to hold in memory, even packed into a [`ReadIdSet`].
+//! This module builds a sorted binary index of the read IDs, which the split commands can
+//! use in place of the text file. Only one key per 4 KiB block is kept in memory, so
+//! looking up a read costs a binary search in memory followed by a single block read.
+//!
+//! The index is built with an external merge sort, so building it is also bounded in memory.
+//!
+//! # Layout
+//!
+//! All integers are little endian. The UUID array starts 16 byte aligned, so the file can be
+//! memory mapped by other tools.
+//!
+//! | Offset | Contents                                                              |
+//! |--------|-----------------------------------------------------------------------|
+//! | 0      | Magic bytes `RFIDX001`                                                |
+//! | 8      | Number of UUIDs, `u64`                                                |
+//! | 16     | Sorted, deduplicated UUIDs, `u128` each                               |
+//! | ...    | Fence keys: the first UUID of every block of `BLOCK_LEN` UUIDs        |
+//!
+//! Only UUIDs are indexed, so a list holding other read IDs is an error, or with `--lenient`
+//! they are skipped with a warning. Such lists can still be used as they are.
+//!
+//! # Functions
+//!
+//! - [`index_ids`](fn.index_ids.html): Build an index from one or more unblocked_read_ids files.
+//! - [`is_index`](fn.is_index.html): Check whether a file is a read ID index.
+//!
+//! [`ReadIdSet`]: crate::read_ids::ReadIdSet
+use crate::_splitting::{open_compressed, parse_read_id_lines};
+use crate::bloom::hash_uuid;
+use crate::output::OutputFiles;
+use crate::read_ids::{parse_uuid, MemoryReport};
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// Magic bytes at the start of a read ID index.
+const INDEX_MAGIC: &[u8; 8] = b"RFIDX001";
+/// Size of the index header in bytes.
+const HEADER_LEN: u64 = 16;
+/// Size of a packed UUID in bytes.
+const UUID_BYTES: usize = mem::size_of::<u128>();
+/// Number of UUIDs in a block, one block (4 KiB) is read from disk per lookup.
+const BLOCK_LEN: usize = 256;
+
+/// Check whether the file at `path` is a read ID index, by its magic bytes.
+pub fn is_index(path: &Path) -> Result<bool, io::Error> {
+    let mut magic = [0; 8];
+    let mut file = File::open(path)?;
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(&magic == INDEX_MAGIC)
+}
+
+/// Build a read ID index from one or more unblocked_read_ids files.
+///
+/// UUIDs are sorted in chunks of `chunk_size`, each sorted chunk is spilled to a temporary run
+/// file next to `output`, and the runs are then merged into the index. The lines of each file
+/// are validated as for the split commands.
+///
+/// # Arguments
+///
+/// * `inputs` - The unblocked_read_ids files, plain text or compressed.
+/// * `output` - The path to write the index to.
+/// * `force` - Overwrite `output` if it already exists.
+/// * `chunk_size` - The number of UUIDs to sort in memory at once.
+/// * `lenient` - Skip and count lines which are not read IDs, rather than failing.
+///
+/// # Returns
+///
+/// Returns `Result<(), io::Error>`, an error if `output` already exists and overwriting wasn't
+/// allowed. The index is written to a temporary file, and only moved to `output` once complete.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let inputs = vec![PathBuf::from("fc1/unblocked_read_ids.txt.gz"), PathBuf::from("fc2/unblocked_read_ids.txt.gz")];
+/// index_ids(inputs, PathBuf::from("unblocked.rfidx"), false, 16_000_000, false).unwrap();
+/// ```
+pub fn index_ids(
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    force: bool,
+    chunk_size: usize,
+    lenient: bool,
+) -> Result<(), io::Error> {
+    let files = OutputFiles::single(&output, force);
+    let out = files.create("")?;
+    let chunk_size = chunk_size.max(1);
+    let mut chunk: Vec<u128> = Vec::new();
+    let mut runs: Vec<PathBuf> = vec![];
+
+    let result = (|| {
+        for input in &inputs {
+            let reader = open_compressed(input)?;
+            let mut not_uuids = 0;
+            parse_read_id_lines(reader, input, lenient, |id| {
+                match parse_uuid(id) {
+                    Some(uuid) => chunk.push(uuid),
+                    None if lenient => not_uuids += 1,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{}: read id {} is not a UUID, only UUIDs can be indexed. Use --lenient to skip them",
+                                input.display(),
+                                String::from_utf8_lossy(id)
+                            ),
+                        ))
+                    }
+                }
+                if chunk.len() >= chunk_size {
+                    runs.push(write_run(&output, runs.len(), &mut chunk)?);
+                }
+                Ok(())
+            })?;
+            if not_uuids > 0 {
+                eprintln!(
+                    "Warning: skipped {} read ids in {} which are not UUIDs, only UUIDs can be indexed",
+                    not_uuids,
+                    input.display()
+                );
+            }
+        }
+        if runs.is_empty() {
+            chunk.sort_unstable();
+            return write_index(out, chunk.drain(..).map(Ok));
+        }
+        if !chunk.is_empty() {
+            runs.push(write_run(&output, runs.len(), &mut chunk)?);
+        }
+        write_index(out, RunMerger::new(&runs)?)
+    })();
+    // Clean up the runs whether or not the index was written
+    for run in &runs {
+        let _ = fs::remove_file(run);
+    }
+    let n_uuids = result?;
+    files.commit()?;
+    eprintln!("Indexed {} read ids into {}", n_uuids, output.display());
+    Ok(())
+}
+
+/// Sort a chunk of UUIDs and spill it to a temporary run file next to `output`.
+fn write_run(output: &Path, n: usize, chunk: &mut Vec<u128>) -> Result<PathBuf, io::Error> {
+    chunk.sort_unstable();
+    chunk.dedup();
+    let mut run_path = output.as_os_str().to_owned();
+    run_path.push(format!(".run{}.tmp", n));
+    let run_path = PathBuf::from(run_path);
+    let mut writer = BufWriter::new(File::create(&run_path)?);
+    for uuid in chunk.drain(..) {
+        writer.write_all(&uuid.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(run_path)
+}
+
+/// Read the next UUID from a run file, `None` at the end of the run.
+fn read_uuid<R: Read>(reader: &mut R) -> Result<Option<u128>, io::Error> {
+    let mut buf = [0; UUID_BYTES];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(u128::from_le_bytes(buf))),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// K-way merge of sorted run files, yielding UUIDs in ascending order.
+struct RunMerger {
+    readers: Vec<BufReader<File>>,
+    heap: BinaryHeap<Reverse<(u128, usize)>>,
+}
+
+impl RunMerger {
+    fn new(runs: &[PathBuf]) -> Result<Self, io::Error> {
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(run)?);
+            if let Some(uuid) = read_uuid(&mut reader)? {
+                heap.push(Reverse((uuid, i)));
+            }
+            readers.push(reader);
+        }
+        Ok(RunMerger { readers, heap })
+    }
+}
+
+impl Iterator for RunMerger {
+    type Item = Result<u128, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((uuid, i)) = self.heap.pop()?;
+        match read_uuid(&mut self.readers[i]) {
+            Ok(Some(next)) => self.heap.push(Reverse((next, i))),
+            Ok(None) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        Some(Ok(uuid))
+    }
+}
+
+/// Write the index from ascending UUIDs, returning the number of UUIDs.
+fn write_index<W, I>(output: W, uuids: I) -> Result<u64, io::Error>
+where
+    W: Write + Seek,
+    I: Iterator<Item = Result<u128, io::Error>>,
+{
+    let mut writer = BufWriter::new(output);
+    // Placeholder header, filled in once the counts are known
+    writer.write_all(&[0; HEADER_LEN as usize])?;
+
+    let mut n_uuids: u64 = 0;
+    let mut previous: Option<u128> = None;
+    let mut fences: Vec<u128> = vec![];
+    for uuid in uuids {
+        let uuid = uuid?;
+        if previous == Some(uuid) {
+            continue;
+        }
+        if (n_uuids as usize).is_multiple_of(BLOCK_LEN) {
+            fences.push(uuid);
+        }
+        writer.write_all(&uuid.to_le_bytes())?;
+        previous = Some(uuid);
+        n_uuids += 1;
+    }
+    for fence in fences {
+        writer.write_all(&fence.to_le_bytes())?;
+    }
+
+    let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(INDEX_MAGIC)?;
+    file.write_all(&n_uuids.to_le_bytes())?;
+    file.flush()?;
+    Ok(n_uuids)
+}
+
+/// A read ID index opened for lookups.
+///
+/// The fence keys are held in memory, the UUIDs stay on disk.
+#[derive(Debug)]
+pub struct ReadIdIndex {
+    /// The open index file.
+    file: RefCell<File>,
+    /// Number of UUIDs in the index.
+    n_uuids: usize,
+    /// First UUID of every block.
+    fences: Vec<u128>,
+    /// Buffer a block is read into.
+    block: RefCell<Vec<u8>>,
+}
+
+impl ReadIdIndex {
+    /// Open a read ID index written by [`index_ids`].
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let mut file = File::open(path)?;
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[..8] != INDEX_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an rftools read id index", path.display()),
+            ));
+        }
+        let n_uuids = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+
+        let n_fences = n_uuids.div_ceil(BLOCK_LEN);
+        file.seek(SeekFrom::Start(HEADER_LEN + (n_uuids * UUID_BYTES) as u64))?;
+        let mut reader = BufReader::new(file);
+        let mut fences = Vec::with_capacity(n_fences);
+        for _ in 0..n_fences {
+            match read_uuid(&mut reader)? {
+                Some(fence) => fences.push(fence),
+                None => return Err(truncated(path)),
+            }
+        }
+
+        Ok(ReadIdIndex {
+            file: RefCell::new(reader.into_inner()),
+            n_uuids,
+            fences,
+            block: RefCell::new(vec![0; BLOCK_LEN * UUID_BYTES]),
+        })
+    }
+
+    /// Check whether a read ID is in the index. Read IDs which are not UUIDs never are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index file can no longer be read.
+    pub fn contains<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> bool {
+        match parse_uuid(id.as_ref()) {
+            Some(uuid) => self
+                .contains_uuid(uuid)
+                .expect("Couldn't read from read id index"),
+            None => false,
+        }
+    }
+
+    /// Binary search the fences for the block that could hold `uuid`, then search the block.
+    fn contains_uuid(&self, uuid: u128) -> Result<bool, io::Error> {
+        let block_idx = match self.fences.partition_point(|fence| *fence <= uuid) {
+            0 => return Ok(false),
+            n => n - 1,
+        };
+        if self.fences[block_idx] == uuid {
+            return Ok(true);
+        }
+        let start = block_idx * BLOCK_LEN;
+        let len = BLOCK_LEN.min(self.n_uuids - start);
+        let mut block = self.block.borrow_mut();
+        let block = &mut block[..len * UUID_BYTES];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(HEADER_LEN + (start * UUID_BYTES) as u64))?;
+        file.read_exact(block)?;
+
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let key = &block[mid * UUID_BYTES..(mid + 1) * UUID_BYTES];
+            let key = u128::from_le_bytes(key.try_into().unwrap());
+            match key.cmp(&uuid) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Call `f` with every UUID in sorted order, reading the UUIDs through once.
+    pub fn for_each_uuid<F: FnMut(u128)>(&self, mut f: F) -> Result<(), io::Error> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut reader = BufReader::new(&*file);
+        for _ in 0..self.n_uuids {
+            match read_uuid(&mut reader)? {
+                Some(uuid) => f(uuid),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "read id index is truncated",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `f` with the Bloom filter hash of every read ID, reading the UUIDs through once.
+    pub fn for_each_hash<F: FnMut(u64)>(&self, mut f: F) -> Result<(), io::Error> {
+        self.for_each_uuid(|uuid| f(hash_uuid(uuid)))
+    }
+
+    /// Number of read IDs in the index.
+    pub fn len(&self) -> usize {
+        self.n_uuids
+    }
+
+    /// Estimate the memory held by the index. The UUIDs themselves stay on disk.
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            uuids: self.n_uuids,
+            other: 0,
+            bytes: self.fences.capacity() * UUID_BYTES + self.block.borrow().capacity(),
+            disk_backed: true,
+        }
+    }
+}
+
+/// Error for an index file which is shorter than its header says.
+fn truncated(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("read id index {} is truncated", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    /// Write a list of `n` UUIDs plus some lines which are not read ids, returning its path.
+    fn write_ids(name: &str, n: u128) -> TempPath {
+        let path = TempPath::new(name);
+        let mut file = File::create(&path).unwrap();
+        // Written in descending order, with a duplicate, to exercise the sort
+        for i in (0..n).rev() {
+            writeln!(file, "{}", uuid_string(i * 7)).unwrap();
+        }
+        writeln!(file, "{}", uuid_string(0)).unwrap();
+        writeln!(file, "read_1\nread_2").unwrap();
+        path
+    }
+
+    fn uuid_string(i: u128) -> String {
+        let hex = format!("{:032x}", i);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    #[test]
+    fn test_index_ids() {
+        let input = write_ids("index_input.txt", 1000);
+        for chunk_size in [100_000, 97] {
+            let output = TempPath::new(&format!("index_{}.rfidx", chunk_size));
+            index_ids(
+                vec![input.to_path_buf()],
+                output.to_path_buf(),
+                false,
+                chunk_size,
+                true,
+            )
+            .unwrap();
+            assert!(is_index(&output).unwrap());
+            let index = ReadIdIndex::open(&output).unwrap();
+            assert_eq!(index.len(), 1000);
+            for i in 0..1000 {
+                assert!(index.contains(&uuid_string(i * 7)));
+                assert!(!index.contains(&uuid_string(i * 7 + 1)));
+            }
+            assert!(!index.contains(&uuid_string(7000)));
+            assert!(!index.contains("read_1"));
+            assert!(index.memory_report().disk_backed);
+            let mut n_hashes = 0;
+            index.for_each_hash(|_| n_hashes += 1).unwrap();
+            assert_eq!(n_hashes, 1000);
+            assert!(index.contains(&uuid_string(7)));
+        }
+        // Without --lenient the lines which are not read ids are an error
+        let output = TempPath::new("index_strict.rfidx");
+        assert!(index_ids(
+            vec![input.to_path_buf()],
+            output.to_path_buf(),
+            false,
+            97,
+            false
+        )
+        .is_err());
+        assert!(!output.with_suffix(".run0.tmp").exists());
+        // and no partial index is left behind
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_index_compressed_and_empty() {
+        let output = TempPath::new("index_compressed.rfidx");
+        index_ids(
+            vec![
+                PathBuf::from("tests/test_unblocked.txt.zst"),
+                PathBuf::from("tests/test_unblocked.txt.gz"),
+                PathBuf::from("tests/test_empty_unb_ids.txt"),
+            ],
+            output.to_path_buf(),
+            false,
+            1,
+            false,
+        )
+        .unwrap();
+        // An existing index is only overwritten with --force
+        let rebuild = |force| {
+            index_ids(
+                vec![PathBuf::from("tests/test_unblocked.txt")],
+                output.to_path_buf(),
+                force,
+                1,
+                false,
+            )
+        };
+        assert_eq!(
+            rebuild(false).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        rebuild(true).unwrap();
+        let index = ReadIdIndex::open(&output).unwrap();
+        assert_eq!(index.len(), 2);
+        assert!(index.contains("2f50d29d-af9a-4acb-948d-c2b93ad310dd"));
+
+        assert!(!is_index(Path::new("tests/test_unblocked.txt")).unwrap());
+        assert!(!is_index(Path::new("tests/test_empty_unb_ids.txt")).unwrap());
+    }
+}
@@ -0,0 +1,442 @@
+//! Module for extracting the read IDs in BAM, FASTX and sequencing summary files.
+//!
+//! This is the inverse of a split: it lists the reads in a file, optionally filtered by length
+//! and quality, so that they can be used as allow or deny lists. The output is one read ID per
+//! line, which can be passed straight to `--unblocked-read-ids`. Columns of metadata can be
+//! added, in which case a header line is written first.
+//!
+//! # Enums
+//!
+//! - [`InputType`](enum.InputType.html): The type of an input file.
+//! - [`IdColumn`](enum.IdColumn.html): A metadata column to write after the read ID.
+//!
+//! # Functions
+//!
+//! - [`ids`](fn.ids.html): Write the read IDs in one or more files.
+//!
+use crate::_splitting::open_compressed;
+use crate::output::OutputFiles;
+use crate::read_meta::{ReadMeta, SummaryColumns};
+use crate::split_fq::header_to_id;
+use clap::ValueEnum;
+use needletail::parse_fastx_file;
+use noodles::bam;
+use noodles_bgzf as bgzf;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufWriter, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Compression suffixes ignored when guessing the type of an input from its name.
+const COMPRESSION_EXTENSIONS: [&str; 3] = ["gz", "bgz", "zst"];
+
+/// The type of an input file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InputType {
+    /// BAM file of basecalled reads.
+    Bam,
+    /// FASTA or FASTQ file, may be gzipped.
+    Fastx,
+    /// Sequencing summary from MinKNOW, may be compressed.
+    Summary,
+}
+
+impl InputType {
+    /// Guess the type of an input from its file extension, ignoring any compression suffix.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let mut path = path.to_path_buf();
+        while path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| COMPRESSION_EXTENSIONS.contains(&ext))
+        {
+            path.set_extension("");
+        }
+        match path.extension()?.to_str()? {
+            "bam" => Some(InputType::Bam),
+            "fastq" | "fq" | "fasta" | "fa" | "fna" => Some(InputType::Fastx),
+            "txt" | "tsv" => Some(InputType::Summary),
+            _ => None,
+        }
+    }
+}
+
+/// A metadata column to write after the read ID.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IdColumn {
+    /// Read length in bases.
+    Length,
+    /// Mean Phred quality of the read.
+    MeanQ,
+    /// Channel the read was sequenced on.
+    Channel,
+    /// Start time of the read.
+    StartTime,
+}
+
+impl IdColumn {
+    /// Name of the column in the header line.
+    fn header(&self) -> &'static str {
+        match self {
+            IdColumn::Length => "length",
+            IdColumn::MeanQ => "mean_qscore",
+            IdColumn::Channel => "channel",
+            IdColumn::StartTime => "start_time",
+        }
+    }
+
+    /// Summary column holding this metadata, for error messages.
+    fn summary_column(&self) -> &'static str {
+        match self {
+            IdColumn::Length => "sequence_length_template",
+            IdColumn::MeanQ => "mean_qscore_template",
+            IdColumn::Channel => "channel",
+            IdColumn::StartTime => "start_time",
+        }
+    }
+}
+
+/// Length and quality filters, a read must pass both to be written.
+#[derive(Debug, Clone, Copy)]
+struct Filter {
+    /// Reads must have a mean quality above this.
+    qual_thresh: Option<usize>,
+    /// Reads must be longer than this.
+    length_thresh: Option<usize>,
+}
+
+impl Filter {
+    /// Whether a read passes. A read missing the metadata a filter needs fails it.
+    fn pass(&self, meta: &ReadMeta) -> bool {
+        let qual = self
+            .qual_thresh
+            .is_none_or(|thresh| meta.mean_q.is_some_and(|q| q > thresh as f64));
+        let length = self
+            .length_thresh
+            .is_none_or(|thresh| meta.length.is_some_and(|l| l > thresh));
+        qual && length
+    }
+}
+
+/// Writes the read IDs and metadata columns that pass the filter, counting them.
+struct IdWriter<W: Write> {
+    /// Output.
+    out: W,
+    /// Columns to write after the read ID.
+    columns: Vec<IdColumn>,
+    /// Filter reads must pass.
+    filter: Filter,
+    /// Read IDs written.
+    written: usize,
+    /// Reads which failed the filter.
+    filtered: usize,
+}
+
+impl<W: Write> IdWriter<W> {
+    /// Write the header line, if there are any columns.
+    fn write_header(&mut self) -> Result<(), io::Error> {
+        if self.columns.is_empty() {
+            return Ok(());
+        }
+        write!(self.out, "read_id")?;
+        for column in &self.columns {
+            write!(self.out, "\t{}", column.header())?;
+        }
+        writeln!(self.out)
+    }
+
+    /// Write a read, if it passes the filter. Missing metadata is written as an empty field.
+    fn write(&mut self, read_id: &[u8], meta: &ReadMeta) -> Result<(), io::Error> {
+        if !self.filter.pass(meta) {
+            self.filtered += 1;
+            return Ok(());
+        }
+        self.out.write_all(read_id)?;
+        for column in &self.columns {
+            self.out.write_all(b"\t")?;
+            match column {
+                IdColumn::Length => {
+                    if let Some(length) = meta.length {
+                        write!(self.out, "{}", length)?;
+                    }
+                }
+                IdColumn::MeanQ => {
+                    if let Some(mean_q) = meta.mean_q {
+                        write!(self.out, "{:.2}", mean_q)?;
+                    }
+                }
+                IdColumn::Channel => {
+                    if let Some(channel) = meta.channel {
+                        write!(self.out, "{}", channel)?;
+                    }
+                }
+                IdColumn::StartTime => {
+                    if let Some(start_time) = &meta.start_time {
+                        write!(self.out, "{}", start_time)?;
+                    }
+                }
+            }
+        }
+        self.out.write_all(b"\n")?;
+        self.written += 1;
+        Ok(())
+    }
+}
+
+/// Write the read IDs in one or more BAM, FASTX or sequencing summary files.
+///
+/// Secondary and supplementary BAM records are skipped, so each read is written once per file.
+///
+/// # Arguments
+///
+/// * `inputs` - The files to read, their types are guessed from the file extension unless `input_type` is set.
+/// * `input_type` - Optional type for all of the inputs.
+/// * `output` - Optional path to write to, standard output if `None`.
+/// * `force` - Overwrite `output` if it already exists.
+/// * `columns` - Metadata columns to write after each read ID.
+/// * `qual_thresh` - Optional quality threshold. If set, reads with a mean quality at or below it are left out.
+/// * `length_thresh` - Optional length threshold. If set, reads at or below this length are left out.
+///
+/// # Returns
+///
+/// Returns `Result<(), Error>` where `Error` is an IO error if an input can't be read, has an unknown
+/// type, or is a sequencing summary without a column needed for `columns`, or if `output` already
+/// exists and overwriting wasn't allowed.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// // The channel of every read longer than 1 kb
+/// ids(
+///     vec![PathBuf::from("sequencing_summary.txt")],
+///     None,
+///     Some(PathBuf::from("long_reads.tsv")),
+///     false,
+///     vec![IdColumn::Channel],
+///     None,
+///     Some(1000),
+/// )?;
+/// ```
+pub fn ids(
+    inputs: Vec<PathBuf>,
+    input_type: Option<InputType>,
+    output: Option<PathBuf>,
+    force: bool,
+    columns: Vec<IdColumn>,
+    qual_thresh: Option<usize>,
+    length_thresh: Option<usize>,
+) -> Result<(), io::Error> {
+    // Written to a temporary file, so an input given as the output isn't truncated
+    let files = output.map(|path| OutputFiles::single(&path, force));
+    let out: Box<dyn Write> = match &files {
+        Some(files) => Box::new(files.create("")?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut writer = IdWriter {
+        out: BufWriter::new(out),
+        columns,
+        filter: Filter {
+            qual_thresh,
+            length_thresh,
+        },
+        written: 0,
+        filtered: 0,
+    };
+    writer.write_header()?;
+    for path in inputs {
+        let input_type = match input_type.or_else(|| InputType::from_path(&path)) {
+            Some(input_type) => input_type,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "can't tell the type of {} from its name, use --input-type",
+                        path.display()
+                    ),
+                ))
+            }
+        };
+        match input_type {
+            InputType::Bam => bam_ids(&path, &mut writer)?,
+            InputType::Fastx => fastx_ids(&path, &mut writer)?,
+            InputType::Summary => summary_ids(&path, &mut writer)?,
+        }
+    }
+    writer.out.into_inner().map_err(|err| err.into_error())?;
+    if let Some(files) = files {
+        files.commit()?;
+    }
+    eprintln!(
+        "Wrote {} read ids, {} reads filtered out",
+        writer.written, writer.filtered
+    );
+    Ok(())
+}
+
+/// Write the read IDs of the primary records in a BAM file.
+fn bam_ids<W: Write>(path: &Path, writer: &mut IdWriter<W>) -> Result<(), io::Error> {
+    let worker_count = thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    let decoder = bgzf::MultithreadedReader::with_worker_count(worker_count, File::open(path)?);
+    let mut reader = bam::io::Reader::from(decoder);
+    reader.read_header()?;
+    let mut record = bam::Record::default();
+    while reader.read_record(&mut record)? != 0 {
+        let flags = record.flags();
+        if flags.is_secondary() || flags.is_supplementary() {
+            continue;
+        }
+        let read_id = record.name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: missing read id on BAM record", path.display()),
+            )
+        })?;
+        writer.write(read_id.as_bytes(), &ReadMeta::from_bam(&record))?;
+    }
+    Ok(())
+}
+
+/// Write the read IDs in a FASTA or FASTQ file.
+fn fastx_ids<W: Write>(path: &Path, writer: &mut IdWriter<W>) -> Result<(), io::Error> {
+    let invalid = |err: needletail::errors::ParseError| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    };
+    let mut reader = parse_fastx_file(path).map_err(invalid)?;
+    while let Some(record) = reader.next() {
+        let record = record.map_err(invalid)?;
+        let header = record.id();
+        let meta = ReadMeta::from_fastx(header, record.num_bases(), record.qual());
+        writer.write(header_to_id(header).as_bytes(), &meta)?;
+    }
+    Ok(())
+}
+
+/// Write the read IDs in a sequencing summary.
+fn summary_ids<W: Write>(path: &Path, writer: &mut IdWriter<W>) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(open_compressed(path)?);
+    let summary_columns = SummaryColumns::new(rdr.byte_headers()?).map_err(invalid)?;
+    for column in &writer.columns {
+        let found = match column {
+            IdColumn::Length => summary_columns.length,
+            IdColumn::MeanQ => summary_columns.mean_q,
+            IdColumn::Channel => summary_columns.channel,
+            IdColumn::StartTime => summary_columns.start_time,
+        };
+        if found.is_none() {
+            return Err(invalid(format!(
+                "Could not find field {}",
+                column.summary_column()
+            )));
+        }
+    }
+    let mut row = csv::ByteRecord::new();
+    while rdr.read_byte_record(&mut row)? {
+        writer.write(&row[summary_columns.read_id], &summary_columns.meta(&row))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use std::fs;
+
+    #[test]
+    fn test_input_type_from_path() {
+        for (path, input_type) in [
+            ("calls.bam", Some(InputType::Bam)),
+            ("reads.fastq.gz", Some(InputType::Fastx)),
+            ("reads.fa", Some(InputType::Fastx)),
+            ("sequencing_summary.txt.zst", Some(InputType::Summary)),
+            ("reads", None),
+            ("reads.gz", None),
+        ] {
+            assert_eq!(
+                InputType::from_path(Path::new(path)),
+                input_type,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_ids() {
+        let output = TempPath::new("ids.tsv");
+        ids(
+            vec![
+                PathBuf::from("tests/test_reads.fastq"),
+                PathBuf::from("tests/test_sequencing_summary.txt"),
+            ],
+            None,
+            Some(output.to_path_buf()),
+            false,
+            vec![IdColumn::Channel, IdColumn::MeanQ],
+            Some(11),
+            None,
+        )
+        .unwrap();
+        let written = fs::read_to_string(&output).unwrap();
+        // An existing file, which may be one of the inputs, is left alone without --force
+        let err = ids(
+            vec![output.to_path_buf()],
+            Some(InputType::Summary),
+            Some(output.to_path_buf()),
+            false,
+            vec![],
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&output).unwrap(), written);
+        assert_eq!(
+            written,
+            "read_id\tchannel\tmean_qscore\n\
+             0afabd54-d901-473f-94ba-c7a6e69716f5\t1\t20.00\n\
+             8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e\t3\t30.00\n\
+             5b1e2c0a-3f7d-4e8a-9c61-2d4f8e7a1b93\t4\t40.00\n\
+             c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e\t1\t25.00\n\
+             0afabd54-d901-473f-94ba-c7a6e69716f5\t1\t12.10\n\
+             8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e\t3\t11.40\n\
+             5b1e2c0a-3f7d-4e8a-9c61-2d4f8e7a1b93\t4\t14.20\n\
+             c3a9f1d2-7e4b-4a5c-8d6e-1f2a3b4c5d6e\t1\t13.00\n"
+        );
+    }
+
+    #[test]
+    fn test_ids_missing_summary_column() {
+        let output = TempPath::new("ids_missing.tsv");
+        let missing = |column: IdColumn| {
+            ids(
+                vec![PathBuf::from("tests/test_readfish_chunks.tsv")],
+                Some(InputType::Summary),
+                Some(output.to_path_buf()),
+                false,
+                vec![column],
+                None,
+                None,
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert!(missing(IdColumn::Length).contains("Could not find field sequence_length_template"));
+        assert!(missing(IdColumn::StartTime).contains("Could not find field start_time"));
+    }
+}
@@ -8,10 +8,27 @@
 //! ## Modules
 //!
 //! - `_splitting`: Module for shared splitting operations.
+//! - `alignment`: Module for reading SAM, BAM and CRAM files alike.
+//! - `bam_index`: Module for indexing the BAM outputs of `split-bam` while they are written.
+//! - `bloom`: Module for a Bloom filter used as a prefilter in front of the unblocked read IDs.
+//! - `classify`: Module for classifying reads into categories, and routing them to one writer per category.
 //! - `cli`: Module for command-line interface (CLI) handling, using Clap.
+//! - `compress`: Module for compressing the FASTX and TSV outputs of the split commands.
+//! - `conditions`: Module for the channel layout of the conditions in a readfish TOML.
+//! - `decisions`: Module for reading the per read decisions from a readfish chunk log.
+//! - `ids`: Module for extracting the read IDs in BAM, FASTX and sequencing summary files.
+//! - `id_index`: Module for the disk backed read ID index built by `index-ids`.
+//! - `read_ids`: Module for compact storage of read IDs.
+//! - `output`: Module for naming the output files of the split commands.
+//! - `provenance`: Module for recording how an output BAM was made in its header.
+//! - `read_meta`: Module for the per read metadata in BAM, FASTQ and sequencing summary files.
+//! - `reference`: Module for the reference sequences CRAM files are compressed against.
 //! - `split_bam`: Module for splitting BAM files.
 //! - `split_fq`: Module for splitting FASTQ files.
 //! - `split_ss`: Module for splitting sequencing summary files.
+//! - `targets`: Module for restricting `split-bam` to target regions of the reference.
+//! - `tag_bam`: Module for tagging BAM records as sequenced or unblocked.
+//! - `time_bins`: Module for placing reads in time by when they started sequencing.
 //!
 //! ## Usage
 //!
@@ -27,11 +44,64 @@
 //! # Splitting Sequencing Summary file, only writing out sequenced
 //! cargo run -- split-ss --prefix output unblocked_read_ids.txt sequencing_summary.txt
 //!
+//! # Splitting FASTQ files, taking the unblocked reads from the end_reason in the sequencing summary
+//! cargo run -- split-fq --unblock-source summary:sequencing_summary.txt input.fq
+//!
+//! # Splitting a sequencing summary using the final decisions from the readfish chunk log
+//! cargo run -- split-ss --unblock-source decisions:readfish_chunks.tsv sequencing_summary.txt
+//!
 //! # Splitting BAM file, writing out both unblocked and sequenced records into sequenced and unblocked bam files, filtering to Q score > 20.
 //! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file input.bam --split-type all --qual-thresh 20
 //!
 //! # Splitting BAM file, writing out only unblocked records into a FASTQ file.
 //! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file input.bam --split-type unblocked-only --emit-type fastq
+//!
+//! # Splitting BAM file against a very large unblocked list, with a Bloom filter in front of it.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file input.bam --bloom-fpr 0.01
+//!
+//! # Listing the read ids, lengths and channels of the reads in a FASTQ file.
+//! cargo run -- ids --columns length,channel --output reads.tsv input.fq
+//!
+//! # Splitting BAM file into one file per final readfish decision.
+//! cargo run -- split-bam --unblock-source decisions:readfish_chunks.tsv --bam-file input.bam --split-type all --split-by decision
+//!
+//! # Splitting FASTQ files into sequenced and unblocked reads for each barcode.
+//! cargo run -- split-fq --write-unblocked --split-by barcode -u unblocked_read_ids.txt input.fq
+//!
+//! # Splitting BAM file into sequenced and unblocked reads for each condition of a readfish config.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file input.bam --split-type all --split-by condition --readfish-toml readfish.toml
+//!
+//! # Splitting a sequencing summary into hourly bins over the first day of the run.
+//! cargo run -- split-ss -u unblocked_read_ids.txt --time-bins 1h --time-range 0-24h sequencing_summary.txt
+//!
+//! # Splitting BAM file by barcode into a directory per barcode.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file input.bam --split-by barcode --outdir split --output-template '{barcode}/{category}.{ext}'
+//!
+//! # Splitting an aligned BAM file into CRAM files, compressed against the reference it was aligned to.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file aligned.bam --split-type all --emit-type cram --reference ref.fa
+//!
+//! # Splitting a coordinate-sorted BAM file, indexing each output BAM as it is written.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file aligned.sorted.bam --split-type all --index
+//!
+//! # Splitting only the reads overlapping the targets of a panel, read through the index of the BAM.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file aligned.sorted.bam --bed targets.bed
+//!
+//! # Splitting a CRAM file, finding its reference in the local reference cache.
+//! cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file aligned.cram --reference-cache ~/.cache/hts-ref
+//!
+//! # Splitting a sequencing summary into zstd compressed outputs, for archiving.
+//! cargo run -- split-ss -u unblocked_read_ids.txt -c zstd --compression-level 19 sequencing_summary.txt
+//!
+//! # Tagging BAM records as sequenced or unblocked, with their readfish condition, then keeping the unblocked.
+//! cargo run -- tag-bam --unblock-source decisions:readfish_chunks.tsv --bam-file input.bam --output tagged.bam
+//! samtools view -b -d rf:unblocked tagged.bam > unblocked.bam
+//!
+//! # Splitting reads straight from the basecaller, piping the sequenced reads on to the aligner.
+//! dorado basecaller hac pod5s/ | cargo run -- split-bam --unblocked-read-ids ids.txt --bam-file - --emit-type fastq --stdout sequenced | minimap2 -ax map-ont ref.mmi -
+//!
+//! # Building an on disk index of several unblocked read id files, and splitting with it.
+//! cargo run -- index-ids --output unblocked.rfidx fc1_unblocked_read_ids.txt fc2_unblocked_read_ids.txt
+//! cargo run -- split-bam --unblocked-read-ids unblocked.rfidx --bam-file input.bam
 //! ```
 //!
 //! ## Error Handling
@@ -39,10 +109,29 @@
 //! Error handling follows the guidelines described in [BurntSushi's Blog](https://blog.burntsushi.net/rust-error-handling/).
 //!
 mod _splitting;
+mod alignment;
+mod bam_index;
+mod bloom;
+mod classify;
 mod cli;
+mod compress;
+mod conditions;
+mod decisions;
+mod id_index;
+mod ids;
+mod output;
+mod provenance;
+mod read_ids;
+mod read_meta;
+mod reference;
 mod split_bam;
 mod split_fq;
 mod split_ss;
+mod tag_bam;
+mod targets;
+#[cfg(test)]
+mod test_util;
+mod time_bins;
 use crate::cli::{Cli, Commands};
 use clap::Parser;
 
@@ -52,38 +141,128 @@ fn main() {
     // https://blog.burntsushi.net/rust-error-handling/
     let args = Cli::parse();
 
-    // let res = match args {
-    match args.command {
+    let res = match args.command {
         Commands::SplitFQ {
-            unblocked_read_ids,
-            prefix,
+            unblock,
+            classify,
+            bloom_fpr,
+            output,
+            compression,
+            unblocked_read_ids_file,
             input_fastq,
             write_unblocked,
-        } => crate::split_fq::split(unblocked_read_ids, prefix, input_fastq, write_unblocked),
+        } => {
+            crate::split_fq::split(
+                unblock,
+                unblocked_read_ids_file,
+                bloom_fpr,
+                classify,
+                output,
+                compression,
+                input_fastq,
+                write_unblocked,
+            );
+            Ok(())
+        }
         Commands::SplitSS {
-            unblocked_read_ids,
-            prefix,
+            unblock,
+            classify,
+            output,
+            compression,
+            unblocked_read_ids_file,
             sequencing_summary,
-        } => crate::split_ss::split(unblocked_read_ids, prefix, sequencing_summary),
+        } => {
+            crate::split_ss::split(
+                unblock,
+                unblocked_read_ids_file,
+                classify,
+                output,
+                compression,
+                sequencing_summary,
+            );
+            Ok(())
+        }
         Commands::SplitBam {
-            prefix,
-            unblocked_read_ids,
+            output,
+            unblock,
+            classify,
+            bloom_fpr,
             bam_file,
             split_type,
             qual_thresh,
             length_thresh,
             emit_type,
             compression,
+            reference,
+            header_comments,
+            index,
+            targets,
         } => crate::split_bam::split_bam(
             bam_file,
-            unblocked_read_ids,
-            prefix,
+            unblock,
+            bloom_fpr,
+            classify,
+            output,
             split_type,
             qual_thresh,
             length_thresh,
             emit_type,
             compression,
-        )
-        .unwrap(),
+            reference,
+            header_comments,
+            index,
+            targets,
+        ),
+        Commands::TagBam {
+            bam_file,
+            output,
+            force,
+            unblock,
+            bloom_fpr,
+            readfish_toml,
+            flowcell,
+            tag,
+            condition_tag,
+            header_comments,
+        } => crate::tag_bam::tag_bam(
+            bam_file,
+            unblock,
+            bloom_fpr,
+            readfish_toml,
+            flowcell,
+            output,
+            force,
+            tag,
+            condition_tag,
+            header_comments,
+        ),
+        Commands::Ids {
+            output,
+            force,
+            input_type,
+            columns,
+            qual_thresh,
+            length_thresh,
+            inputs,
+        } => crate::ids::ids(
+            inputs,
+            input_type,
+            output,
+            force,
+            columns,
+            qual_thresh,
+            length_thresh,
+        ),
+        Commands::IndexIds {
+            output,
+            force,
+            chunk_size,
+            lenient,
+            unblocked_read_ids,
+        } => crate::id_index::index_ids(unblocked_read_ids, output, force, chunk_size, lenient),
     };
+    if let Err(err) = res {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    }
 }
@@ -0,0 +1,747 @@
+//! Module for naming and creating the output files of the split commands.
+//!
+//! Each output holds the reads with one combination of labels, e.g. `unblocked.barcode01`. The
+//! path of an output comes from a template, `{prefix}.{name}.{ext}` by default, in which
+//! `{name}` is all of the labels and each classifier's label can also be used on its own, e.g.
+//! `{barcode}/{prefix}.{category}.{ext}`. Fields which are empty, like the default prefix, are
+//! left out along with a separator next to them, so no file name starts with a `.`.
+//!
+//! Outputs are written to hidden temporary files next to their final paths, and only renamed
+//! into place once every output has been written, so a failed split never leaves behind files
+//! which look complete. Existing files are not overwritten without `--force`.
+//!
+//! One output can instead be written to standard output with `--stdout`, to be piped into
+//! another program. Reports are then printed to standard error, so they don't mix with it.
+//!
+//! # Structs
+//!
+//! - [`OutputArgs`](struct.OutputArgs.html): Command line arguments naming the outputs, shared by the split commands.
+//! - [`OutputFiles`](struct.OutputFiles.html): Names the outputs from a template, and creates them atomically.
+//!
+//! # Enums
+//!
+//! - [`OutputFile`](enum.OutputFile.html): An output file, or standard output.
+//!
+//! # Functions
+//!
+//! - [`output_extension`](fn.output_extension.html): The file extension for an output format and compression.
+//! - [`compressed_extension`](fn.compressed_extension.html): A file extension with the suffix of a compression added.
+//!
+use crate::_splitting::{CompressionType, EmitType};
+use crate::classify::LABEL_SEPARATOR;
+use clap::Args;
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Template used if none is given.
+const DEFAULT_TEMPLATE: &str = "{prefix}.{name}.{ext}";
+/// Fields of a template which don't come from a classifier.
+const FIXED_FIELDS: [&str; 3] = ["prefix", "name", "ext"];
+/// Fields of a template which name the label of a classifier.
+const LABEL_FIELDS: [&str; 4] = ["category", "condition", "barcode", "bin"];
+/// Characters dropped next to an empty field.
+const SEPARATORS: [char; 3] = ['.', '_', '-'];
+
+/// The file extension for an output, e.g. `fastq.gz`. BAM is always BGZF compressed, and CRAM
+/// compresses itself, so neither has a compression suffix.
+pub fn output_extension(emit_type: &EmitType, compression: &CompressionType) -> String {
+    let extension = match emit_type {
+        EmitType::Bam => return String::from("bam"),
+        EmitType::Cram => return String::from("cram"),
+        EmitType::Sam => "sam",
+        EmitType::Fasta => "fasta",
+        EmitType::Fastq => "fastq",
+    };
+    compressed_extension(extension, compression)
+}
+
+/// A file extension with the suffix of a compression added, e.g. `txt.zst`.
+pub fn compressed_extension(extension: &str, compression: &CompressionType) -> String {
+    match compression {
+        CompressionType::Gzipped | CompressionType::Bgzipped => format!("{}.gz", extension),
+        CompressionType::Zstd => format!("{}.zst", extension),
+        CompressionType::Xz => format!("{}.xz", extension),
+        CompressionType::Uncompressed => extension.to_owned(),
+    }
+}
+
+/// Command line arguments naming the outputs, shared by the split commands.
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Output file prefix
+    #[clap(short, long, default_value = "")]
+    pub prefix: String,
+    /// Directory to write the outputs to, created if needed. Default is the current directory
+    #[clap(long, value_name = "DIR", parse(from_os_str))]
+    pub outdir: Option<PathBuf>,
+    /// Template for the output paths, from {prefix}, {name} (all labels), {ext}, and the labels
+    /// {category}, {condition}, {barcode} and {bin}. Default is {prefix}.{name}.{ext}
+    #[clap(long, value_name = "TEMPLATE")]
+    pub output_template: Option<String>,
+    /// Overwrite output files which already exist
+    #[clap(long)]
+    pub force: bool,
+    /// Write the output with this name, e.g. sequenced or unblocked.barcode01, to standard
+    /// output rather than to a file
+    #[clap(long, value_name = "NAME")]
+    pub stdout: Option<String>,
+}
+
+impl OutputArgs {
+    /// Name and create the outputs of a split.
+    ///
+    /// # Arguments
+    ///
+    /// * `fields` - The field of each label in an output name, from [`Router::fields`](crate::classify::Router::fields).
+    /// * `labels` - The labels each field can take, from [`Router::field_labels`](crate::classify::Router::field_labels).
+    /// * `extension` - The file extension, without the leading `.`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the outputs, or an `io::Error` if the template is invalid or would give more
+    /// than one output the same path, or the output chosen with `--stdout` can't be one of
+    /// them.
+    pub fn files(
+        &self,
+        fields: Vec<&'static str>,
+        labels: Vec<Vec<String>>,
+        extension: &str,
+    ) -> Result<OutputFiles, io::Error> {
+        let template = self.output_template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--output-template {}: {}", template, msg),
+            )
+        };
+        let segments = parse_template(template).map_err(invalid)?;
+        let used: Vec<&str> = segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Field(field) => Some(field.as_str()),
+                Segment::Literal(_) => None,
+            })
+            .collect();
+        for field in &used {
+            if LABEL_FIELDS.contains(field) && !fields.contains(field) {
+                return Err(invalid(format!(
+                    "reads are not split by {{{}}}, see --split-by",
+                    field
+                )));
+            }
+        }
+        if !used.contains(&"name") {
+            if let Some(missing) = fields.iter().find(|field| !used.contains(field)) {
+                return Err(invalid(format!(
+                    "needs {{{}}} or {{name}}, or outputs would overwrite each other",
+                    missing
+                )));
+            }
+        }
+        // Labels only known once the reads are seen are checked once they have been
+        let mut stdout_known = true;
+        if let Some(name) = &self.stdout {
+            let n_labels = name.split(LABEL_SEPARATOR).count();
+            if name.is_empty() || n_labels != fields.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "--stdout {}: expected an output name of the labels {{{}}} joined by {}, e.g. sequenced",
+                        name,
+                        fields.join("}, {"),
+                        LABEL_SEPARATOR
+                    ),
+                ));
+            }
+            for ((label, field), known) in name.split(LABEL_SEPARATOR).zip(&fields).zip(&labels) {
+                if known.is_empty() {
+                    stdout_known = false;
+                } else if !known.iter().any(|known| known == label) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "--stdout {}: {} is not a {{{}}} label, expected one of {}",
+                            name,
+                            label,
+                            field,
+                            known.join(", ")
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(OutputFiles {
+            outdir: self.outdir.clone(),
+            segments,
+            prefix: self.prefix.clone(),
+            fields,
+            extension: extension.to_owned(),
+            force: self.force,
+            pending: RefCell::new(Vec::new()),
+            stdout: self.stdout.clone(),
+            stdout_known,
+            stdout_created: Cell::new(false),
+        })
+    }
+}
+
+/// An output file, or standard output.
+#[derive(Debug)]
+pub enum OutputFile {
+    /// A file.
+    File(File),
+    /// Standard output.
+    Stdout(io::Stdout),
+}
+
+impl From<File> for OutputFile {
+    fn from(file: File) -> Self {
+        OutputFile::File(file)
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::File(file) => file.write(buf),
+            OutputFile::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::File(file) => file.flush(),
+            OutputFile::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+impl Seek for OutputFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            OutputFile::File(file) => file.seek(pos),
+            OutputFile::Stdout(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "standard output can't be seeked",
+            )),
+        }
+    }
+}
+
+/// A piece of a template.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// Text copied into the path.
+    Literal(String),
+    /// A `{field}` replaced in the path.
+    Field(String),
+}
+
+/// Split a template into text and `{field}`s, checking the fields are known.
+fn parse_template(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            segments.push(Segment::Literal(rest[..open].to_owned()));
+        }
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| "unclosed {".to_owned())?
+            + open;
+        let field = &rest[open + 1..close];
+        if !FIXED_FIELDS.contains(&field) && !LABEL_FIELDS.contains(&field) {
+            return Err(format!(
+                "unknown field {{{}}}, expected one of {{{}}}",
+                field,
+                [FIXED_FIELDS.as_slice(), LABEL_FIELDS.as_slice()]
+                    .concat()
+                    .join("}, {")
+            ));
+        }
+        segments.push(Segment::Field(field.to_owned()));
+        rest = &rest[close + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_owned()));
+    }
+    Ok(segments)
+}
+
+/// Names the outputs of a split from a template, and creates them as temporary files which are
+/// renamed into place by [`commit`](#method.commit). Temporary files which are not committed
+/// are removed when this is dropped.
+#[derive(Debug)]
+pub struct OutputFiles {
+    /// Directory the paths are in, `None` for the current directory.
+    outdir: Option<PathBuf>,
+    /// The template.
+    segments: Vec<Segment>,
+    /// Value of `{prefix}`.
+    prefix: String,
+    /// The field of each label in an output name.
+    fields: Vec<&'static str>,
+    /// Value of `{ext}`.
+    extension: String,
+    /// Whether existing files can be overwritten.
+    force: bool,
+    /// Each temporary file created, and the path it is renamed to.
+    pending: RefCell<Vec<(PathBuf, PathBuf)>>,
+    /// Name of the output written to standard output, if any.
+    stdout: Option<String>,
+    /// Whether the output written to standard output is known to be one of the outputs,
+    /// rather than having labels only known once the reads are seen.
+    stdout_known: bool,
+    /// Whether the output written to standard output has been created.
+    stdout_created: Cell<bool>,
+}
+
+impl OutputFiles {
+    /// A single output at `path`, whatever name it is created with.
+    pub fn single(path: &Path, force: bool) -> Self {
+        OutputFiles {
+            outdir: None,
+            segments: vec![Segment::Literal(path.to_string_lossy().into_owned())],
+            prefix: String::new(),
+            fields: Vec::new(),
+            extension: String::new(),
+            force,
+            pending: RefCell::new(Vec::new()),
+            stdout: None,
+            stdout_known: true,
+            stdout_created: Cell::new(false),
+        }
+    }
+
+    /// Whether an output is written to standard output.
+    pub fn writes_stdout(&self) -> bool {
+        self.stdout.is_some()
+    }
+
+    /// The name of the output written to standard output, if it hasn't been created because
+    /// no reads were routed to it. It should still be created, so that a program reading it
+    /// gets a valid empty file, e.g. a BAM with just a header.
+    ///
+    /// # Returns
+    ///
+    /// Returns the name, or an `io::Error` if it has a label only known once the reads are
+    /// seen, like a barcode, which no read had, so it isn't one of the outputs.
+    pub fn unwritten_stdout(&self) -> Result<Option<&str>, io::Error> {
+        match self.stdout.as_deref() {
+            Some(name) if !self.stdout_created.get() && !self.stdout_known => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "--stdout {}: no read had these labels, so it isn't one of the outputs",
+                    name
+                ),
+            )),
+            stdout => Ok(stdout.filter(|_| !self.stdout_created.get())),
+        }
+    }
+
+    /// Print a report, to standard output, or to standard error if an output is written to
+    /// standard output.
+    pub fn report(&self, report: impl fmt::Display) {
+        if self.writes_stdout() {
+            eprintln!("{}", report);
+        } else {
+            println!("{}", report);
+        }
+    }
+
+    /// The path of the output for a name from the [`Router`](crate::classify::Router), without
+    /// creating any directories.
+    pub fn render(&self, name: &str) -> PathBuf {
+        let labels: Vec<&str> = if name.is_empty() {
+            Vec::new()
+        } else {
+            name.split(LABEL_SEPARATOR).collect()
+        };
+        let mut path = String::new();
+        // Set when an empty field starts the file name, to drop the separator after it
+        let mut skip_separator = false;
+        for segment in &self.segments {
+            let value = match segment {
+                Segment::Literal(text) => {
+                    let text = match skip_separator {
+                        true => text.strip_prefix(SEPARATORS).unwrap_or(text),
+                        false => text,
+                    };
+                    skip_separator = false;
+                    path.push_str(text);
+                    continue;
+                }
+                Segment::Field(field) => match field.as_str() {
+                    "prefix" => self.prefix.as_str(),
+                    "name" => name,
+                    "ext" => self.extension.as_str(),
+                    field => self
+                        .fields
+                        .iter()
+                        .position(|f| *f == field)
+                        .and_then(|idx| labels.get(idx).copied())
+                        .unwrap_or(""),
+                },
+            };
+            if !value.is_empty() {
+                path.push_str(value);
+                skip_separator = false;
+            } else if path.ends_with(SEPARATORS) {
+                path.pop();
+            } else if path.is_empty() || path.ends_with('/') {
+                skip_separator = true;
+            }
+        }
+        match &self.outdir {
+            Some(outdir) => outdir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Create the output for a name from the [`Router`](crate::classify::Router), as a
+    /// temporary file in the directory of its path, creating the directory if needed, or as
+    /// standard output if it is the output chosen with `--stdout`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the temporary file, or an `io::Error` if the output already exists and
+    /// overwriting wasn't allowed, or the file could not be created.
+    pub fn create(&self, name: &str) -> Result<OutputFile, io::Error> {
+        if self.stdout.as_deref() == Some(name) {
+            self.stdout_created.set(true);
+            return Ok(OutputFile::Stdout(io::stdout()));
+        }
+        self.create_path(self.render(name))
+    }
+
+    /// Create a temporary file for `path`, creating its directory if needed.
+    fn create_path(&self, path: PathBuf) -> Result<OutputFile, io::Error> {
+        if let Some(parent) = path.parent().filter(|parent| parent != &Path::new("")) {
+            fs::create_dir_all(parent)?;
+        }
+        if !self.force && path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Output file {} already exists, use --force to overwrite it",
+                    path.display()
+                ),
+            ));
+        }
+        let temp = temp_path(&path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Could not create output file: {}\n{}", temp.display(), err),
+                )
+            })?;
+        self.pending.borrow_mut().push((temp, path));
+        Ok(OutputFile::File(file))
+    }
+
+    /// Create the index of the output for a name, as a temporary file at the path of the output
+    /// with `suffix` added, e.g. `.bai`. It is renamed into place with the outputs.
+    pub fn create_index(&self, name: &str, suffix: &str) -> Result<OutputFile, io::Error> {
+        let mut path = self.render(name).into_os_string();
+        path.push(suffix);
+        self.create_path(PathBuf::from(path))
+    }
+
+    /// Rename every output into place, once they have all been written and closed. The
+    /// temporary files are synced to disk first, and nothing is renamed if another process
+    /// created one of the outputs in the meantime and overwriting wasn't allowed. Temporary
+    /// files which weren't renamed are removed if this fails.
+    pub fn commit(self) -> Result<(), io::Error> {
+        let pending = self.pending.borrow();
+        for (temp, _) in pending.iter() {
+            OpenOptions::new()
+                .write(true)
+                .open(temp)
+                .and_then(|file| file.sync_all())
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("Could not write output file: {}\n{}", temp.display(), err),
+                    )
+                })?;
+        }
+        if !self.force {
+            if let Some((_, path)) = pending.iter().find(|(_, path)| path.exists()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "Output file {} was created while splitting, use --force to overwrite it",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        for (temp, path) in pending.iter() {
+            fs::rename(temp, path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "Could not move {} to {}\n{}",
+                        temp.display(),
+                        path.display(),
+                        err
+                    ),
+                )
+            })?;
+        }
+        drop(pending);
+        self.pending.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Remove the temporary files, e.g. before exiting on an error.
+    pub fn discard(&self) {
+        for (temp, _) in self.pending.take() {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+impl Drop for OutputFiles {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+/// The temporary path an output is written to, a hidden file in the same directory which is
+/// unique to this process.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn args(prefix: &str, outdir: Option<&str>, template: Option<&str>) -> OutputArgs {
+        OutputArgs {
+            prefix: prefix.to_owned(),
+            outdir: outdir.map(PathBuf::from),
+            output_template: template.map(str::to_owned),
+            force: false,
+            stdout: None,
+        }
+    }
+
+    /// The labels of the category, and of the barcodes, which are only known once reads are seen.
+    fn labels(fields: &[&str]) -> Vec<Vec<String>> {
+        fields
+            .iter()
+            .map(|field| match *field {
+                "category" => vec![String::from("sequenced"), String::from("unblocked")],
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stdout() {
+        let mut output = args("", None, None);
+        output.stdout = Some(String::from("sequenced.barcode01"));
+        assert!(output
+            .files(vec!["category"], labels(&["category"]), "bam")
+            .is_err());
+        let files = output
+            .files(
+                vec!["category", "barcode"],
+                labels(&["category", "barcode"]),
+                "bam",
+            )
+            .unwrap();
+        assert!(files.writes_stdout());
+        // A barcode no read had isn't an output
+        assert!(files.unwritten_stdout().is_err());
+        assert!(matches!(
+            files.create("sequenced.barcode01").unwrap(),
+            OutputFile::Stdout(_)
+        ));
+        assert_eq!(files.unwritten_stdout().unwrap(), None);
+        // A known output is written to standard output even if no read went to it
+        output.stdout = Some(String::from("unblocked"));
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "bam")
+            .unwrap();
+        assert_eq!(files.unwritten_stdout().unwrap(), Some("unblocked"));
+        // but a misspelt one is an error up front
+        output.stdout = Some(String::from("unblokced"));
+        assert!(output
+            .files(vec!["category"], labels(&["category"]), "bam")
+            .is_err());
+    }
+
+    #[test]
+    fn test_output_extension() {
+        assert_eq!(
+            output_extension(&EmitType::Bam, &CompressionType::Gzipped),
+            "bam"
+        );
+        assert_eq!(
+            output_extension(&EmitType::Fastq, &CompressionType::Gzipped),
+            "fastq.gz"
+        );
+        assert_eq!(
+            output_extension(&EmitType::Fasta, &CompressionType::Uncompressed),
+            "fasta"
+        );
+        assert_eq!(
+            output_extension(&EmitType::Fastq, &CompressionType::Zstd),
+            "fastq.zst"
+        );
+        assert_eq!(
+            output_extension(&EmitType::Cram, &CompressionType::Gzipped),
+            "cram"
+        );
+        assert_eq!(
+            output_extension(&EmitType::Sam, &CompressionType::Uncompressed),
+            "sam"
+        );
+        assert_eq!(compressed_extension("txt", &CompressionType::Xz), "txt.xz");
+    }
+
+    #[test]
+    fn test_default_template() {
+        let namer = args("", None, None)
+            .files(vec!["category"], labels(&["category"]), "bam")
+            .unwrap();
+        assert_eq!(namer.render("unblocked"), PathBuf::from("unblocked.bam"));
+        let namer = args("run1", Some("out"), None)
+            .files(
+                vec!["category", "barcode"],
+                labels(&["category", "barcode"]),
+                "fastq",
+            )
+            .unwrap();
+        assert_eq!(
+            namer.render("sequenced.barcode01"),
+            PathBuf::from("out/run1.sequenced.barcode01.fastq")
+        );
+    }
+
+    #[test]
+    fn test_template_fields() {
+        let namer = args("", None, Some("{barcode}/{prefix}_{category}.{ext}"))
+            .files(
+                vec!["category", "barcode"],
+                labels(&["category", "barcode"]),
+                "txt",
+            )
+            .unwrap();
+        assert_eq!(
+            namer.render("unblocked.barcode01"),
+            PathBuf::from("barcode01/unblocked.txt")
+        );
+        let namer = args("run1", None, Some("{prefix}.{category}.{bin}.{ext}"))
+            .files(vec!["category", "bin"], labels(&["category", "bin"]), "txt")
+            .unwrap();
+        assert_eq!(
+            namer.render("sequenced.0-1h"),
+            PathBuf::from("run1.sequenced.0-1h.txt")
+        );
+    }
+
+    #[test]
+    fn test_atomic_outputs() {
+        use std::io::Write;
+        let outdir = TempPath::new("outputs");
+        let mut output = args("", Some(outdir.to_str().unwrap()), None);
+        let listing = || {
+            let mut names: Vec<String> = fs::read_dir(&outdir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        // Nothing is left behind if the split fails
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "txt")
+            .unwrap();
+        files
+            .create("sequenced")
+            .unwrap()
+            .write_all(b"partial")
+            .unwrap();
+        assert_eq!(listing().len(), 1);
+        drop(files);
+        assert!(listing().is_empty());
+        // Outputs appear once committed
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "txt")
+            .unwrap();
+        files
+            .create("sequenced")
+            .unwrap()
+            .write_all(b"first")
+            .unwrap();
+        files.commit().unwrap();
+        assert_eq!(listing(), ["sequenced.txt"]);
+        // and are only overwritten with --force
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "txt")
+            .unwrap();
+        let err = files.create("sequenced").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        output.force = true;
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "txt")
+            .unwrap();
+        files
+            .create("sequenced")
+            .unwrap()
+            .write_all(b"second")
+            .unwrap();
+        files.commit().unwrap();
+        assert_eq!(fs::read(outdir.join("sequenced.txt")).unwrap(), b"second");
+        // An output created by something else during the split is not overwritten either
+        output.force = false;
+        let files = output
+            .files(vec!["category"], labels(&["category"]), "txt")
+            .unwrap();
+        files
+            .create("unblocked")
+            .unwrap()
+            .write_all(b"ours")
+            .unwrap();
+        fs::write(outdir.join("unblocked.txt"), b"theirs").unwrap();
+        let err = files.commit().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(listing(), ["sequenced.txt", "unblocked.txt"]);
+        assert_eq!(fs::read(outdir.join("unblocked.txt")).unwrap(), b"theirs");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let fields = vec!["category", "barcode"];
+        for template in [
+            "{prefix}.{category}.{ext}",
+            "{prefix}.{name}.{condition}.{ext}",
+            "{prefix}.{name}.{colour}",
+            "{prefix}.{name",
+        ] {
+            assert!(
+                args("", None, Some(template))
+                    .files(fields.clone(), labels(&fields), "txt")
+                    .is_err(),
+                "{}",
+                template
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Module for recording how an output BAM was made in its header.
+//!
+//! The BAM commands copy the header of their input, so without a record of their own a split
+//! file can't be told apart from the original. Each output gets an `@PG` line for rftools, with
+//! its version and command line, chained through `PP` to the last program already in the
+//! header, e.g. the basecaller. Optionally `@CO` lines record a checksum of each unblocked read
+//! ID list and the settings used, so a split can be checked against the lists it was made from.
+//!
+//! # Functions
+//!
+//! - [`command_line`](fn.command_line.html): The command line rftools was run with, quoted for a shell.
+//! - [`add_program`](fn.add_program.html): Add an `@PG` line for rftools to a header.
+//! - [`list_comments`](fn.list_comments.html): `@CO` lines recording the unblocked read ID lists.
+//! - [`settings_comment`](fn.settings_comment.html): An `@CO` line recording the settings used.
+//!
+use crate::read_ids::UnblockedReads;
+use noodles::sam::{
+    header::record::value::{
+        map::{program::tag, Program},
+        Map,
+    },
+    Header,
+};
+use std::{env, io};
+
+/// Name, and `@PG` ID, of this program.
+const PROGRAM_NAME: &str = "rftools";
+
+/// Quote an argument for a shell, if it needs it.
+fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:=,+@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The command line rftools was run with, with arguments quoted so that it can be pasted back
+/// into a shell. Tabs, which can't be in a SAM header field, are replaced with spaces, and
+/// arguments which aren't UTF-8 are converted lossily.
+pub fn command_line() -> String {
+    env::args_os()
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<String>>()
+        .join(" ")
+        .replace('\t', " ")
+}
+
+/// The ID of the last program in the chain of `@PG` lines, the last one in the header which no
+/// other program names as its previous program.
+fn last_program(header: &Header) -> Option<Vec<u8>> {
+    let programs = header.programs();
+    let previous: Vec<&[u8]> = programs
+        .values()
+        .filter_map(|program| program.other_fields().get(&tag::PREVIOUS_PROGRAM_ID))
+        .map(|id| id.as_ref())
+        .collect();
+    programs
+        .keys()
+        .rev()
+        .find(|id| !previous.contains(&id.as_ref()))
+        .map(|id| id.to_vec())
+}
+
+/// Add an `@PG` line for rftools to a header, with its version and `command_line`, chained
+/// to the last program already in the header. Its ID is `rftools`, or `rftools.1`, `rftools.2`
+/// and so on if the header already has one, as when a split file is split again.
+pub fn add_program(header: &mut Header, command_line: &str) {
+    let previous = last_program(header);
+    let id = (0..)
+        .map(|n| match n {
+            0 => PROGRAM_NAME.to_owned(),
+            n => format!("{}.{}", PROGRAM_NAME, n),
+        })
+        .find(|id| !header.programs().contains_key(id.as_bytes()))
+        .expect("a free program id");
+    let mut program = Map::<Program>::builder()
+        .insert(tag::NAME, PROGRAM_NAME)
+        .insert(tag::VERSION, env!("CARGO_PKG_VERSION"))
+        .insert(tag::COMMAND_LINE, command_line);
+    if let Some(previous) = previous {
+        program = program.insert(tag::PREVIOUS_PROGRAM_ID, previous);
+    }
+    let program = program.build().expect("a valid program");
+    header.programs_mut().insert(id.into(), program);
+}
+
+/// `@CO` lines recording each unblocked read ID list, with its label, the number of read IDs
+/// and their [MD5 checksum](crate::read_ids::ReadIds::checksum). An on disk index is read through
+/// once for this.
+pub fn list_comments(unblocked: &UnblockedReads) -> Result<Vec<String>, io::Error> {
+    Ok(unblocked
+        .checksums()?
+        .into_iter()
+        .map(|(label, len, checksum)| {
+            format!(
+                "{}: unblocked read ids {}: {} ids, md5 {}",
+                PROGRAM_NAME, label, len, checksum
+            )
+        })
+        .collect())
+}
+
+/// An `@CO` line recording the settings a command was run with, as `name=value` pairs, with
+/// `none` for those which weren't set.
+pub fn settings_comment(settings: &[(&str, Option<String>)]) -> String {
+    let settings: Vec<String> = settings
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value.as_deref().unwrap_or("none")))
+        .collect();
+    format!("{}: settings {}", PROGRAM_NAME, settings.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::bam;
+    use std::fs::File;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("--bam-file"), "--bam-file");
+        assert_eq!(quote("reads/input.bam"), "reads/input.bam");
+        assert_eq!(
+            quote("{barcode}/{category}.{ext}"),
+            "'{barcode}/{category}.{ext}'"
+        );
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn test_settings_comment() {
+        use crate::_splitting::{value_name, SplitType};
+        assert_eq!(
+            settings_comment(&[
+                ("split-type", Some(value_name(&SplitType::SequencedOnly))),
+                ("qual-thresh", Some(20.to_string())),
+                ("length-thresh", None),
+            ]),
+            "rftools: settings split-type=sequenced-only qual-thresh=20 length-thresh=none"
+        );
+    }
+
+    #[test]
+    fn test_add_program() {
+        let mut reader = bam::io::Reader::new(File::open("tests/test_reads.bam").unwrap());
+        let mut header = reader.read_header().unwrap();
+        add_program(&mut header, "rftools split-bam -b in.bam");
+        add_program(&mut header, "rftools split-bam -b sequenced.bam");
+        let programs = header.programs();
+        let ids: Vec<&[u8]> = programs.keys().map(|id| id.as_ref()).collect();
+        assert_eq!(ids, [&b"basecaller"[..], b"rftools", b"rftools.1"]);
+        let field = |id: &[u8], tag| {
+            programs[id]
+                .other_fields()
+                .get(&tag)
+                .map(|value| value.to_string())
+        };
+        assert_eq!(
+            field(b"rftools", tag::PREVIOUS_PROGRAM_ID).as_deref(),
+            Some("basecaller")
+        );
+        assert_eq!(
+            field(b"rftools.1", tag::PREVIOUS_PROGRAM_ID).as_deref(),
+            Some("rftools")
+        );
+        assert_eq!(field(b"rftools", tag::NAME).as_deref(), Some("rftools"));
+        assert_eq!(
+            field(b"rftools.1", tag::COMMAND_LINE).as_deref(),
+            Some("rftools split-bam -b sequenced.bam")
+        );
+        assert_eq!(
+            field(b"rftools", tag::VERSION).as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+    }
+}
@@ -0,0 +1,729 @@
+//! Module for compact storage of read IDs.
+//!
+//! ONT read IDs are canonical, lower case, hyphenated UUIDs (`8-4-4-4-12` hex digits).
+//! Stored as a `String` each one costs 36 bytes of heap, plus a 24 byte `String` header
+//! and the allocator overhead, in addition to the hash table slot. Here they are packed into a
+//! `u128` instead, so a PromethION sized unblocked list takes a fraction of the memory.
+//!
+//! Any ID which is not a canonical UUID is kept as an owned byte string, so lookups still
+//! behave exactly like a set of strings.
+//!
+//! # Structs
+//!
+//! - [`ReadIdMap`](struct.ReadIdMap.html): Map keyed by read ID, storing UUIDs as `u128`.
+//! - [`ReadIdSet`](struct.ReadIdSet.html): Set of read IDs, storing UUIDs as `u128`.
+//! - [`MemoryReport`](struct.MemoryReport.html): Estimate of the heap used by a `ReadIdSet`.
+//! - [`UnblockedReads`](struct.UnblockedReads.html): One or more labelled lists of unblocked read IDs.
+//! - [`ListTally`](struct.ListTally.html): Counts of rejections by the list they came from.
+//!
+//! The lists can be fronted by a Bloom filter, see [`UnblockedReads::build_prefilter`].
+//!
+//! # Enums
+//!
+//! - [`ReadIds`](enum.ReadIds.html): Read IDs held in memory, or in an on disk index.
+//!
+//! # Functions
+//!
+//! - [`parse_uuid`](fn.parse_uuid.html): Parse a canonical UUID into a `u128`.
+//! - [`format_uuid`](fn.format_uuid.html): Write a `u128` back out as a canonical UUID.
+//!
+use crate::bloom::{hash_id, hash_uuid, BloomFilter, Prefilter, PrefilterStats};
+use crate::decisions::ReadDecisions;
+use crate::id_index::ReadIdIndex;
+use fnv::FnvHashMap;
+use md5::{Digest, Md5};
+use std::{fmt, io, mem};
+
+/// Maximum number of unblocked read ID lists, one bit each in a [`ListHits`].
+pub const MAX_LISTS: usize = ListHits::BITS as usize;
+
+/// Bit set of the unblocked read ID lists a read was found in, bit `i` for list `i`.
+pub type ListHits = u64;
+
+/// Length of a hyphenated UUID.
+const UUID_LEN: usize = 36;
+/// Positions of the hyphens in a hyphenated UUID.
+const HYPHENS: [usize; 4] = [8, 13, 18, 23];
+
+/// Parse a canonical (lower case, hyphenated) UUID into a `u128`.
+///
+/// Upper case UUIDs are deliberately not accepted, so that two IDs which differ as strings
+/// never compare equal once packed.
+///
+/// # Arguments
+///
+/// * `id` - The read ID bytes.
+///
+/// # Returns
+///
+/// Returns `Some(u128)` if `id` is a canonical UUID, `None` otherwise.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let id = parse_uuid(b"0afabd54-d901-473f-94ba-c7a6e69716f5");
+/// assert_eq!(id, Some(0x0afabd54d901473f94bac7a6e69716f5));
+/// ```
+pub fn parse_uuid(id: &[u8]) -> Option<u128> {
+    if id.len() != UUID_LEN {
+        return None;
+    }
+    let mut value: u128 = 0;
+    for (i, &b) in id.iter().enumerate() {
+        if HYPHENS.contains(&i) {
+            if b != b'-' {
+                return None;
+            }
+            continue;
+        }
+        let nibble = match b {
+            b'0'..=b'9' => b - b'0',
+            b'a'..=b'f' => b - b'a' + 10,
+            _ => return None,
+        };
+        value = (value << 4) | nibble as u128;
+    }
+    Some(value)
+}
+
+/// Write a UUID packed by [`parse_uuid`] back out in its canonical form.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let id = format_uuid(0x0afabd54d901473f94bac7a6e69716f5);
+/// assert_eq!(&id, b"0afabd54-d901-473f-94ba-c7a6e69716f5");
+/// ```
+pub fn format_uuid(uuid: u128) -> [u8; UUID_LEN] {
+    let mut id = [b'-'; UUID_LEN];
+    let mut nibbles = (0..32).rev().map(|i| (uuid >> (i * 4)) as u8 & 0xf);
+    for (i, b) in id.iter_mut().enumerate() {
+        if !HYPHENS.contains(&i) {
+            *b = b"0123456789abcdef"[usize::from(nibbles.next().unwrap())];
+        }
+    }
+    id
+}
+
+/// A map keyed by read ID. Canonical UUIDs are stored as `u128`, anything else as owned bytes.
+///
+/// Lookups take a byte slice, so checking a record never allocates.
+#[derive(Debug)]
+pub struct ReadIdMap<V> {
+    /// Values for read IDs that are canonical UUIDs.
+    uuids: FnvHashMap<u128, V>,
+    /// Values for read IDs that are not canonical UUIDs.
+    other: FnvHashMap<Box<[u8]>, V>,
+}
+
+impl<V> Default for ReadIdMap<V> {
+    fn default() -> Self {
+        ReadIdMap {
+            uuids: FnvHashMap::default(),
+            other: FnvHashMap::default(),
+        }
+    }
+}
+
+impl<V> ReadIdMap<V> {
+    /// Insert a value for a read ID, returning the previous value if there was one.
+    pub fn insert<T: AsRef<[u8]> + ?Sized>(&mut self, id: &T, value: V) -> Option<V> {
+        let id = id.as_ref();
+        match parse_uuid(id) {
+            Some(uuid) => self.uuids.insert(uuid, value),
+            None => self.other.insert(id.into(), value),
+        }
+    }
+
+    /// Get the value for a read ID.
+    pub fn get<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> Option<&V> {
+        let id = id.as_ref();
+        match parse_uuid(id) {
+            Some(uuid) => self.uuids.get(&uuid),
+            None => self.other.get(id),
+        }
+    }
+
+    /// Get a mutable reference to the value for a read ID.
+    pub fn get_mut<T: AsRef<[u8]> + ?Sized>(&mut self, id: &T) -> Option<&mut V> {
+        let id = id.as_ref();
+        match parse_uuid(id) {
+            Some(uuid) => self.uuids.get_mut(&uuid),
+            None => self.other.get_mut(id),
+        }
+    }
+
+    /// Iterate over the values, in no particular order.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.uuids.values().chain(self.other.values())
+    }
+
+    /// Iterate over the read IDs as Bloom filter hashes, with their values.
+    pub fn hashes(&self) -> impl Iterator<Item = (u64, &V)> {
+        self.uuids
+            .iter()
+            .map(|(uuid, value)| (hash_uuid(*uuid), value))
+            .chain(self.other.iter().map(|(id, value)| (hash_id(id), value)))
+    }
+
+    /// The read IDs whose values are kept by `keep`, as UUIDs and other IDs, in no particular
+    /// order.
+    pub fn ids<F: Fn(&V) -> bool>(&self, keep: F) -> (Vec<u128>, Vec<&[u8]>) {
+        let uuids = self
+            .uuids
+            .iter()
+            .filter(|(_, value)| keep(value))
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        let other = self
+            .other
+            .iter()
+            .filter(|(_, value)| keep(value))
+            .map(|(id, _)| &id[..])
+            .collect();
+        (uuids, other)
+    }
+
+    /// Number of read IDs in the map.
+    pub fn len(&self) -> usize {
+        self.uuids.len() + self.other.len()
+    }
+
+    /// Estimate the heap memory used by this map.
+    pub fn memory_report(&self) -> MemoryReport {
+        let other_bytes: usize = self.other.keys().map(|id| id.len()).sum();
+        MemoryReport {
+            uuids: self.uuids.len(),
+            other: self.other.len(),
+            bytes: table_bytes::<(u128, V)>(self.uuids.capacity())
+                + table_bytes::<(Box<[u8]>, V)>(self.other.capacity())
+                + other_bytes,
+            disk_backed: false,
+        }
+    }
+}
+
+/// A set of read IDs. Canonical UUIDs are stored as `u128`, anything else as owned bytes.
+///
+/// Lookups take a byte slice, so checking a record never allocates.
+#[derive(Debug, Default)]
+pub struct ReadIdSet(ReadIdMap<()>);
+
+impl ReadIdSet {
+    /// Create an empty `ReadIdSet`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a read ID, returning `true` if it was not already present.
+    pub fn insert<T: AsRef<[u8]> + ?Sized>(&mut self, id: &T) -> bool {
+        self.0.insert(id, ()).is_none()
+    }
+
+    /// Check whether a read ID is in the set.
+    pub fn contains<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> bool {
+        self.0.get(id).is_some()
+    }
+
+    /// Iterate over the read IDs as Bloom filter hashes.
+    pub fn hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.0.hashes().map(|(hash, _)| hash)
+    }
+
+    /// Number of read IDs in the set.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if the set contains no read IDs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimate the heap memory used by this set.
+    pub fn memory_report(&self) -> MemoryReport {
+        self.0.memory_report()
+    }
+}
+
+/// Read IDs to look records up in, either loaded into memory, in an on disk index
+/// built by `rftools index-ids`, or the final decisions from a readfish chunk log.
+#[derive(Debug)]
+pub enum ReadIds {
+    /// Read IDs loaded into memory.
+    InMemory(ReadIdSet),
+    /// Read IDs in a sorted index on disk.
+    OnDisk(ReadIdIndex),
+    /// Final decision for every read in a readfish chunk log. Reads with a final
+    /// decision of unblock count as unblocked.
+    Decisions(ReadDecisions),
+}
+
+impl ReadIds {
+    /// Check whether a read ID is present, i.e. the read was unblocked.
+    pub fn contains<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> bool {
+        match self {
+            ReadIds::InMemory(set) => set.contains(id),
+            ReadIds::OnDisk(index) => index.contains(id),
+            ReadIds::Decisions(decisions) => decisions.is_unblocked(id),
+        }
+    }
+
+    /// The readfish decisions, if the read IDs came from a chunk log.
+    pub fn decisions(&self) -> Option<&ReadDecisions> {
+        match self {
+            ReadIds::Decisions(decisions) => Some(decisions),
+            _ => None,
+        }
+    }
+
+    /// Number of read IDs which were unblocked.
+    pub fn len(&self) -> usize {
+        match self {
+            ReadIds::InMemory(set) => set.len(),
+            ReadIds::OnDisk(index) => index.len(),
+            ReadIds::Decisions(decisions) => decisions.n_unblocked(),
+        }
+    }
+
+    /// Returns `true` if there are no read IDs.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            ReadIds::InMemory(set) => set.is_empty(),
+            _ => self.len() == 0,
+        }
+    }
+
+    /// Call `f` with the Bloom filter hash of every unblocked read ID. An on disk index is read
+    /// through once.
+    fn for_each_hash<F: FnMut(u64)>(&self, f: F) -> Result<(), io::Error> {
+        match self {
+            ReadIds::InMemory(set) => set.hashes().for_each(f),
+            ReadIds::OnDisk(index) => index.for_each_hash(f)?,
+            ReadIds::Decisions(decisions) => decisions.unblocked_hashes().for_each(f),
+        }
+        Ok(())
+    }
+
+    /// Add the unblocked read IDs to a Bloom filter. An on disk index is read through once.
+    pub fn add_to_filter(&self, filter: &mut BloomFilter) -> Result<(), io::Error> {
+        self.for_each_hash(|hash| filter.insert_hash(hash))
+    }
+
+    /// A checksum of the unblocked read IDs, the MD5 of the sorted IDs one per line, as
+    /// `LC_ALL=C sort -u | md5sum` gives for a list of them. It doesn't depend on the order of
+    /// the IDs, or on how the list was stored or compressed. An on disk index is read through
+    /// once, its UUIDs already being sorted.
+    pub fn checksum(&self) -> Result<String, io::Error> {
+        let mut digest = IdDigest::default();
+        match self {
+            ReadIds::InMemory(set) => digest.add_sorted(set.0.ids(|_| true)),
+            ReadIds::OnDisk(index) => {
+                index.for_each_uuid(|uuid| digest.add(&format_uuid(uuid)))?
+            }
+            ReadIds::Decisions(decisions) => digest.add_sorted(decisions.unblocked_ids()),
+        }
+        Ok(digest.finish())
+    }
+
+    /// Estimate the memory held for the read IDs.
+    pub fn memory_report(&self) -> MemoryReport {
+        match self {
+            ReadIds::InMemory(set) => set.memory_report(),
+            ReadIds::OnDisk(index) => index.memory_report(),
+            ReadIds::Decisions(decisions) => decisions.memory_report(),
+        }
+    }
+}
+
+/// MD5 digest of a list of read IDs, one per line.
+#[derive(Default)]
+struct IdDigest(Md5);
+
+impl IdDigest {
+    /// Add the next read ID.
+    fn add(&mut self, id: &[u8]) {
+        self.0.update(id);
+        self.0.update(b"\n");
+    }
+
+    /// Add UUIDs and other read IDs in byte order, merging the two once each is sorted.
+    fn add_sorted(&mut self, (mut uuids, mut other): (Vec<u128>, Vec<&[u8]>)) {
+        // Canonical UUIDs sort the same as text as they do packed
+        uuids.sort_unstable();
+        other.sort_unstable();
+        let mut other = other.into_iter().peekable();
+        for uuid in uuids {
+            let uuid = format_uuid(uuid);
+            while let Some(id) = other.next_if(|id| *id < &uuid[..]) {
+                self.add(id);
+            }
+            self.add(&uuid);
+        }
+        other.for_each(|id| self.add(id));
+    }
+
+    /// The digest in hex.
+    fn finish(self) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
+
+/// One or more labelled lists of unblocked read IDs, e.g. one per flowcell position.
+///
+/// Every list is checked for each read, so that rejections can be attributed to the list they
+/// came from, and reads found in several lists can be reported.
+#[derive(Debug)]
+pub struct UnblockedReads {
+    /// Label and read IDs for each list.
+    lists: Vec<(String, ReadIds)>,
+    /// Bloom filter over every list, checked before the lists themselves.
+    prefilter: Option<Prefilter>,
+}
+
+impl UnblockedReads {
+    /// Create from labelled lists. There can be at most [`MAX_LISTS`] lists.
+    pub fn new(lists: Vec<(String, ReadIds)>) -> Self {
+        assert!(lists.len() <= MAX_LISTS, "too many unblocked read id lists");
+        UnblockedReads {
+            lists,
+            prefilter: None,
+        }
+    }
+
+    /// Put a Bloom filter with false positive rate `fpr` in front of the lists, so that only
+    /// reads which pass it are checked against the lists themselves.
+    ///
+    /// # Arguments
+    ///
+    /// * `fpr` - The target false positive rate of the filter, between 0 and 1.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `io::Error` if an on disk index can not be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let mut unblocked = unblock.load()?;
+    /// unblocked.build_prefilter(0.01)?;
+    /// let hits = unblocked.find("0afabd54-d901-473f-94ba-c7a6e69716f5");
+    /// println!("{}", unblocked.prefilter_stats().unwrap());
+    /// ```
+    pub fn build_prefilter(&mut self, fpr: f64) -> Result<(), io::Error> {
+        let n = self.lists.iter().map(|(_, list)| list.len()).sum();
+        let mut filter = BloomFilter::new(n, fpr);
+        for (_, list) in &self.lists {
+            list.add_to_filter(&mut filter)?;
+        }
+        self.prefilter = Some(Prefilter::new(filter));
+        Ok(())
+    }
+
+    /// Counts of the lookups made through the Bloom filter, if there is one.
+    pub fn prefilter_stats(&self) -> Option<PrefilterStats> {
+        self.prefilter.as_ref().map(|prefilter| prefilter.stats())
+    }
+
+    /// The lists containing a read ID, as a bit set. Zero if the read was not unblocked.
+    pub fn find<T: AsRef<[u8]> + ?Sized>(&self, id: &T) -> ListHits {
+        let id = id.as_ref();
+        if let Some(prefilter) = &self.prefilter {
+            if !prefilter.may_contain(id) {
+                return 0;
+            }
+        }
+        let hits = self
+            .lists
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, list))| list.contains(id))
+            .fold(0, |hits, (i, _)| hits | 1 << i);
+        if let (Some(prefilter), 0) = (&self.prefilter, hits) {
+            prefilter.false_positive();
+        }
+        hits
+    }
+
+    /// The label, number of read IDs and [`checksum`](enum.ReadIds.html#method.checksum) of
+    /// each list.
+    pub fn checksums(&self) -> Result<Vec<(&str, usize, String)>, io::Error> {
+        self.lists
+            .iter()
+            .map(|(label, list)| Ok((label.as_str(), list.len(), list.checksum()?)))
+            .collect()
+    }
+
+    /// The readfish decisions, from the first list that came from a chunk log.
+    pub fn decisions(&self) -> Option<&ReadDecisions> {
+        self.lists.iter().find_map(|(_, list)| list.decisions())
+    }
+
+    /// Returns `true` if none of the lists contain any read IDs.
+    pub fn is_empty(&self) -> bool {
+        self.lists.iter().all(|(_, list)| list.is_empty())
+    }
+
+    /// Estimate the memory held for all of the lists.
+    pub fn memory_report(&self) -> MemoryReport {
+        self.lists.iter().map(|(_, list)| list.memory_report()).fold(
+            MemoryReport {
+                uuids: 0,
+                other: 0,
+                bytes: 0,
+                disk_backed: false,
+            },
+            |total, report| MemoryReport {
+                uuids: total.uuids + report.uuids,
+                other: total.other + report.other,
+                bytes: total.bytes + report.bytes,
+                disk_backed: total.disk_backed || report.disk_backed,
+            },
+        )
+    }
+
+    /// An empty tally of rejections by list, `None` if there is only one list.
+    pub fn tally(&self) -> Option<ListTally> {
+        if self.lists.len() < 2 {
+            return None;
+        }
+        Some(ListTally {
+            labels: self.lists.iter().map(|(label, _)| label.clone()).collect(),
+            counts: vec![0; self.lists.len()],
+            in_several: 0,
+        })
+    }
+}
+
+/// Number of reads found in several lists which are warned about individually.
+const MAX_OVERLAP_WARNINGS: usize = 10;
+
+/// Counts of rejected reads by the list they were found in. A read in several lists is
+/// counted against the first, and a warning is printed. Created by [`UnblockedReads::tally`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListTally {
+    /// Label of each list.
+    labels: Vec<String>,
+    /// Rejections attributed to each list.
+    counts: Vec<usize>,
+    /// Reads found in more than one list.
+    in_several: usize,
+}
+
+impl ListTally {
+    /// Count a read by the lists it was found in.
+    pub fn add<T: AsRef<[u8]> + ?Sized>(&mut self, id: &T, hits: ListHits) {
+        if hits == 0 {
+            return;
+        }
+        self.counts[hits.trailing_zeros() as usize] += 1;
+        if hits.count_ones() > 1 {
+            self.in_several += 1;
+            if self.in_several <= MAX_OVERLAP_WARNINGS {
+                let labels: Vec<&str> = (0..self.labels.len())
+                    .filter(|i| hits & (1 << i) != 0)
+                    .map(|i| self.labels[i].as_str())
+                    .collect();
+                eprintln!(
+                    "Warning: read {} is in more than one unblocked list ({})",
+                    String::from_utf8_lossy(id.as_ref()),
+                    labels.join(", ")
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for ListTally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unblocked reads by list:")?;
+        for (label, count) in self.labels.iter().zip(&self.counts) {
+            write!(f, "\n\t{}: {}", label, count)?;
+        }
+        if self.in_several > 0 {
+            write!(
+                f,
+                "\nWarning: {} reads were in more than one unblocked list, each is counted against the first",
+                self.in_several
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Approximate heap size of a hash table with the given capacity. hashbrown keeps its
+/// buckets at most 7/8 full, and stores one control byte per bucket.
+fn table_bytes<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = (capacity * 8 / 7).next_power_of_two();
+    buckets * (mem::size_of::<T>() + 1)
+}
+
+/// Estimate of the memory held by a [`ReadIdSet`] or read ID index, for sizing cluster jobs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryReport {
+    /// Number of read IDs stored as UUIDs.
+    pub uuids: usize,
+    /// Number of read IDs stored as strings.
+    pub other: usize,
+    /// Estimated heap usage in bytes.
+    pub bytes: usize,
+    /// Whether the UUIDs are held on disk rather than in memory.
+    pub disk_backed: bool,
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = self.bytes as f64 / (1024.0 * 1024.0);
+        if self.disk_backed {
+            write!(
+                f,
+                "Opened index of {} read ids ({} UUIDs on disk, {} other), using ~{:.1} MiB",
+                self.uuids + self.other,
+                self.uuids,
+                self.other,
+                mib
+            )
+        } else {
+            write!(
+                f,
+                "Loaded {} read ids ({} UUIDs, {} other), using ~{:.1} MiB",
+                self.uuids + self.other,
+                self.uuids,
+                self.other,
+                mib
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uuid() {
+        assert_eq!(
+            parse_uuid(b"0afabd54-d901-473f-94ba-c7a6e69716f5"),
+            Some(0x0afabd54_d901_473f_94ba_c7a6e69716f5)
+        );
+        // Upper case, missing hyphens and wrong lengths are not canonical
+        assert_eq!(parse_uuid(b"0AFABD54-D901-473F-94BA-C7A6E69716F5"), None);
+        assert_eq!(parse_uuid(b"0afabd54d901473f94bac7a6e69716f5"), None);
+        assert_eq!(parse_uuid(b"0afabd54-d901-473f-94ba-c7a6e69716f"), None);
+        assert_eq!(parse_uuid(b""), None);
+    }
+
+    #[test]
+    fn test_read_id_set() {
+        let mut set = ReadIdSet::new();
+        assert!(set.is_empty());
+        assert!(set.insert("0afabd54-d901-473f-94ba-c7a6e69716f5"));
+        assert!(!set.insert("0afabd54-d901-473f-94ba-c7a6e69716f5"));
+        assert!(set.insert("read_1"));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(b"0afabd54-d901-473f-94ba-c7a6e69716f5".as_slice()));
+        assert!(set.contains("read_1"));
+        assert!(!set.contains("0AFABD54-D901-473F-94BA-C7A6E69716F5"));
+        assert!(!set.contains("read_2"));
+    }
+
+    #[test]
+    fn test_unblocked_reads() {
+        let mut p1 = ReadIdSet::new();
+        p1.insert("read_1");
+        p1.insert("read_2");
+        let mut p2 = ReadIdSet::new();
+        p2.insert("read_2");
+        p2.insert("read_3");
+        let unblocked = UnblockedReads::new(vec![
+            (String::from("P1"), ReadIds::InMemory(p1)),
+            (String::from("P2"), ReadIds::InMemory(p2)),
+        ]);
+        assert_eq!(unblocked.find("read_1"), 0b01);
+        assert_eq!(unblocked.find("read_2"), 0b11);
+        assert_eq!(unblocked.find("read_3"), 0b10);
+        assert_eq!(unblocked.find("read_4"), 0);
+        assert!(!unblocked.is_empty());
+
+        let mut tally = unblocked.tally().unwrap();
+        for id in ["read_1", "read_2", "read_3", "read_4"] {
+            tally.add(id, unblocked.find(id));
+        }
+        assert_eq!(tally.counts, vec![2, 1]);
+        assert_eq!(tally.in_several, 1);
+    }
+
+    #[test]
+    fn test_unblocked_reads_prefilter() {
+        let mut set = ReadIdSet::new();
+        set.insert("0afabd54-d901-473f-94ba-c7a6e69716f5");
+        set.insert("read_1");
+        let mut unblocked =
+            UnblockedReads::new(vec![(String::from("P1"), ReadIds::InMemory(set))]);
+        assert!(unblocked.prefilter_stats().is_none());
+        unblocked.build_prefilter(0.001).unwrap();
+        assert_eq!(unblocked.find("0afabd54-d901-473f-94ba-c7a6e69716f5"), 1);
+        assert_eq!(unblocked.find("read_1"), 1);
+        assert_eq!(unblocked.find("read_2"), 0);
+        assert_eq!(unblocked.find("8d2ce7e4-fb4d-4ba4-9bd8-7a5cc8cd4f0e"), 0);
+        let stats = unblocked.prefilter_stats().unwrap();
+        assert_eq!(stats.lookups, 4);
+        assert_eq!(stats.avoided + stats.false_positives, 2);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let set = |ids: &[&str]| {
+            let mut set = ReadIdSet::new();
+            ids.iter().for_each(|id| {
+                set.insert(*id);
+            });
+            ReadIds::InMemory(set)
+        };
+        let forward = set(&["0afabd54-d901-473f-94ba-c7a6e69716f5", "read_1"]);
+        let reverse = set(&["read_1", "0afabd54-d901-473f-94ba-c7a6e69716f5"]);
+        assert_eq!(forward.checksum().unwrap(), reverse.checksum().unwrap());
+        assert_ne!(
+            forward.checksum().unwrap(),
+            set(&["read_1"]).checksum().unwrap()
+        );
+        // echo -e "0afabd54-d901-473f-94ba-c7a6e69716f5\nread_1" | md5sum
+        assert_eq!(
+            forward.checksum().unwrap(),
+            "c2d24839f7052e0a1e17b447cfdd2f25"
+        );
+        // Other IDs either side of the UUIDs, in byte order, as with
+        // echo -e "0\n1afabd54-d901-473f-94ba-c7a6e69716f5\nread_1" | md5sum
+        assert_eq!(
+            set(&["read_1", "1afabd54-d901-473f-94ba-c7a6e69716f5", "0"])
+                .checksum()
+                .unwrap(),
+            "81ffff29917972abd6ffaedcb3d15013"
+        );
+        // printf "" | md5sum
+        assert_eq!(
+            set(&[]).checksum().unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        let unblocked = UnblockedReads::new(vec![(String::from("P1"), forward)]);
+        let checksums = unblocked.checksums().unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(checksums[0].0, "P1");
+        assert_eq!(checksums[0].1, 2);
+    }
+
+    #[test]
+    fn test_memory_report() {
+        let mut set = ReadIdSet::new();
+        set.insert("0afabd54-d901-473f-94ba-c7a6e69716f5");
+        set.insert("read_1");
+        let report = set.memory_report();
+        assert_eq!(report.uuids, 1);
+        assert_eq!(report.other, 1);
+        assert!(report.bytes > 0);
+        assert_eq!(ReadIdSet::new().memory_report().bytes, 0);
+    }
+}
@@ -0,0 +1,304 @@
+//! Module for the per read metadata MinKNOW records in BAM, FASTQ and sequencing summary files.
+//!
+//! Each format carries the same information in a different place: the channel is the `ch`
+//! aux tag in a BAM, the `ch=` field of a FASTQ header, and the `channel` column of a
+//! sequencing summary. This module reads them all into a [`ReadMeta`].
+//!
+//! # Structs
+//!
+//! - [`ReadMeta`](struct.ReadMeta.html): Length, mean quality, channel and start time of a read.
+//! - [`SummaryColumns`](struct.SummaryColumns.html): Positions of the metadata columns in a sequencing summary.
+//! - [`ReadGroupBarcodes`](struct.ReadGroupBarcodes.html): Barcodes of the read groups in a BAM header.
+//!
+//! # Functions
+//!
+//! - [`header_field`](fn.header_field.html): Find a `key=value` field in a FASTX header.
+//! - [`bam_channel`](fn.bam_channel.html): The channel in the `ch` tag of a BAM record.
+//! - [`bam_start_time`](fn.bam_start_time.html): The start time in the `st` tag of a BAM record.
+//! - [`read_group_run_start`](fn.read_group_run_start.html): The start of the run, from the read groups in a BAM header.
+//! - [`parse_field`](fn.parse_field.html): Parse a number from a field of a header or summary.
+//!
+use crate::_splitting::{_ave_qual, get_key_col};
+use crate::time_bins::parse_timestamp;
+use fnv::FnvHashMap;
+use noodles::bam;
+use noodles::sam::{
+    alignment::record::{
+        data::field::{Tag, Value},
+        Data,
+    },
+    header::record::value::map::read_group::tag as read_group_tag,
+    Header,
+};
+use std::str;
+
+/// Quality score BAM uses for every base when a record has no qualities.
+const MISSING_QUAL: u8 = 0xff;
+/// Offset of FASTQ quality characters.
+const PHRED_OFFSET: u8 = 33;
+/// Tag MinKNOW writes the channel of a read to.
+const CHANNEL: Tag = Tag::new(b'c', b'h');
+/// Tag MinKNOW writes the start time of a read to.
+const START_TIME: Tag = Tag::new(b's', b't');
+
+/// Find the value of a `key=value` field in a FASTX header, as written by MinKNOW.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let header = b"0afabd54-d901-473f-94ba-c7a6e69716f5 runid=5e8c2d1a ch=12";
+/// assert_eq!(header_field(header, b"ch"), Some(b"12".as_slice()));
+/// ```
+pub fn header_field<'a>(header: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    header
+        .split(|b| b.is_ascii_whitespace())
+        .skip(1)
+        .find_map(|field| {
+            field
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix(b"="))
+        })
+}
+
+/// The channel of a BAM, SAM or CRAM record, from its `ch` tag.
+pub fn bam_channel(data: &dyn Data) -> Option<u32> {
+    match data.get(&CHANNEL) {
+        Some(Ok(value)) => value.as_int().and_then(|ch| u32::try_from(ch).ok()),
+        _ => None,
+    }
+}
+
+/// The start time of a BAM, SAM or CRAM record, from its `st` tag.
+pub fn bam_start_time<'a>(data: &'a (dyn Data + 'a)) -> Option<&'a [u8]> {
+    match data.get(&START_TIME) {
+        Some(Ok(Value::String(st))) => Some(st),
+        _ => None,
+    }
+}
+
+/// The start of the run in seconds since the Unix epoch, from the `DT` field of the read groups
+/// in a BAM header, the earliest if there is more than one.
+pub fn read_group_run_start(header: &Header) -> Option<f64> {
+    header
+        .read_groups()
+        .values()
+        .filter_map(|read_group| read_group.other_fields().get(&read_group_tag::PRODUCED_AT))
+        .filter_map(|dt| parse_timestamp(dt))
+        .reduce(f64::min)
+}
+
+/// Metadata for a read, any of which may be missing from the input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadMeta {
+    /// Read length in bases.
+    pub length: Option<usize>,
+    /// Mean Phred quality, computed from error probabilities.
+    pub mean_q: Option<f64>,
+    /// Channel the read was sequenced on.
+    pub channel: Option<u32>,
+    /// Start time of the read, as written in the input. An ISO 8601 timestamp in BAM and
+    /// FASTQ, seconds since the start of the run in a sequencing summary.
+    pub start_time: Option<String>,
+}
+
+impl ReadMeta {
+    /// Read the metadata of a BAM record, from its sequence, qualities and `ch` and `st` tags.
+    pub fn from_bam(record: &bam::Record) -> Self {
+        let quals = record.quality_scores();
+        let quals = quals.as_ref();
+        let data = record.data();
+        let channel = bam_channel(&data);
+        let start_time = bam_start_time(&data).map(|st| String::from_utf8_lossy(st).into_owned());
+        ReadMeta {
+            length: Some(record.sequence().len()),
+            mean_q: match quals.first() {
+                None | Some(&MISSING_QUAL) => None,
+                Some(_) => Some(_ave_qual(quals)),
+            },
+            channel,
+            start_time,
+        }
+    }
+
+    /// Read the metadata of a FASTX record, from its sequence, qualities and MinKNOW header.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The header line, without the leading `@` or `>`.
+    /// * `seq_len` - The length of the sequence.
+    /// * `qual` - The quality line, `None` for FASTA.
+    pub fn from_fastx(header: &[u8], seq_len: usize, qual: Option<&[u8]>) -> Self {
+        ReadMeta {
+            length: Some(seq_len),
+            mean_q: qual.filter(|qual| !qual.is_empty()).map(|qual| {
+                let quals: Vec<u8> = qual
+                    .iter()
+                    .map(|q| q.saturating_sub(PHRED_OFFSET))
+                    .collect();
+                _ave_qual(&quals)
+            }),
+            channel: header_field(header, b"ch").and_then(parse_field),
+            start_time: header_field(header, b"start_time")
+                .map(|st| String::from_utf8_lossy(st).into_owned()),
+        }
+    }
+}
+
+/// Parse a number from a field of a header or summary.
+pub fn parse_field<T: str::FromStr>(field: &[u8]) -> Option<T> {
+    str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Positions of the metadata columns in a sequencing summary, `None` for those it doesn't have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryColumns {
+    /// The `read_id` column.
+    pub read_id: usize,
+    /// The `sequence_length_template` column.
+    pub length: Option<usize>,
+    /// The `mean_qscore_template` column.
+    pub mean_q: Option<usize>,
+    /// The `channel` column.
+    pub channel: Option<usize>,
+    /// The `start_time` column.
+    pub start_time: Option<usize>,
+    /// The `barcode_arrangement` column.
+    pub barcode: Option<usize>,
+}
+
+impl SummaryColumns {
+    /// Find the columns in the header line of a sequencing summary. Only `read_id` is required.
+    pub fn new(headers: &csv::ByteRecord) -> Result<Self, String> {
+        Ok(SummaryColumns {
+            read_id: get_key_col(headers, b"read_id")?,
+            length: get_key_col(headers, b"sequence_length_template").ok(),
+            mean_q: get_key_col(headers, b"mean_qscore_template").ok(),
+            channel: get_key_col(headers, b"channel").ok(),
+            start_time: get_key_col(headers, b"start_time").ok(),
+            barcode: get_key_col(headers, b"barcode_arrangement").ok(),
+        })
+    }
+
+    /// Read the metadata of a row of the sequencing summary.
+    pub fn meta(&self, row: &csv::ByteRecord) -> ReadMeta {
+        let field = |col: Option<usize>| col.and_then(|col| row.get(col));
+        ReadMeta {
+            length: field(self.length).and_then(parse_field),
+            mean_q: field(self.mean_q).and_then(parse_field),
+            channel: field(self.channel).and_then(parse_field),
+            start_time: field(self.start_time).map(|st| String::from_utf8_lossy(st).into_owned()),
+        }
+    }
+}
+
+/// Barcodes of the read groups in a BAM header, from their `BC` field.
+#[derive(Debug, Clone, Default)]
+pub struct ReadGroupBarcodes(FnvHashMap<Vec<u8>, Vec<u8>>);
+
+impl ReadGroupBarcodes {
+    /// Collect the barcodes of the read groups which have one.
+    pub fn new(header: &Header) -> Self {
+        ReadGroupBarcodes(
+            header
+                .read_groups()
+                .iter()
+                .filter_map(|(id, read_group)| {
+                    let barcode = read_group.other_fields().get(&read_group_tag::BARCODE)?;
+                    Some((id.to_vec(), barcode.to_vec()))
+                })
+                .collect(),
+        )
+    }
+
+    /// The barcode of a BAM record, from its `BC` tag, or else the `BC` field of its read group.
+    ///
+    /// The barcode borrows from `data`, so bind `record.data()` before calling this.
+    pub fn barcode<'a>(&'a self, data: &'a (dyn Data + 'a)) -> Option<&'a [u8]> {
+        if let Some(Ok(Value::String(barcode))) = data.get(&Tag::SAMPLE_BARCODE_SEQUENCE) {
+            return Some(barcode);
+        }
+        match data.get(&Tag::READ_GROUP) {
+            Some(Ok(Value::String(read_group))) => {
+                self.0.get(read_group.as_ref() as &[u8]).map(Vec::as_slice)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_header_field() {
+        let header = b"0afabd54-d901-473f-94ba-c7a6e69716f5 runid=5e8c2d1a ch=12 start_time=2024-01-01T10:00:10Z";
+        assert_eq!(header_field(header, b"ch"), Some(b"12".as_slice()));
+        assert_eq!(
+            header_field(header, b"start_time"),
+            Some(b"2024-01-01T10:00:10Z".as_slice())
+        );
+        assert_eq!(header_field(header, b"c"), None);
+        assert_eq!(header_field(b"ch=1", b"ch"), None);
+    }
+
+    #[test]
+    fn test_from_fastx() {
+        let meta = ReadMeta::from_fastx(
+            b"read_1 ch=7 start_time=2024-01-01T10:00:10Z",
+            4,
+            Some(b"5555"),
+        );
+        assert_eq!(meta.length, Some(4));
+        assert!((meta.mean_q.unwrap() - 20.0).abs() < 1e-9);
+        assert_eq!(meta.channel, Some(7));
+        assert_eq!(meta.start_time.as_deref(), Some("2024-01-01T10:00:10Z"));
+        assert_eq!(ReadMeta::from_fastx(b"read_1", 4, None).mean_q, None);
+    }
+
+    #[test]
+    fn test_summary_columns() {
+        let file = File::open("tests/test_sequencing_summary.txt").unwrap();
+        let mut rdr = csv::ReaderBuilder::new().delimiter(b'\t').from_reader(file);
+        let columns = SummaryColumns::new(rdr.byte_headers().unwrap()).unwrap();
+        assert_eq!(columns.read_id, 1);
+        let mut row = csv::ByteRecord::new();
+        rdr.read_byte_record(&mut row).unwrap();
+        assert_eq!(
+            columns.meta(&row),
+            ReadMeta {
+                length: Some(420),
+                mean_q: Some(12.1),
+                channel: Some(1),
+                start_time: Some(String::from("10.5")),
+            }
+        );
+        assert!(SummaryColumns::new(&csv::ByteRecord::from(vec!["channel"])).is_err());
+    }
+
+    #[test]
+    fn test_read_group_barcodes() {
+        let mut reader = bam::io::Reader::new(File::open("tests/test_reads.bam").unwrap());
+        let header = reader.read_header().unwrap();
+        let barcodes = ReadGroupBarcodes::new(&header);
+        let found: Vec<Option<Vec<u8>>> = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let data = record.data();
+                barcodes.barcode(&data).map(<[u8]>::to_vec)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                Some(b"barcode01".to_vec()),
+                Some(b"barcode01".to_vec()),
+                Some(b"barcode02".to_vec()),
+                None,
+                None
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Module for the reference sequences CRAM files are compressed against.
+//!
+//! CRAM stores aligned reads as differences from the reference, so reading or writing one needs
+//! the reference the reads were aligned to, either as a FASTA or as a local reference cache, a
+//! directory of sequences named by their MD5 checksum as used by samtools. Each `@SQ` line of a
+//! CRAM header must also carry the `M5` checksum of its sequence, which aligners often leave
+//! out, so any missing ones are computed from the FASTA.
+//!
+//! # Structs
+//!
+//! - [`ReferenceArgs`](struct.ReferenceArgs.html): Command line arguments choosing the reference for CRAM input and output.
+//!
+//! # Functions
+//!
+//! - [`reference_repository`](fn.reference_repository.html): Open a reference FASTA to look sequences up in.
+//! - [`add_md5_checksums`](fn.add_md5_checksums.html): Add the missing `M5` checksums to the `@SQ` lines of a header.
+//!
+use crate::_splitting::open_compressed;
+use clap::Args;
+use fnv::FnvHashMap;
+use md5::{Digest, Md5};
+use noodles::{
+    fasta::{
+        self, fai,
+        record::{Definition, Sequence},
+        repository::{adapters::IndexedReader, Adapter},
+    },
+    sam::{header::record::value::map::reference_sequence::tag, Header},
+};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Command line arguments choosing the reference CRAM input is read with, and CRAM output is
+/// compressed against.
+#[derive(Debug, Default, Args)]
+pub struct ReferenceArgs {
+    /// Reference FASTA the reads were aligned to, to read CRAM input and write CRAM output. Needed for CRAM of aligned reads
+    #[clap(long, value_name = "FASTA", parse(from_os_str))]
+    pub reference: Option<PathBuf>,
+    /// Local reference cache to read CRAM input and write CRAM output with, a directory of sequences named by their MD5 checksum, as made by samtools seq_cache_populate.pl
+    #[clap(
+        long,
+        value_name = "DIR",
+        parse(from_os_str),
+        conflicts_with = "reference"
+    )]
+    pub reference_cache: Option<PathBuf>,
+}
+
+impl ReferenceArgs {
+    /// Whether a reference FASTA or cache was given.
+    pub fn is_set(&self) -> bool {
+        self.reference.is_some() || self.reference_cache.is_some()
+    }
+
+    /// The reference sequences of the `@SQ` lines of `header`, from the FASTA or the cache. With
+    /// neither, the repository is empty, which is enough for unaligned reads.
+    pub fn repository(&self, header: &Header) -> Result<fasta::Repository, io::Error> {
+        match (&self.reference, &self.reference_cache) {
+            (Some(reference), _) => reference_repository(reference),
+            (None, Some(cache)) => Ok(fasta::Repository::new(ReferenceCache::new(
+                cache.clone(),
+                header,
+            ))),
+            (None, None) => Ok(fasta::Repository::default()),
+        }
+    }
+
+    /// Add the `M5` checksums missing from the `@SQ` lines of `header`, if a reference FASTA was
+    /// given. Sequences are looked up in a cache by their checksum, so it can't fill them in.
+    pub fn add_md5_checksums(&self, header: &mut Header) -> Result<(), io::Error> {
+        match &self.reference {
+            Some(reference) => add_md5_checksums(header, reference),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A local reference cache, looking the sequences of a header up by the `M5` checksums of its
+/// `@SQ` lines. A sequence is either directly in the cache directory, or, as written by
+/// `seq_cache_populate.pl -subdirs 2`, under two levels of subdirectories named for the first
+/// four characters of its checksum.
+struct ReferenceCache {
+    /// The cache directory.
+    dir: PathBuf,
+    /// Lower case `M5` checksums of the reference sequences, by name.
+    checksums: FnvHashMap<Vec<u8>, String>,
+}
+
+impl ReferenceCache {
+    /// A cache for the reference sequences of `header` which have an `M5` checksum.
+    fn new(dir: PathBuf, header: &Header) -> Self {
+        let checksums = header
+            .reference_sequences()
+            .iter()
+            .filter_map(|(name, sq)| {
+                let m5 = sq.other_fields().get(&tag::MD5_CHECKSUM)?;
+                let m5 = m5.to_string().to_ascii_lowercase();
+                // An MD5 checksum is 32 hex digits, and anything else can't be a path in the cache
+                let is_md5 = m5.len() == 32 && m5.bytes().all(|b| b.is_ascii_hexdigit());
+                is_md5.then(|| (name.to_vec(), m5))
+            })
+            .collect();
+        ReferenceCache { dir, checksums }
+    }
+}
+
+impl Adapter for ReferenceCache {
+    fn get(&mut self, name: &[u8]) -> Option<io::Result<fasta::Record>> {
+        let m5 = self.checksums.get(name)?;
+        let nested = self.dir.join(&m5[..2]).join(&m5[2..4]).join(&m5[4..]);
+        let sequence = match fs::read(self.dir.join(m5)) {
+            Err(err) if err.kind() == ErrorKind::NotFound => fs::read(nested),
+            read => read,
+        };
+        Some(sequence.map(|sequence| {
+            fasta::Record::new(Definition::new(name, None), Sequence::from(sequence))
+        }))
+    }
+}
+
+/// Open a reference FASTA to look sequences up in, using its `.fai` index, or indexing it if
+/// there is none. A gzipped FASTA (`.gz` or `.bgz`) must be BGZF compressed, with both the
+/// `.fai` and `.gzi` indexes made by `samtools faidx`, as it can't be indexed here.
+pub fn reference_repository(path: &Path) -> Result<fasta::Repository, io::Error> {
+    let compressed = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("gz" | "bgz")
+    );
+    let needs_indexes = |err: io::Error| match compressed {
+        true => io::Error::new(
+            err.kind(),
+            format!(
+                "{}: a gzipped reference FASTA must be BGZF compressed, with .fai and .gzi indexes, as made by bgzip and samtools faidx\n{}",
+                path.display(),
+                err
+            ),
+        ),
+        false => err,
+    };
+    let mut fai_path = path.as_os_str().to_owned();
+    fai_path.push(".fai");
+    let index = match fai::read(&fai_path) {
+        Ok(index) => index,
+        Err(err) if err.kind() == ErrorKind::NotFound && !compressed => fasta::index(path)?,
+        Err(err) => return Err(needs_indexes(err)),
+    };
+    let reader = fasta::indexed_reader::Builder::default()
+        .set_index(index)
+        .build_from_path(path)
+        .map_err(needs_indexes)?;
+    Ok(fasta::Repository::new(IndexedReader::new(reader)))
+}
+
+/// Add the `M5` checksum of every `@SQ` line which doesn't have one, reading the sequences
+/// from a reference FASTA.
+///
+/// # Returns
+///
+/// Returns an `io::Error` if the FASTA can't be read, or is missing one of the sequences.
+pub fn add_md5_checksums(header: &mut Header, reference: &Path) -> Result<(), io::Error> {
+    let missing = |header: &Header| -> Vec<Vec<u8>> {
+        header
+            .reference_sequences()
+            .iter()
+            .filter(|(_, sq)| !sq.other_fields().contains_key(&tag::MD5_CHECKSUM))
+            .map(|(name, _)| name.to_vec())
+            .collect()
+    };
+    if missing(header).is_empty() {
+        return Ok(());
+    }
+    let mut reader = fasta::Reader::new(open_compressed(reference)?);
+    let mut definition = String::new();
+    let mut sequence = Vec::new();
+    loop {
+        definition.clear();
+        if reader.read_definition(&mut definition)? == 0 {
+            break;
+        }
+        sequence.clear();
+        reader.read_sequence(&mut sequence)?;
+        let name = definition
+            .trim_start_matches('>')
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+        let reference_sequences = header.reference_sequences_mut();
+        let sq = match reference_sequences.get_mut(name.as_bytes()) {
+            Some(sq) if !sq.other_fields().contains_key(&tag::MD5_CHECKSUM) => sq,
+            _ => continue,
+        };
+        // The checksum is of the upper case sequence
+        sequence.make_ascii_uppercase();
+        let checksum = format!("{:x}", Md5::digest(&sequence));
+        sq.other_fields_mut()
+            .insert(tag::MD5_CHECKSUM, checksum.into());
+    }
+    match missing(header).first() {
+        Some(name) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "reference sequence {} from the BAM header is not in {}",
+                String::from_utf8_lossy(name),
+                reference.display()
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use noodles::sam::header::record::value::{map::ReferenceSequence, Map};
+    use std::{fs, num::NonZeroUsize};
+
+    /// Write a small reference FASTA, returning its path.
+    fn write_reference(name: &str) -> TempPath {
+        let path = TempPath::new(&format!("{}.fa", name));
+        fs::write(&path, ">sq0 first\nACGTacgt\nACGT\n>sq1\nNNNN\n").unwrap();
+        path
+    }
+
+    fn header(names: &[&str]) -> Header {
+        names
+            .iter()
+            .fold(Header::builder(), |builder, name| {
+                builder.add_reference_sequence(
+                    *name,
+                    Map::<ReferenceSequence>::new(NonZeroUsize::new(12).unwrap()),
+                )
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_add_md5_checksums() {
+        let reference = write_reference("md5");
+        let mut sq0 = header(&["sq0"]);
+        add_md5_checksums(&mut sq0, &reference).unwrap();
+        // md5sum of ACGTACGTACGT
+        assert_eq!(
+            sq0.reference_sequences()[b"sq0".as_slice()]
+                .other_fields()
+                .get(&tag::MD5_CHECKSUM)
+                .map(|m5| m5.to_string())
+                .as_deref(),
+            Some("31e91beccf6059ff57c696827c0c6a4b")
+        );
+        assert!(add_md5_checksums(&mut header(&["sq0", "sq2"]), &reference).is_err());
+
+        let repository = reference_repository(&reference).unwrap();
+        let sq1 = repository.get(b"sq1").unwrap().unwrap();
+        assert_eq!(sq1.as_ref(), b"NNNN");
+        // A gzipped FASTA can't be indexed here
+        let gzipped = reference.with_suffix(".gz");
+        fs::copy(&reference, &gzipped).unwrap();
+        let err = reference_repository(&gzipped).unwrap_err();
+        assert!(err.to_string().contains(".fai and .gzi"), "{}", err);
+    }
+
+    #[test]
+    fn test_reference_cache() {
+        let cache = TempPath::new("ref_cache");
+        let m5 = "31e91beccf6059ff57c696827c0c6a4b";
+        let nested = cache.join(&m5[..2]).join(&m5[2..4]);
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join(&m5[4..]), b"ACGTACGTACGT").unwrap();
+        let mut header = header(&["sq0", "sq1"]);
+        header
+            .reference_sequences_mut()
+            .get_mut(b"sq0".as_slice())
+            .unwrap()
+            .other_fields_mut()
+            .insert(tag::MD5_CHECKSUM, m5.into());
+        let args = ReferenceArgs {
+            reference: None,
+            reference_cache: Some(cache.to_path_buf()),
+        };
+        let repository = args.repository(&header).unwrap();
+        let sq0 = repository.get(b"sq0").unwrap().unwrap();
+        assert_eq!(sq0.as_ref(), b"ACGTACGTACGT");
+        // No M5 to look it up by, or one which isn't a checksum
+        assert!(repository.get(b"sq1").is_none());
+        for m5 in ["../../../../../../../etc/passwdx", "aé34567890123456789012345678901"] {
+            assert_eq!(m5.len(), 32);
+            header
+                .reference_sequences_mut()
+                .get_mut(b"sq1".as_slice())
+                .unwrap()
+                .other_fields_mut()
+                .insert(tag::MD5_CHECKSUM, m5.into());
+            assert!(args.repository(&header).unwrap().get(b"sq1").is_none());
+        }
+    }
+}
@@ -1,8 +1,8 @@
 //! Module for splitting BAM files into sequenced and unblocked records.
 //!
 //! This module provides functionality to split BAM files into two categories: sequenced and unblocked records.
-//! It includes the `split_bam` function that takes a BAM file, a list of unblocked read IDs, and other parameters,
-//! and writes the sequenced and unblocked records to separate output files in either BAM, FASTA, or FASTQ format.
+//! It includes the `split_bam` function that takes a SAM, BAM or CRAM file, a list of unblocked read IDs, and other parameters,
+//! and writes the sequenced and unblocked records to separate output files in BAM, SAM, CRAM, FASTA, or FASTQ format.
 //!
 //! # Function
 //!
@@ -41,38 +41,52 @@
 //! - Sequenced Records: Output files containing only sequenced records.
 //! - Unblocked Records: Output files containing only unblocked records.
 //!
-//! The output files can be in BAM, FASTA, or FASTQ format, depending on the chosen `EmitType`.
+//! The output files can be in BAM, SAM, CRAM, FASTA, or FASTQ format, depending on the chosen `EmitType`.
+//! CRAM output of aligned reads is compressed against the reference FASTA given with `--reference`.
 //!
 use crate::_splitting::{
-    _ave_qual, read_unblocked_read_ids, CompressionType, EmitType, SplitType, Wrapper,
+    _ave_qual, value_name, CompressionType, EmitType, SplitType, UnblockArgs, Wrapper,
 };
-use flate2::{write::GzEncoder, Compression};
+use crate::alignment::AlignmentReader;
+use crate::bam_index::{BamIndexer, IndexFormat};
+use crate::classify::{ClassifyArgs, ReadInfo, Router};
+use crate::compress::{CompressionArgs, OutputCompression};
+use crate::output::{output_extension, OutputArgs, OutputFile};
+use crate::provenance::{add_program, command_line, list_comments, settings_comment};
+use crate::read_meta::{bam_channel, bam_start_time, read_group_run_start, ReadGroupBarcodes};
+use crate::reference::ReferenceArgs;
+use crate::targets::TargetArgs;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use noodles::{
-    bam::{self, Record},
-    sam::{alignment::record::QualityScores, Header},
+    bam, cram,
+    csi::binning_index::index::reference_sequence::bin::Chunk,
+    fasta,
+    sam::{
+        self,
+        alignment::{io::Write as _, record::data::field::Tag, Record},
+        Header,
+    },
 };
 use noodles_bgzf as bgzf;
 use std::{
-    fs::File,
-    io::{BufWriter, Error, Write},
-    num::NonZeroUsize,
+    io::{Error, ErrorKind, Write},
     path::PathBuf,
-    thread,
 };
 /// DO the Newline SLice
 const NEWLINE_SLICE: &[u8] = &[10];
 /// Minimum value for a Phred quality score WHAT A DUMB SYSTEm
 const MIN_VALUE: u8 = b'!';
+/// Tag dorado marks duplex reads with.
+const DUPLEX: Tag = Tag::new(b'd', b'x');
 
 /// Write a Record to a Fasta file
 fn write_fasta_record<W: Write>(
-    record: &Record,
+    record: &dyn Record,
     mut writer: W,
     seq: &mut Vec<u8>,
     read_id: &[u8],
 ) -> Result<(), Error> {
-    *seq = record.sequence().iter().map(u8::from).collect();
+    *seq = record.sequence().iter().collect();
     writer.write_all(b">")?;
     writer.write_all(read_id)?;
     writer.write_all(NEWLINE_SLICE)?;
@@ -83,13 +97,13 @@ fn write_fasta_record<W: Write>(
 
 /// write a record to a Fastq file
 fn write_fastq_record<W: Write>(
-    record: &Record,
+    record: &dyn Record,
     mut writer: W,
     seq: &mut Vec<u8>,
     qual: &mut Vec<u8>,
     read_id: &[u8],
 ) -> Result<(), Error> {
-    *seq = record.sequence().iter().map(u8::from).collect();
+    *seq = record.sequence().iter().collect();
     *qual = record
         .quality_scores()
         .iter()
@@ -107,42 +121,138 @@ fn write_fastq_record<W: Write>(
     Ok(())
 }
 
+/// Write a record to a FASTA or FASTQ file, as chosen by `emit_type`.
+fn write_fastx_record<W: Write>(
+    record: &dyn Record,
+    writer: W,
+    emit_type: &EmitType,
+    seq: &mut Vec<u8>,
+    qual: &mut Vec<u8>,
+    read_id: &[u8],
+) -> Result<(), Error> {
+    match emit_type {
+        EmitType::Fasta => write_fasta_record(record, writer, seq, read_id),
+        EmitType::Fastq => write_fastq_record(record, writer, seq, qual, read_id),
+        EmitType::Bam | EmitType::Sam | EmitType::Cram => unreachable!(),
+    }
+}
+
+/// Write a record to an output of any type.
+fn write_record(
+    writer: &mut Wrapper,
+    header: &Header,
+    record: &dyn Record,
+    emit_type: &EmitType,
+    seq: &mut Vec<u8>,
+    qual: &mut Vec<u8>,
+    read_id: &[u8],
+) -> Result<(), Error> {
+    match writer {
+        Wrapper::Bam(bam_writer, None) => bam_writer.write_alignment_record(header, record),
+        Wrapper::Bam(bam_writer, Some(indexer)) => {
+            let start = bam_writer.get_ref().virtual_position();
+            bam_writer.write_alignment_record(header, record)?;
+            let end = bam_writer.get_ref().virtual_position();
+            indexer.add_record(header, record, Chunk::new(start, end))
+        }
+        Wrapper::Fastx(fastx_writer) => {
+            write_fastx_record(record, fastx_writer, emit_type, seq, qual, read_id)
+        }
+        Wrapper::Sam(sam_writer) => sam_writer.write_alignment_record(header, record),
+        Wrapper::Cram(cram_writer) => cram_writer.write_alignment_record(header, record),
+    }
+}
+
+/// Flush an output, and write the end of file marker of a compressed one. A CRAM output
+/// writes its last container here.
+///
+/// # Returns
+///
+/// Returns the indexer of a BAM output which was indexed as it was written, for its index to
+/// be written next to it.
+fn finish_output(writer: Wrapper, header: &Header) -> Result<Option<BamIndexer>, Error> {
+    match writer {
+        Wrapper::Bam(mut bam_writer, indexer) => bam_writer.try_finish().map(|_| indexer),
+        Wrapper::Fastx(fastx_writer) => fastx_writer.finish().map(|_| None),
+        Wrapper::Sam(sam_writer) => sam_writer.into_inner().finish().map(|_| None),
+        Wrapper::Cram(mut cram_writer) => cram_writer.try_finish(header).map(|_| None),
+    }
+}
+
+/// Create the writer for an output file, writing the header of BAM, SAM and CRAM outputs.
+/// CRAM outputs are compressed against the sequences in `repository`, and BAM outputs are
+/// indexed as they are written if `index` is set.
 fn create_output_file(
-    fh: String,
+    out_file: OutputFile,
     emit_type: &EmitType,
-    compression: &CompressionType,
-    header: Option<&Header>,
-) -> Option<Wrapper> {
+    compression: &OutputCompression,
+    header: &Header,
+    repository: &fasta::Repository,
+    index: Option<IndexFormat>,
+) -> Result<Wrapper, Error> {
     match emit_type {
         EmitType::Bam => {
-            let mut bam: bam::io::Writer<bgzf::Writer<File>> =
-                bam::io::Writer::new(File::create(fh).expect("Failed to create file"));
-            bam.write_header(header.unwrap()).unwrap();
-            Some(Wrapper::Bam(bam))
+            let mut bam: bam::io::Writer<bgzf::Writer<OutputFile>> = bam::io::Writer::new(out_file);
+            bam.write_header(header)?;
+            let indexer = index.map(|format| BamIndexer::new(format, header));
+            Ok(Wrapper::Bam(bam, indexer))
         }
-        _ => {
-            let out_file = match File::create(&fh) {
-                Ok(file) => BufWriter::new(file),
-                Err(err) => {
-                    eprintln!("Could not create output file: {}\n{}", &fh, err);
-                    std::process::exit(1)
-                }
-            };
-            match compression {
-                CompressionType::Uncompressed => Some(Wrapper::Fastx(out_file)),
-                CompressionType::Gzipped => {
-                    let gz_out = GzEncoder::new(out_file, Compression::default());
-                    Some(Wrapper::GzFastx(gz_out))
-                }
-                _ => {
-                    unimplemented!()
-                }
-            }
+        EmitType::Sam => {
+            let mut sam = sam::io::Writer::new(compression.writer(out_file)?);
+            sam.write_header(header)?;
+            Ok(Wrapper::Sam(sam))
+        }
+        EmitType::Cram => {
+            let mut cram = cram::io::writer::Builder::default()
+                .set_reference_sequence_repository(repository.clone())
+                .build_with_writer(out_file);
+            cram.write_header(header)?;
+            Ok(Wrapper::Cram(cram))
         }
+        EmitType::Fastq | EmitType::Fasta => Ok(Wrapper::Fastx(compression.writer(out_file)?)),
     }
 }
 
-/// Write a record to a Fasta file
+/// The reference sequences CRAM outputs are compressed against, adding the `M5` checksums
+/// CRAM needs to the `@SQ` lines of the header. Aligned reads can only be written to CRAM with
+/// a reference, while unaligned reads need none.
+fn cram_reference(
+    header: &mut Header,
+    emit_type: &EmitType,
+    reference: &ReferenceArgs,
+) -> Result<fasta::Repository, Error> {
+    match emit_type {
+        EmitType::Cram if !reference.is_set() && !header.reference_sequences().is_empty() => {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the header has reference sequences, set --reference or --reference-cache to write CRAM",
+            ))
+        }
+        EmitType::Cram => {
+            reference.add_md5_checksums(header)?;
+            reference.repository(header)
+        }
+        _ => Ok(fasta::Repository::default()),
+    }
+}
+
+/// A spinner counting the BAM records read, drawn on standard error if an output is written to
+/// standard output.
+pub fn progress_bar(writes_stdout: bool) -> ProgressBar {
+    let target = match writes_stdout {
+        true => ProgressDrawTarget::stderr(),
+        false => ProgressDrawTarget::stdout(),
+    };
+    let bar = ProgressBar::with_draw_target(None, target).with_message("BAM Records");
+    bar.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] {spinner} {pos:>7} {msg}")
+            .unwrap()
+            // For more spinners check out the cli-spinners project:
+            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+            .tick_strings(&["⣾", "⣽", "⣻", "⢿", "⡿", "⣟", "⣯", "⣷"]),
+    );
+    bar
+}
 
 /// Split a BAM file into sequenced and unblocked records.
 ///
@@ -152,14 +262,20 @@ fn create_output_file(
 ///
 /// # Arguments
 ///
-/// * `bam_file` - The path to the input BAM file.
-/// * `unblocked_read_ids` - The path to the file containing unblocked read IDs.
-/// * `prefix` - The output file prefix. If empty, default filenames will be used.
+/// * `bam_file` - The path to the input SAM, BAM or CRAM file, or `-` for standard input.
+/// * `unblock` - Where to read the unblocked read IDs from.
+/// * `classify` - How reads are classified, beyond sequenced and unblocked.
+/// * `bloom_fpr` - Optional false positive rate. If set, reads are checked against a Bloom filter before the exact unblocked set.
+/// * `output` - How the output files are named, and where they are written.
 /// * `split_type` - The type of reads to output: `All`, `SequencedOnly`, or `UnblockedOnly`.
 /// * `qual_thresh` - Optional quality threshold. If set, sequences below this average quality will be filtered out.
 /// * `length_thresh` - Optional minimum read length. If set, reads shorter than this will be filtered out.
-/// * `emit_type` - The type of file to write for split records: `Bam`, `Fastq`, or `Fasta`.
-/// * `compression` - The type of compression to use for FASTX output: `Gzipped`, `Uncompressed`
+/// * `emit_type` - The type of file to write for split records: `Bam`, `Sam`, `Cram`, `Fastq`, or `Fasta`.
+/// * `compression` - The compression to use for FASTX output, gzipped by default, and SAM output, uncompressed by default.
+/// * `reference` - The reference FASTA or cache to read CRAM input and write CRAM output with, needed if the reads are aligned.
+/// * `header_comments` - Add `@CO` lines with checksums of the unblocked read ID lists and the settings used to BAM output.
+/// * `index` - Optional index format. If set, each BAM output of a coordinate-sorted input is indexed as it is written.
+/// * `targets` - The target regions, if any. If set, only reads overlapping them are split, read through the index of the input.
 ///
 /// # Returns
 ///
@@ -207,199 +323,157 @@ fn create_output_file(
 #[allow(clippy::too_many_arguments)]
 pub fn split_bam(
     bam_file: PathBuf,
-    unblocked_read_ids: PathBuf,
-    prefix: String,
+    unblock: UnblockArgs,
+    bloom_fpr: Option<f64>,
+    mut classify: ClassifyArgs,
+    output: OutputArgs,
     split_type: SplitType,
     qual_thresh: Option<usize>,
     length_thresh: Option<usize>,
     emit_type: EmitType,
-    compression: CompressionType,
+    compression: CompressionArgs,
+    reference: ReferenceArgs,
+    header_comments: bool,
+    index: Option<IndexFormat>,
+    targets: TargetArgs,
 ) -> Result<(), Error> {
-    assert!(bam_file.exists());
-    let file = File::open(bam_file)?;
-
-    let worker_count = thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
-    let decoder = bgzf::MultithreadedReader::with_worker_count(worker_count, file);
-
-    let mut bam_reader = bam::io::Reader::from(decoder);
-    let _header = bam_reader.read_header()?;
-    let unblocked_read_ids = read_unblocked_read_ids(unblocked_read_ids).unwrap();
-    let suffix = match compression {
-        CompressionType::Gzipped => ".gz",
-        _ => "",
+    let (mut reader, mut header) = match targets.is_set() {
+        true => AlignmentReader::open_targets(&bam_file, &targets)?,
+        false => AlignmentReader::open(&bam_file, &reference)?,
     };
-    // Choose output file names
-    let (seq_fn, unb_fn) = if prefix.is_empty() {
-        match emit_type {
-            EmitType::Bam => (String::from("sequenced.bam"), String::from("unblocked.bam")),
-            EmitType::Fasta => (
-                String::from("sequenced.fasta"),
-                String::from("unblocked.fasta"),
-            ),
-            EmitType::Fastq => (
-                String::from("sequenced.fastq"),
-                String::from("unblocked.fastq"),
-            ),
-        }
-    } else {
-        match emit_type {
-            EmitType::Bam => (
-                format!("{}.sequenced.bam", prefix),
-                format!("{}.unblocked.bam", prefix),
-            ),
-            EmitType::Fasta => (
-                format!("{}.sequenced.fasta", prefix),
-                format!("{}.unblocked.fasta", prefix),
-            ),
-            EmitType::Fastq => (
-                format!("{}.sequenced.fastq", prefix),
-                format!("{}.unblocked.fastq", prefix),
-            ),
-        }
-    };
-    let (seq_fn, unb_fn) = (
-        format!("{}{}", seq_fn, suffix),
-        format!("{}{}", unb_fn, suffix),
-    );
-    // Create outfile writers> first choose sequenecd, unblocked, both then emit type -> Fastx or BAM
-    let (mut sequenced_reads_writer, mut unblocked_reads_writer) = match split_type {
-        SplitType::All => {
-            // What are we emitting
-            (
-                create_output_file(seq_fn, &emit_type, &compression, Some(&_header)),
-                create_output_file(unb_fn, &emit_type, &compression, Some(&_header)),
-            )
+    let unblocked_read_ids = unblock.load_reported(bloom_fpr)?;
+    add_program(&mut header, &command_line());
+    if header_comments {
+        let split_by: Vec<String> = classify.split_by.iter().map(value_name).collect();
+        let comments = list_comments(&unblocked_read_ids)?
+            .into_iter()
+            .chain([settings_comment(&[
+                ("split-type", Some(value_name(&split_type))),
+                (
+                    "split-by",
+                    (!split_by.is_empty()).then(|| split_by.join(",")),
+                ),
+                ("qual-thresh", qual_thresh.map(|q| q.to_string())),
+                ("length-thresh", length_thresh.map(|l| l.to_string())),
+                (
+                    "region",
+                    (!targets.region.is_empty()).then(|| {
+                        let regions: Vec<String> =
+                            targets.region.iter().map(ToString::to_string).collect();
+                        regions.join(",")
+                    }),
+                ),
+                (
+                    "bed",
+                    targets.bed.as_ref().map(|bed| bed.display().to_string()),
+                ),
+                (
+                    "time-bins",
+                    classify.time.time_bins.map(|width| width.to_string()),
+                ),
+                (
+                    "time-range",
+                    classify.time.time_range.map(|range| range.to_string()),
+                ),
+                (
+                    "readfish-toml",
+                    classify
+                        .readfish_toml
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+                (
+                    "flowcell",
+                    classify
+                        .readfish_toml
+                        .as_ref()
+                        .map(|_| value_name(&classify.flowcell)),
+                ),
+                ("bloom-fpr", bloom_fpr.map(|fpr| fpr.to_string())),
+            ])]);
+        header.comments_mut().extend(comments.map(Into::into));
+    }
+    if classify.time.run_start.is_none() {
+        classify.time.run_start = read_group_run_start(&header);
+    }
+    if classify.by_time() && classify.time.run_start.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the start of the run is not in the read groups of the BAM header, set --run-start",
+        ));
+    }
+    if let Some(format) = index {
+        if emit_type != EmitType::Bam {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--index can only be used with BAM output",
+            ));
         }
-        SplitType::SequencedOnly => (
-            create_output_file(seq_fn, &emit_type, &compression, Some(&_header)),
-            None,
-        ),
-
-        SplitType::UnblockedOnly => (
-            None,
-            create_output_file(unb_fn, &emit_type, &compression, Some(&_header)),
-        ),
+        format.check(&header)?;
+    }
+    let repository = cram_reference(&mut header, &emit_type, &reference)?;
+    let compression = compression.resolve(match emit_type {
+        EmitType::Sam => CompressionType::Uncompressed,
+        _ => CompressionType::Gzipped,
+    })?;
+    let mut router = Router::new(classify.classifiers(unblocked_read_ids, split_type)?);
+    let compression = compression.share(router.known_outputs());
+    let files = output.files(
+        router.fields(),
+        router.field_labels(),
+        &output_extension(&emit_type, &compression.compression),
+    )?;
+    // One writer per category, named for its labels
+    let create = |name: &str| {
+        create_output_file(
+            files.create(name)?,
+            &emit_type,
+            &compression,
+            &header,
+            &repository,
+            index,
+        )
     };
-    let mut record = noodles::bam::Record::default();
-    let write_unblock = split_type != SplitType::SequencedOnly;
+    router.create_known(create)?;
+    let read_group_barcodes = ReadGroupBarcodes::new(&header);
     let mut seq: Vec<u8> = vec![];
     let mut qual: Vec<u8> = vec![];
 
-    // Setup progress bar
-    let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stdout())
-        .with_message("BAM Records");
-    bar.set_style(
-        ProgressStyle::with_template("[{elapsed_precise}] {spinner} {pos:>7} {msg}")
-            .unwrap()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["⣾", "⣽", "⣻", "⢿", "⡿", "⣟", "⣯", "⣷"]),
-    );
+    let bar = progress_bar(files.writes_stdout());
 
-    while bam_reader.read_record(&mut record).unwrap() != 0 {
+    reader.for_each_record(&header, |record| {
         let readid = record.name().expect("missing read id on BAM record");
-        if filter(&record, qual_thresh, length_thresh) {
+        if filter(record, qual_thresh, length_thresh)? {
             let read_id = readid.as_bytes();
-            let was_unblocked = if let Some(Ok(_tag)) = record.data().get(b"dx") {
-                read_id
-                    .split(|b| b == &b';')
-                    .any(|b| unblocked_read_ids.contains(&String::from_utf8(b.to_vec()).unwrap()))
-            } else {
-                unblocked_read_ids.contains(&String::from_utf8(read_id.to_vec()).unwrap())
+            let data = record.data();
+            let read = ReadInfo {
+                read_id,
+                duplex: matches!(data.get(&DUPLEX), Some(Ok(_))),
+                barcode: read_group_barcodes.barcode(&*data),
+                channel: bam_channel(&*data),
+                start_time: bam_start_time(&*data),
             };
-            if write_unblock && was_unblocked {
-                match unblocked_reads_writer.as_mut().unwrap() {
-                    Wrapper::Bam(unblocked_bam_writer) => {
-                        unblocked_bam_writer
-                            .write_record(&_header, &record)
-                            .unwrap();
-                    }
-                    Wrapper::Fastx(unblocked_fastx_writer) => match emit_type {
-                        EmitType::Fasta => {
-                            write_fasta_record(&record, unblocked_fastx_writer, &mut seq, read_id)?;
-                        }
-                        EmitType::Fastq => {
-                            write_fastq_record(
-                                &record,
-                                unblocked_fastx_writer,
-                                &mut seq,
-                                &mut qual,
-                                read_id,
-                            )?;
-                        }
-                        _ => unreachable!(),
-                    },
-                    Wrapper::GzFastx(unblocked_fastx_writer_gz) => match emit_type {
-                        EmitType::Fasta => {
-                            write_fasta_record(
-                                &record,
-                                unblocked_fastx_writer_gz,
-                                &mut seq,
-                                read_id,
-                            )?;
-                        }
-                        EmitType::Fastq => {
-                            write_fastq_record(
-                                &record,
-                                unblocked_fastx_writer_gz,
-                                &mut seq,
-                                &mut qual,
-                                read_id,
-                            )?;
-                        }
-                        _ => unreachable!(),
-                    },
-                }
-            } else if sequenced_reads_writer.is_some() && !was_unblocked {
-                let sequence_writer = sequenced_reads_writer.as_mut().unwrap();
-                match sequence_writer {
-                    Wrapper::Bam(sequenced_bam_writer) => {
-                        sequenced_bam_writer
-                            .write_record(&_header, &record)
-                            .unwrap();
-                    }
-                    Wrapper::Fastx(sequenced_fastx_writer) => match emit_type {
-                        EmitType::Fasta => {
-                            write_fasta_record(&record, sequenced_fastx_writer, &mut seq, read_id)?;
-                        }
-                        EmitType::Fastq => {
-                            write_fastq_record(
-                                &record,
-                                sequenced_fastx_writer,
-                                &mut seq,
-                                &mut qual,
-                                read_id,
-                            )?;
-                        }
-                        _ => unreachable!(),
-                    },
-                    Wrapper::GzFastx(sequenced_fastx_writer_gz) => match emit_type {
-                        EmitType::Fasta => {
-                            write_fasta_record(
-                                &record,
-                                sequenced_fastx_writer_gz,
-                                &mut seq,
-                                read_id,
-                            )?;
-                        }
-                        EmitType::Fastq => {
-                            write_fastq_record(
-                                &record,
-                                sequenced_fastx_writer_gz,
-                                &mut seq,
-                                &mut qual,
-                                read_id,
-                            )?;
-                        }
-                        _ => unreachable!(),
-                    },
-                }
+            if let Some(writer) = router.route(&read, create)? {
+                write_record(
+                    writer, &header, record, &emit_type, &mut seq, &mut qual, read_id,
+                )?;
             }
         }
 
-        bar.inc(1)
+        bar.inc(1);
+        Ok(())
+    })?;
+    files.report(&router);
+    for output in router.into_outputs() {
+        if let Some(indexer) = finish_output(output.writer, &header)? {
+            let suffix = indexer.format().suffix();
+            indexer.write(&header, files.create_index(&output.name, suffix)?)?;
+        }
     }
-    Ok(())
+    if let Some(name) = files.unwritten_stdout()? {
+        finish_output(create(name)?, &header)?;
+    }
+    files.commit()
 }
 
 /// Filters a BAM record based on mapping quality and flags.
@@ -454,9 +528,14 @@ pub fn split_bam(
 /// // Filter the record with a quality threshold of 35
 /// assert_eq!(filter(&record, Some(35)), false);
 /// ````
-fn filter(record: &bam::Record, qual: Option<usize>, length_thresh: Option<usize>) -> bool {
+fn filter(
+    record: &dyn Record,
+    qual: Option<usize>,
+    length_thresh: Option<usize>,
+) -> Result<bool, Error> {
     let x = if let Some(qual_thresh) = qual {
-        let q = _ave_qual(record.quality_scores().as_ref());
+        let quals: Vec<u8> = record.quality_scores().iter().collect();
+        let q = _ave_qual(&quals);
         q > qual_thresh as f64
     } else {
         true
@@ -468,20 +547,213 @@ fn filter(record: &bam::Record, qual: Option<usize>, length_thresh: Option<usize
     } else {
         true
     };
-    let flags = record.flags();
+    let flags = record.flags()?;
 
-    long_enough && x && !flags.is_supplementary() && !flags.is_secondary()
+    Ok(long_enough && x && !flags.is_supplementary() && !flags.is_secondary())
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use noodles::bam::{self, record::QualityScores, Record};
-//     use noodles::sam::alignment::record::Flags;
-//     use noodles::sam::alignment::RecordBuf;
-//     use std::any::Any;
-//     use std::borrow::BorrowMut;
-//     use std::path::PathBuf;
-//     #[test]
-//     fn test_filter_passes_no_quality_threshold() {}
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use std::{fs, fs::File, io::Read};
+
+    #[test]
+    fn test_bgzf_fastx_output() {
+        let mut reader = bam::io::Reader::new(File::open("tests/test_reads.bam").unwrap());
+        let header = reader.read_header().unwrap();
+        let path = TempPath::new("bgzf.fastq.gz");
+        let compression = CompressionArgs {
+            compression: Some(CompressionType::Bgzipped),
+            ..Default::default()
+        }
+        .resolve(CompressionType::Gzipped)
+        .unwrap();
+        let mut writer = create_output_file(
+            File::create(&path).unwrap().into(),
+            &EmitType::Fastq,
+            &compression,
+            &header,
+            &fasta::Repository::default(),
+            None,
+        )
+        .unwrap();
+        let (mut seq, mut qual) = (Vec::new(), Vec::new());
+        let mut n_records = 0;
+        for record in reader.records() {
+            let record = record.unwrap();
+            let read_id = record.name().unwrap().as_bytes().to_vec();
+            write_record(
+                &mut writer,
+                &header,
+                &record,
+                &EmitType::Fastq,
+                &mut seq,
+                &mut qual,
+                &read_id,
+            )
+            .unwrap();
+            n_records += 1;
+        }
+        finish_output(writer, &header).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        // A BGZF file ends with an empty block
+        assert!(bytes.ends_with(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ]));
+        let mut fastq = String::new();
+        bgzf::Reader::new(bytes.as_slice())
+            .read_to_string(&mut fastq)
+            .unwrap();
+        assert_eq!(fastq.lines().count(), 4 * n_records);
+        assert!(fastq.starts_with("@0afabd54-d901-473f-94ba-c7a6e69716f5\n"));
+    }
+
+    #[test]
+    fn test_sam_cram_output() {
+        let mut reader = bam::io::Reader::new(File::open("tests/test_reads.bam").unwrap());
+        let mut header = reader.read_header().unwrap();
+        let records: Vec<bam::Record> = reader.records().map(Result::unwrap).collect();
+        let names: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| record.name().unwrap().as_bytes().to_vec())
+            .collect();
+        // The reads are unaligned, so CRAM needs no reference
+        let repository =
+            cram_reference(&mut header, &EmitType::Cram, &ReferenceArgs::default()).unwrap();
+        for emit_type in [EmitType::Sam, EmitType::Cram] {
+            let path = TempPath::new(&format!("{:?}_output", emit_type));
+            let compression = CompressionArgs::default()
+                .resolve(CompressionType::Uncompressed)
+                .unwrap();
+            let mut writer = create_output_file(
+                File::create(&path).unwrap().into(),
+                &emit_type,
+                &compression,
+                &header,
+                &repository,
+                None,
+            )
+            .unwrap();
+            let (mut seq, mut qual) = (Vec::new(), Vec::new());
+            for (record, name) in records.iter().zip(&names) {
+                write_record(
+                    &mut writer,
+                    &header,
+                    record,
+                    &emit_type,
+                    &mut seq,
+                    &mut qual,
+                    name,
+                )
+                .unwrap();
+            }
+            finish_output(writer, &header).unwrap();
+
+            let written: Vec<Vec<u8>> = match emit_type {
+                EmitType::Sam => {
+                    let mut reader = sam::io::reader::Builder::default()
+                        .build_from_path(&path)
+                        .unwrap();
+                    let header = reader.read_header().unwrap();
+                    reader
+                        .record_bufs(&header)
+                        .map(|record| record.unwrap().name().unwrap().as_ref().to_vec())
+                        .collect()
+                }
+                _ => {
+                    let mut reader = cram::io::reader::Builder::default()
+                        .build_from_path(&path)
+                        .unwrap();
+                    let header = reader.read_header().unwrap();
+                    reader
+                        .records(&header)
+                        .map(|record| record.unwrap().name().unwrap().as_ref().to_vec())
+                        .collect()
+                }
+            };
+            assert_eq!(written, names, "{:?}", emit_type);
+        }
+    }
+
+    #[test]
+    fn test_cram_reference() {
+        use noodles::{
+            core::Position,
+            sam::{
+                alignment::{
+                    record::cigar::{op::Kind, Op},
+                    record_buf::{Name, QualityScores, Sequence},
+                    RecordBuf,
+                },
+                header::record::value::{map::ReferenceSequence, Map},
+            },
+        };
+        let mut header = Header::builder()
+            .add_reference_sequence(
+                "sq0",
+                Map::<ReferenceSequence>::new(std::num::NonZeroUsize::new(8).unwrap()),
+            )
+            .build();
+        let no_reference = ReferenceArgs::default();
+        assert!(cram_reference(&mut header.clone(), &EmitType::Cram, &no_reference).is_err());
+        assert!(cram_reference(&mut header.clone(), &EmitType::Bam, &no_reference).is_ok());
+
+        let fasta = TempPath::new("cram_ref.fa");
+        fs::write(&fasta, ">sq0\nACGTACGT\n").unwrap();
+        let reference = ReferenceArgs {
+            reference: Some(fasta.to_path_buf()),
+            reference_cache: None,
+        };
+        let repository = cram_reference(&mut header, &EmitType::Cram, &reference).unwrap();
+
+        // An aligned read round trips through CRAM against the reference
+        let record = RecordBuf::builder()
+            .set_name(Name::from(b"read0".as_slice()))
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(3).unwrap())
+            .set_cigar([Op::new(Kind::Match, 4)].into_iter().collect())
+            .set_sequence(Sequence::from(b"GTAG".to_vec()))
+            .set_quality_scores(QualityScores::from(vec![20; 4]))
+            .build();
+        let path = TempPath::new("aligned.cram");
+        let compression = CompressionArgs::default()
+            .resolve(CompressionType::Uncompressed)
+            .unwrap();
+        let mut writer = create_output_file(
+            File::create(&path).unwrap().into(),
+            &EmitType::Cram,
+            &compression,
+            &header,
+            &repository,
+            None,
+        )
+        .unwrap();
+        write_record(
+            &mut writer,
+            &header,
+            &record,
+            &EmitType::Cram,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            b"read0",
+        )
+        .unwrap();
+        finish_output(writer, &header).unwrap();
+
+        let (mut reader, header) = AlignmentReader::open(&path, &reference).unwrap();
+        let mut read = Vec::new();
+        reader
+            .for_each_record(&header, |record| {
+                read.push((
+                    record.alignment_start().transpose()?,
+                    record.sequence().iter().collect::<Vec<u8>>(),
+                ));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, [(Position::new(3), b"GTAG".to_vec())]);
+    }
+}
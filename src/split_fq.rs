@@ -1,9 +1,13 @@
-use crate::_splitting::read_unblocked_read_ids;
-use needletail::parse_fastx_file;
+use crate::_splitting::{is_stdio, open_input, CompressionType, SplitType, UnblockArgs, STDIO};
+use crate::classify::{ClassifyArgs, ReadInfo, Router};
+use crate::compress::CompressedWriter;
+use crate::compress::CompressionArgs;
+use crate::output::{compressed_extension, OutputArgs};
+use crate::read_meta::{header_field, parse_field};
+use needletail::{parse_fastx_file, parse_fastx_reader, parser::Format};
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
     str,
 };
 
@@ -22,65 +26,148 @@ const NEWLINE_SLICE: &[u8] = &[10];
 /// let id = header_to_id(&a);
 /// assert_eq!(id, "Alex");
 /// ```
-fn header_to_id(bytes: &[u8]) -> &str {
+pub fn header_to_id(bytes: &[u8]) -> &str {
     match bytes.iter().position(|&char| char == SPACE) {
         None => str::from_utf8(bytes).unwrap(),
         Some(x) => str::from_utf8(&bytes[..x]).unwrap(),
     }
 }
 
+/// Standard input, decompressed, read only once it is reached among the inputs.
+type Stdin = Option<Box<dyn BufRead + Send>>;
+
+/// The format of the input files, from their first records. FASTA and FASTQ can't be split
+/// together, as they would be written to the same outputs. Standard input can't be read twice,
+/// so its format is taken from its first byte instead.
+fn input_format(input_fastq: &[PathBuf], stdin: &mut Stdin) -> Result<Format, String> {
+    let mut format = None;
+    for path in input_fastq {
+        let first = match stdin.as_mut().filter(|_| is_stdio(path)) {
+            Some(stdin) => match stdin.fill_buf() {
+                Ok([b'>', ..]) => Format::Fasta,
+                Ok([b'@', ..]) => Format::Fastq,
+                Ok([]) => continue,
+                Ok(_) => return Err(String::from("Invalid record in standard input")),
+                Err(err) => return Err(format!("Could not read standard input\n{}", err)),
+            },
+            None => {
+                let mut reader = parse_fastx_file(path)
+                    .map_err(|_| format!("Could not read FASTA/Q file: {:#?}", path))?;
+                match reader.next() {
+                    Some(Ok(record)) => record.format(),
+                    Some(Err(err)) => {
+                        return Err(format!("Invalid record in file {:#?}\n{}", path, err))
+                    }
+                    None => continue,
+                }
+            }
+        };
+        match format {
+            Some(format) if format != first => {
+                return Err(String::from(
+                    "FASTA and FASTQ files can't be split together",
+                ))
+            }
+            _ => format = Some(first),
+        }
+    }
+    Ok(format.unwrap_or(Format::Fastq))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn split(
-    unblocked_read_ids: PathBuf,
-    prefix: String,
-    input_fastq: Vec<PathBuf>,
+    mut unblock: UnblockArgs,
+    unblocked_read_ids_file: Option<PathBuf>,
+    bloom_fpr: Option<f64>,
+    classify: ClassifyArgs,
+    output: OutputArgs,
+    compression: CompressionArgs,
+    mut input_fastq: Vec<PathBuf>,
     write_unblocked: bool,
 ) {
-    // Read our unblocked read ids into a hash set
-    let rejected_reads = match read_unblocked_read_ids(unblocked_read_ids) {
+    if let Some(path) = unblock.take_positional(unblocked_read_ids_file) {
+        input_fastq.insert(0, path);
+    }
+    // Read our unblocked read ids into a compact set
+    let rejected_reads = match unblock.load_reported(bloom_fpr) {
         Ok(hs) => hs,
         Err(e) => {
             eprintln!("Error: could not read unblocked_read_ids\n{}", e);
             std::process::exit(1)
         }
     };
-    let seq_fn: String;
-    let unb_fn: String;
-
-    // Parse if we have a prefix
-    if prefix.is_empty() {
-        seq_fn = String::from("sequenced.fastq");
-        unb_fn = String::from("unblocked.fastq");
+    let split_type = if write_unblocked {
+        SplitType::All
     } else {
-        seq_fn = format!("{}.sequenced.fastq", prefix);
-        unb_fn = format!("{}.unblocked.fastq", prefix);
+        SplitType::SequencedOnly
+    };
+    if classify.by_time() && classify.time.run_start.is_none() {
+        eprintln!("Error: FASTQ start times are timestamps, set --run-start to measure them from");
+        std::process::exit(1)
     }
-
-    let mut _sequenced_reads = match File::create(&seq_fn) {
-        Ok(file) => file,
+    let mut router = match classify.classifiers(rejected_reads, split_type) {
+        Ok(classifiers) => Router::new(classifiers),
         Err(err) => {
-            eprintln!("Could not create output file: {}\n{}", &seq_fn, err);
+            eprintln!("Error: {}", err);
             std::process::exit(1)
         }
     };
-    let mut sequenced_reads: BufWriter<File> = BufWriter::new(_sequenced_reads);
-    let mut unblocked_reads: Option<BufWriter<File>>;
-    if write_unblocked {
-        unblocked_reads = match File::create(&unb_fn) {
-            Ok(file) => Some(BufWriter::new(file)),
+    let mut stdin: Stdin = None;
+    match input_fastq.iter().filter(|path| is_stdio(path)).count() {
+        0 => {}
+        1 => match open_input(Path::new(STDIO)) {
+            Ok(reader) => stdin = Some(reader),
             Err(err) => {
-                eprintln!("Could not create output file: {}\n{}", &unb_fn, err);
+                eprintln!("Could not read standard input\n{}", err);
                 std::process::exit(1)
             }
-        };
-    } else {
-        unblocked_reads = None
+        },
+        _ => {
+            eprintln!("Error: standard input, -, can only be read once");
+            std::process::exit(1)
+        }
+    }
+    let extension = match input_format(&input_fastq, &mut stdin) {
+        Ok(Format::Fasta) => "fasta",
+        Ok(Format::Fastq) => "fastq",
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+    };
+    let compression = match compression.resolve(CompressionType::Uncompressed) {
+        Ok(compression) => compression.share(router.known_outputs()),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
+        }
+    };
+    let extension = compressed_extension(extension, &compression.compression);
+    let files = match output.files(router.fields(), router.field_labels(), &extension) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
+        }
+    };
+    // One writer per category, named for its labels
+    let create = |name: &str| compression.writer(files.create(name)?);
+    if let Err(err) = router.create_known(create) {
+        files.discard();
+        eprintln!("{}", err);
+        std::process::exit(1)
     }
 
     // Process each FASTQ file
     for path in input_fastq {
-        let mut reader = match parse_fastx_file(&path) {
+        let reader = match is_stdio(&path) {
+            true => parse_fastx_reader(stdin.take().expect("standard input is only read once")),
+            false => parse_fastx_file(&path),
+        };
+        let mut reader = match reader {
             Ok(reader) => reader,
             Err(_) => {
+                files.discard();
                 eprintln!("Could not read FASTA/Q file: {:#?}", path);
                 std::process::exit(1)
             }
@@ -89,21 +176,64 @@ pub fn split(
             let record = match record.as_ref() {
                 Ok(rec) => rec,
                 Err(err) => {
+                    files.discard();
                     eprintln!("Invalid record in file {:#?}\n{}", path, err);
                     std::process::exit(1)
                 }
             };
             let id = header_to_id(record.id());
-            if rejected_reads.contains(id) {
-                // ID in HashSet, unblock was sent
-                if let Some(ref mut file) = unblocked_reads {
-                    file.write_all(record.all()).expect("???");
-                    file.write_all(NEWLINE_SLICE).expect("askjd");
+            let read = ReadInfo {
+                barcode: header_field(record.id(), b"barcode"),
+                channel: header_field(record.id(), b"ch").and_then(parse_field),
+                start_time: header_field(record.id(), b"start_time"),
+                ..ReadInfo::new(id.as_bytes())
+            };
+            let file = match router.route(&read, create) {
+                Ok(file) => file,
+                Err(err) => {
+                    files.discard();
+                    eprintln!("{}", err);
+                    std::process::exit(1)
+                }
+            };
+            if let Some(file) = file {
+                let written = file
+                    .write_all(record.all())
+                    .and_then(|()| file.write_all(NEWLINE_SLICE));
+                if let Err(err) = written {
+                    files.discard();
+                    eprintln!("Could not write output file\n{}", err);
+                    std::process::exit(1)
                 }
-            } else {
-                sequenced_reads.write_all(record.all()).expect("???");
-                sequenced_reads.write_all(NEWLINE_SLICE).expect("askjd");
             }
         }
     }
+    files.report(&router);
+    for output in router.into_outputs() {
+        if let Err(err) = output.writer.finish() {
+            files.discard();
+            eprintln!("Could not write output file\n{}", err);
+            std::process::exit(1)
+        }
+    }
+    // Standard output is left empty, rather than unwritten, if no read went to it
+    let unwritten = match files.unwritten_stdout() {
+        Ok(name) => name,
+        Err(err) => {
+            files.discard();
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+    };
+    if let Some(name) = unwritten {
+        if let Err(err) = create(name).and_then(CompressedWriter::finish) {
+            files.discard();
+            eprintln!("Could not write output file\n{}", err);
+            std::process::exit(1)
+        }
+    }
+    if let Err(err) = files.commit() {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    }
 }
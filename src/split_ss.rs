@@ -1,86 +1,183 @@
-use fnv::FnvHashSet;
-use std::{
-    fs::File,
-    io,
-    io::{BufRead, BufReader},
-    path::PathBuf,
-};
+use crate::_splitting::{open_input, CompressionType, SplitType, UnblockArgs};
+use crate::classify::{ClassifyArgs, ReadInfo, Router};
+use crate::compress::{CompressedWriter, CompressionArgs};
+use crate::output::{compressed_extension, OutputArgs};
+use crate::read_meta::{parse_field, SummaryColumns};
+use std::{io, path::PathBuf};
 
-/// Read unblocked_read_ids.txt into a HashSet
-fn read_unblocked_read_ids(path: PathBuf) -> Result<FnvHashSet<String>, io::Error> {
-    match File::open(path) {
-        Ok(file) => {
-            let reader = BufReader::new(file);
-            let rejected_reads: FnvHashSet<String> = reader
-                .lines()
-                .map(|l| l.expect("Couldn't read line"))
-                .collect();
-            Ok(rejected_reads)
+pub fn split(
+    mut unblock: UnblockArgs,
+    unblocked_read_ids_file: Option<PathBuf>,
+    classify: ClassifyArgs,
+    output: OutputArgs,
+    compression: CompressionArgs,
+    sequencing_summary: Option<PathBuf>,
+) {
+    // The positional read ids file is the sequencing summary if -u or --unblock-source is given
+    let sequencing_summary = match (
+        unblock.take_positional(unblocked_read_ids_file),
+        sequencing_summary,
+    ) {
+        (Some(path), None) | (None, Some(path)) => path,
+        (Some(_), Some(_)) => {
+            eprintln!("Error: give the unblocked read ids either positionally or with --unblocked-read-ids or --unblock-source, not both");
+            std::process::exit(1)
         }
-        Err(err) => Err(err),
-    }
-}
-
-fn get_key_col(first_line: &csv::ByteRecord) -> Result<usize, String> {
-    for (i, field) in first_line.iter().enumerate() {
-        if field == b"read_id" {
-            return Ok(i);
+        (None, None) => {
+            eprintln!("Error: the sequencing summary to split must be given");
+            std::process::exit(1)
         }
-    }
-    Err("Could not find field".to_owned())
-}
-
-pub fn split(unblocked_read_ids: PathBuf, prefix: String, sequencing_summary: PathBuf) {
-    let rejected_reads = match read_unblocked_read_ids(unblocked_read_ids) {
+    };
+    let rejected_reads = match unblock.load_reported(None) {
         Ok(hs) => hs,
         Err(e) => {
             eprintln!("Error: could not read unblocked_read_ids\n{}", e);
             std::process::exit(1)
         }
     };
-    let seq_fn: String;
-    let unb_fn: String;
-
-    // Parse if we have a prefix
-    if prefix.is_empty() {
-        seq_fn = String::from("sequenced.txt");
-        unb_fn = String::from("unblocked.txt");
-    } else {
-        seq_fn = format!("{}.sequenced.txt", prefix);
-        unb_fn = format!("{}.unblocked.txt", prefix);
-    }
 
-    let mut rdr = match File::open(sequencing_summary) {
-        Ok(file) => csv::ReaderBuilder::new().delimiter(b'\t').from_reader(file),
-        _ => {
-            eprintln!("Error CSV reader!");
+    let mut rdr = match open_input(&sequencing_summary) {
+        Ok(reader) => csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(reader),
+        Err(err) => {
+            eprintln!(
+                "Error: could not open sequencing summary {}\n{}",
+                sequencing_summary.display(),
+                err
+            );
             std::process::exit(1);
         }
     };
-    let key_col = get_key_col(rdr.byte_headers().expect("A")).expect("asdbf");
-    let headers = &rdr.byte_headers().expect("a").clone();
-    let mut wtr_s = csv::WriterBuilder::new()
-        .delimiter(b'\t')
-        .from_path(seq_fn)
-        .expect("seq wtr");
-    let mut wtr_r = csv::WriterBuilder::new()
-        .delimiter(b'\t')
-        .from_path(unb_fn)
-        .expect("unb wtr");
-    wtr_r.write_byte_record(headers).expect("1");
-    wtr_s.write_byte_record(headers).expect("2");
+    let headers = match rdr.byte_headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => {
+            eprintln!(
+                "Error: could not read the sequencing summary header line\n{}",
+                err
+            );
+            std::process::exit(1)
+        }
+    };
+    let columns = match SummaryColumns::new(&headers) {
+        Ok(columns) => columns,
+        Err(err) => {
+            eprintln!("Error: {} in the sequencing summary header line", err);
+            std::process::exit(1)
+        }
+    };
+    if classify.by_barcode() && columns.barcode.is_none() {
+        eprintln!("Error: --split-by barcode needs the barcode_arrangement column");
+        std::process::exit(1)
+    }
+    if classify.by_condition() && columns.channel.is_none() {
+        eprintln!("Error: --split-by condition needs the channel column");
+        std::process::exit(1)
+    }
+    if classify.by_time() && columns.start_time.is_none() {
+        eprintln!("Error: --time-bins and --time-range need the start_time column");
+        std::process::exit(1)
+    }
+    let mut router = match classify.classifiers(rejected_reads, SplitType::All) {
+        Ok(classifiers) => Router::new(classifiers),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
+        }
+    };
+    let compression = match compression.resolve(CompressionType::Uncompressed) {
+        Ok(compression) => compression.share(router.known_outputs()),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
+        }
+    };
+    let extension = compressed_extension("txt", &compression.compression);
+    let files = match output.files(router.fields(), router.field_labels(), &extension) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
+        }
+    };
+    // One writer per category, named for its labels, starting with the header line
+    let create = |name: &str| -> Result<csv::Writer<CompressedWriter>, io::Error> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(compression.writer(files.create(name)?)?);
+        wtr.write_byte_record(&headers)?;
+        Ok(wtr)
+    };
+    if let Err(err) = router.create_known(create) {
+        files.discard();
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    }
     let mut row = csv::ByteRecord::new();
-    let mut s = 0;
-    let mut r = 0;
-    while rdr.read_byte_record(&mut row).expect("x") {
+    loop {
+        match rdr.read_byte_record(&mut row) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                files.discard();
+                eprintln!("Error: could not read sequencing summary row\n{}", err);
+                std::process::exit(1)
+            }
+        }
         // Decide what file to put this in.
-        if rejected_reads.contains(std::str::from_utf8(&row[key_col]).unwrap()) {
-            r += 1;
-            wtr_r.write_byte_record(&row).expect("3");
-        } else {
-            s += 1;
-            wtr_s.write_byte_record(&row).expect("4");
+        let read = ReadInfo {
+            barcode: columns.barcode.and_then(|col| row.get(col)),
+            channel: columns
+                .channel
+                .and_then(|col| row.get(col))
+                .and_then(parse_field),
+            start_time: columns.start_time.and_then(|col| row.get(col)),
+            ..ReadInfo::new(&row[columns.read_id])
+        };
+        let written = match router.route(&read, create) {
+            Ok(Some(wtr)) => wtr.write_byte_record(&row).map_err(io::Error::from),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            files.discard();
+            eprintln!("Error: could not write output file\n{}", err);
+            std::process::exit(1)
+        }
+    }
+    let (sequenced, unblocked) = router.category_totals();
+    files.report(format!(
+        "Sequenced: {:?}\nUnblocked: {:?}",
+        sequenced, unblocked
+    ));
+    eprintln!("{}", router);
+    let finish = |wtr: csv::Writer<CompressedWriter>| {
+        wtr.into_inner()
+            .map_err(|err| err.into_error())
+            .and_then(CompressedWriter::finish)
+    };
+    // Standard output gets just the header line, rather than nothing, if no read went to it
+    let unwritten = match files.unwritten_stdout() {
+        Ok(name) => name.map(create),
+        Err(err) => {
+            files.discard();
+            eprintln!("Error: {}", err);
+            std::process::exit(1)
         }
+    };
+    let writers = router
+        .into_outputs()
+        .into_iter()
+        .map(|output| Ok(output.writer));
+    for wtr in writers.chain(unwritten) {
+        if let Err(err) = wtr.and_then(finish) {
+            files.discard();
+            eprintln!("Error: could not write output file\n{}", err);
+            std::process::exit(1)
+        }
+    }
+    if let Err(err) = files.commit() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
     }
-    println!("Sequenced: {:?}\nUnblocked: {:?}", s, r);
 }
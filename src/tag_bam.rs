@@ -0,0 +1,299 @@
+//! Module for tagging the records of a BAM file with whether they were sequenced or unblocked.
+//!
+//! Rather than splitting a BAM into one file per category, `tag-bam` writes a single BAM in
+//! which every primary record has an aux tag holding its category, `rf:Z:sequenced` or
+//! `rf:Z:unblocked` by default, and a second tag holding its readfish condition when that is
+//! known, `rc:Z:adaptive` for example. Downstream tools can then filter on the tags, e.g.
+//! `samtools view -d rf:unblocked`.
+//!
+//! The condition of a read is the one readfish assigned it in the chunk log, when the unblock
+//! source is a chunk log and readfish evaluated the read, or else the condition its channel is
+//! in from a readfish TOML.
+//!
+//! Secondary and supplementary records are written unchanged, and every record is written, so
+//! the output has the same records in the same order as the input.
+//!
+//! # Functions
+//!
+//! - [`parse_tag`](fn.parse_tag.html): Parse a two character SAM aux tag from the command line.
+//! - [`tag_bam`](fn.tag_bam.html): Tag the records of a BAM file with their category and condition.
+//!
+use crate::_splitting::{value_name, SplitType, UnblockArgs};
+use crate::alignment::open_bam;
+use crate::classify::{ReadClassifier, ReadInfo, UnblockClassifier};
+use crate::conditions::{ChannelConditions, Flowcell};
+use crate::output::OutputFiles;
+use crate::provenance::{add_program, command_line, list_comments, settings_comment};
+use crate::read_meta::bam_channel;
+use crate::split_bam::progress_bar;
+use fnv::FnvHashMap;
+use noodles::{
+    bam,
+    sam::alignment::{
+        io::Write as _,
+        record::data::field::Tag,
+        record_buf::{data::field::Value, RecordBuf},
+    },
+};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+/// Parse a SAM aux tag, two characters of which the first is a letter and the second a letter
+/// or digit.
+pub fn parse_tag(s: &str) -> Result<Tag, String> {
+    match s.as_bytes() {
+        [first, second] if first.is_ascii_alphabetic() && second.is_ascii_alphanumeric() => {
+            Ok(Tag::from([*first, *second]))
+        }
+        _ => Err(format!(
+            "expected a tag of a letter then a letter or digit, e.g. rf, got {}",
+            s
+        )),
+    }
+}
+
+/// Tag the records of a BAM file with whether they were sequenced or unblocked, and with the
+/// readfish condition of the read when it is known, writing a single BAM.
+///
+/// # Arguments
+///
+/// * `bam_file` - The path to the input BAM file.
+/// * `unblock` - Where to read the unblocked read IDs from.
+/// * `bloom_fpr` - Optional false positive rate. If set, reads are checked against a Bloom filter before the exact unblocked set.
+/// * `readfish_toml` - Optional readfish TOML, giving the condition of reads by their channel.
+/// * `flowcell` - The flowcell the run used, whose channels readfish splits between the conditions.
+/// * `output` - The path of the tagged BAM.
+/// * `force` - Overwrite `output` if it already exists.
+/// * `tag` - The tag holding the category, `sequenced` or `unblocked`.
+/// * `condition_tag` - The tag holding the condition.
+/// * `header_comments` - Add `@CO` lines with checksums of the unblocked read ID lists and the settings used.
+///
+/// # Returns
+///
+/// Returns `Result<(), Error>` where `Error` is an IO error if any occurs during file operations.
+#[allow(clippy::too_many_arguments)]
+pub fn tag_bam(
+    bam_file: PathBuf,
+    unblock: UnblockArgs,
+    bloom_fpr: Option<f64>,
+    readfish_toml: Option<PathBuf>,
+    flowcell: Flowcell,
+    output: PathBuf,
+    force: bool,
+    tag: Tag,
+    condition_tag: Tag,
+    header_comments: bool,
+) -> Result<(), Error> {
+    if tag == condition_tag {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--tag and --condition-tag must be different tags",
+        ));
+    }
+    let mut bam_reader = open_bam(&bam_file)?;
+    let mut header = bam_reader.read_header()?;
+    let unblocked_read_ids = unblock.load_reported(bloom_fpr)?;
+    add_program(&mut header, &command_line());
+    if header_comments {
+        let tag_name = |tag: Tag| String::from_utf8_lossy(tag.as_ref()).into_owned();
+        let comments = list_comments(&unblocked_read_ids)?
+            .into_iter()
+            .chain([settings_comment(&[
+                ("tag", Some(tag_name(tag))),
+                ("condition-tag", Some(tag_name(condition_tag))),
+                (
+                    "readfish-toml",
+                    readfish_toml
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+                (
+                    "flowcell",
+                    readfish_toml.as_ref().map(|_| value_name(&flowcell)),
+                ),
+                ("bloom-fpr", bloom_fpr.map(|fpr| fpr.to_string())),
+            ])]);
+        header.comments_mut().extend(comments.map(Into::into));
+    }
+    let conditions = readfish_toml
+        .as_deref()
+        .map(|path| ChannelConditions::from_toml(path, flowcell))
+        .transpose()?;
+    let mut classifier = UnblockClassifier::new(unblocked_read_ids, SplitType::All);
+
+    let files = OutputFiles::single(&output, force);
+    let mut writer = bam::io::Writer::new(files.create("")?);
+    writer.write_header(&header)?;
+
+    let mut tally = TagTally::default();
+    let mut record = bam::Record::default();
+    let bar = progress_bar(false);
+    while bam_reader.read_record(&mut record)? != 0 {
+        let flags = record.flags();
+        if flags.is_secondary() || flags.is_supplementary() {
+            writer.write_record(&header, &record)?;
+            tally.untagged += 1;
+            bar.inc(1);
+            continue;
+        }
+        let readid = record.name().expect("missing read id on BAM record");
+        let read_id = readid.as_bytes();
+        let data = record.data();
+        let read = ReadInfo {
+            duplex: matches!(data.get(b"dx"), Some(Ok(_))),
+            channel: bam_channel(&data),
+            ..ReadInfo::new(read_id)
+        };
+        let category = classifier
+            .classify(&read)
+            .expect("every read is classified when writing all categories")
+            .to_owned();
+        let condition = condition(&classifier, conditions.as_ref(), &read);
+
+        let mut tagged = RecordBuf::try_from_alignment_record(&header, &record)?;
+        let tagged_data = tagged.data_mut();
+        if let Some(condition) = &condition {
+            tagged_data.insert(condition_tag, Value::from(condition.clone()));
+        }
+        tagged_data.insert(tag, Value::from(category.clone()));
+        writer.write_alignment_record(&header, &tagged)?;
+        tally.add(category, condition);
+        bar.inc(1);
+    }
+    writer.try_finish()?;
+    drop(writer);
+    println!("{}", tally);
+    if let Some(report) = classifier.report() {
+        println!("{}", report);
+    }
+    files.commit()
+}
+
+/// The condition of a read, from the readfish chunk log if readfish evaluated it, or else from
+/// the channel layout of the readfish TOML.
+fn condition(
+    classifier: &UnblockClassifier,
+    conditions: Option<&ChannelConditions>,
+    read: &ReadInfo<'_>,
+) -> Option<String> {
+    if let Some(decisions) = classifier.decisions() {
+        if let Some(read_decision) = read.lookup_ids().find_map(|id| decisions.get(id)) {
+            return Some(decisions.condition(read_decision).to_owned());
+        }
+    }
+    let conditions = conditions?;
+    let idx = conditions.condition(read.channel?)?;
+    Some(conditions.names()[idx].clone())
+}
+
+/// Counts of tagged records by category and condition.
+#[derive(Debug, Default)]
+struct TagTally {
+    /// Tagged records by category, then condition, `None` if it wasn't known.
+    tagged: FnvHashMap<(String, Option<String>), usize>,
+    /// Secondary and supplementary records, written without tags.
+    untagged: usize,
+}
+
+impl TagTally {
+    /// Count a tagged record.
+    fn add(&mut self, category: String, condition: Option<String>) {
+        *self.tagged.entry((category, condition)).or_default() += 1;
+    }
+}
+
+impl fmt::Display for TagTally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut counts: Vec<_> = self.tagged.iter().collect();
+        counts.sort();
+        writeln!(f, "Tagged records:")?;
+        for ((category, condition), count) in counts {
+            match condition {
+                Some(condition) => writeln!(f, "  {} ({}): {}", category, condition, count)?,
+                None => writeln!(f, "  {}: {}", category, count)?,
+            }
+        }
+        write!(
+            f,
+            "Secondary and supplementary records, not tagged: {}",
+            self.untagged
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_splitting::{LabelledSource, UnblockSource};
+    use crate::test_util::TempPath;
+    use noodles::sam::alignment::record::data::field::Value as FieldValue;
+    use std::fs::File;
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(parse_tag("rf"), Ok(Tag::from(*b"rf")));
+        assert_eq!(parse_tag("X1"), Ok(Tag::from(*b"X1")));
+        assert!(parse_tag("1x").is_err());
+        assert!(parse_tag("rfx").is_err());
+        assert!(parse_tag("r").is_err());
+    }
+
+    #[test]
+    fn test_tag_bam() {
+        let unblock = UnblockArgs {
+            unblocked_read_ids: Vec::new(),
+            unblock_source: vec![LabelledSource {
+                label: String::from("decisions"),
+                source: UnblockSource::Decisions(PathBuf::from("tests/test_readfish_chunks.tsv")),
+            }],
+            unblock_end_reason: Vec::new(),
+            lenient: false,
+        };
+        let output = TempPath::new("tag_bam.bam");
+        tag_bam(
+            PathBuf::from("tests/test_reads.bam"),
+            unblock,
+            None,
+            Some(PathBuf::from("tests/test_readfish.toml")),
+            Flowcell::Minion,
+            output.to_path_buf(),
+            false,
+            Tag::from(*b"rf"),
+            Tag::from(*b"rc"),
+            false,
+        )
+        .unwrap();
+
+        let mut reader = bam::io::Reader::new(File::open(&output).unwrap());
+        reader.read_header().unwrap();
+        let string_tag = |data: &bam::record::Data<'_>, tag: &[u8; 2]| match data.get(tag) {
+            Some(Ok(FieldValue::String(value))) => Some(value.to_string()),
+            _ => None,
+        };
+        let tags: Vec<(Option<String>, Option<String>)> = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let data = record.data();
+                (string_tag(&data, b"rf"), string_tag(&data, b"rc"))
+            })
+            .collect();
+        let tag = |category: &str, condition: &str| {
+            (Some(category.to_owned()), Some(condition.to_owned()))
+        };
+        // Conditions from the chunk log for reads readfish evaluated, else from the channel
+        assert_eq!(
+            tags,
+            vec![
+                tag("unblocked", "adaptive"),
+                tag("sequenced", "control"),
+                tag("sequenced", "adaptive"),
+                tag("sequenced", "control"),
+                tag("sequenced", "control"),
+            ]
+        );
+    }
+}
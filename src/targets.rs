@@ -0,0 +1,284 @@
+//! Module for restricting `split-bam` to target regions of the reference.
+//!
+//! Regions are given with `--region`, as `chr`, `chr:start` or `chr:start-end` in 1-based,
+//! inclusive coordinates as samtools takes them, or read from a BED file with `--bed`, whose
+//! 0-based, half open intervals are converted. Rather than reading the whole input, only the
+//! parts of it holding reads in the regions are read, found through its BAI or CSI index.
+//!
+//! Regions are sorted into the order of the reference sequences in the header, and overlapping
+//! or adjacent ones are merged. A read overlapping several regions is then found again only in
+//! the regions straight after the first, where it starts before the region it is found in, so
+//! it is only split once, and the reads are split in the order of the input.
+//!
+//! # Structs
+//!
+//! - [`TargetArgs`](struct.TargetArgs.html): Command line arguments choosing the target regions.
+//! - [`Target`](struct.Target.html): A target region, resolved against the header of the input.
+//!
+//! # Functions
+//!
+//! - [`read_bed`](fn.read_bed.html): Read the regions of a BED file.
+//!
+use crate::_splitting::open_compressed;
+use clap::Args;
+use noodles::{
+    core::{Position, Region},
+    sam::{alignment::Record, Header},
+};
+use std::{
+    io::{self, BufRead, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Command line arguments choosing the target regions reads are split from.
+#[derive(Debug, Default, Args)]
+pub struct TargetArgs {
+    /// Only split reads overlapping this region, e.g. chr1:10000-20000, looked up in the BAI or CSI index of the input BAM. Can be given more than once
+    #[clap(long, value_name = "REGION", multiple_occurrences = true)]
+    pub region: Vec<Region>,
+    /// Only split reads overlapping the regions in this BED file, looked up in the BAI or CSI index of the input BAM
+    #[clap(long, value_name = "BED", parse(from_os_str))]
+    pub bed: Option<PathBuf>,
+}
+
+impl TargetArgs {
+    /// Whether any regions were chosen.
+    pub fn is_set(&self) -> bool {
+        !self.region.is_empty() || self.bed.is_some()
+    }
+
+    /// The chosen regions, sorted into the order of the reference sequences of `header` and
+    /// merged where they overlap or touch.
+    ///
+    /// # Returns
+    ///
+    /// Returns the targets, or an `io::Error` if the BED can't be read, or a region is on a
+    /// reference sequence which isn't in the header or starts past its end.
+    pub fn targets(&self, header: &Header) -> Result<Vec<Target>, io::Error> {
+        let bed = match &self.bed {
+            Some(bed) => read_bed(bed)?,
+            None => Vec::new(),
+        };
+        let mut targets = self
+            .region
+            .iter()
+            .chain(&bed)
+            .map(|region| Target::new(header, region))
+            .collect::<Result<Vec<Target>, io::Error>>()?;
+        targets.sort_by_key(|target| (target.reference_sequence_id, target.start));
+        let mut merged: Vec<Target> = Vec::with_capacity(targets.len());
+        for target in targets {
+            match merged.last_mut() {
+                Some(last)
+                    if last.reference_sequence_id == target.reference_sequence_id
+                        && usize::from(target.start) <= usize::from(last.end) + 1 =>
+                {
+                    last.end = last.end.max(target.end);
+                }
+                _ => merged.push(target),
+            }
+        }
+        Ok(merged)
+    }
+}
+
+/// A target region, resolved against the header of the input, with both of its ends set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// Index of the reference sequence in the header.
+    pub reference_sequence_id: usize,
+    /// First position of the region.
+    pub start: Position,
+    /// Last position of the region, at most the end of the reference sequence.
+    pub end: Position,
+}
+
+impl Target {
+    /// Resolve a region against `header`. A region without an end runs to the end of the
+    /// reference sequence.
+    fn new(header: &Header, region: &Region) -> Result<Self, io::Error> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("region {} {}", region, reason),
+            )
+        };
+        let (reference_sequence_id, _, sq) = header
+            .reference_sequences()
+            .get_full(region.name())
+            .ok_or_else(|| {
+            invalid("is on a reference sequence which isn't in the BAM header")
+        })?;
+        let length = Position::new(sq.length().get()).expect("lengths are non-zero");
+        let interval = region.interval();
+        let start = interval.start().unwrap_or(Position::MIN);
+        let end = interval.end().map_or(length, |end| end.min(length));
+        if start > end {
+            return Err(invalid(
+                "starts after its end, or past the end of its reference",
+            ));
+        }
+        Ok(Target {
+            reference_sequence_id,
+            start,
+            end,
+        })
+    }
+
+    /// The region to query the index with.
+    pub fn region(&self, header: &Header) -> Region {
+        let (name, _) = header
+            .reference_sequences()
+            .get_index(self.reference_sequence_id)
+            .expect("targets are resolved against the header");
+        Region::new(name.to_vec(), self.start..=self.end)
+    }
+
+    /// Whether a record starts at or before this target's end, on its reference sequence, so
+    /// it was found in this target if it overlaps the next one too. A record found in the next
+    /// target which starts at or before this one's end has already been split.
+    pub fn holds_start(&self, header: &Header, record: &dyn Record) -> Result<bool, io::Error> {
+        let reference_sequence_id = record.reference_sequence_id(header).transpose()?;
+        let start = record.alignment_start().transpose()?;
+        Ok(reference_sequence_id == Some(self.reference_sequence_id)
+            && start.is_some_and(|start| start <= self.end))
+    }
+}
+
+/// Read the regions of a BED file, which may be gzip or zstd compressed, skipping comments and
+/// `track` and `browser` lines. Only the first three columns are used. A zero-length record,
+/// such as an insertion site, is taken as the one base at its end, or the first base if it is
+/// at the start of its reference sequence.
+///
+/// # Returns
+///
+/// Returns the regions in 1-based, inclusive coordinates, or an `io::Error` if a line doesn't
+/// start with a reference sequence name, start and end.
+pub fn read_bed(path: &Path) -> Result<Vec<Region>, io::Error> {
+    let mut regions = Vec::new();
+    for (idx, line) in open_compressed(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "line {} of {} is not a BED region: {:?}",
+                    idx + 1,
+                    path.display(),
+                    line
+                ),
+            )
+        };
+        let mut fields = line.split('\t');
+        let (name, start, end) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(start), Some(end)) => (name, start, end),
+            _ => return Err(invalid()),
+        };
+        let start: usize = start.trim().parse().map_err(|_| invalid())?;
+        let end: usize = end.trim().parse().map_err(|_| invalid())?;
+        // BED is 0-based and half open, so the first base is start + 1 and the last is end
+        let (start, end) = match start == end {
+            true => (end.max(1), end.max(1)),
+            false => (start + 1, end),
+        };
+        let start = Position::try_from(start).map_err(|_| invalid())?;
+        let end = Position::try_from(end).map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        regions.push(Region::new(name, start..=end));
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use noodles::sam::header::record::value::{map::ReferenceSequence, Map};
+    use std::{fs, num::NonZeroUsize};
+
+    fn header() -> Header {
+        Header::builder()
+            .add_reference_sequence(
+                "sq0",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(1000).unwrap()),
+            )
+            .add_reference_sequence(
+                "sq1",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(500).unwrap()),
+            )
+            .build()
+    }
+
+    fn target(reference_sequence_id: usize, start: usize, end: usize) -> Target {
+        Target {
+            reference_sequence_id,
+            start: Position::try_from(start).unwrap(),
+            end: Position::try_from(end).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_read_bed() {
+        let path = TempPath::new("targets.bed");
+        fs::write(
+            &path,
+            "track name=panel\n# comment\nsq0\t0\t100\tgene1\nsq1\t199\t200\nsq1\t300\t300\nsq1\t0\t0\n",
+        )
+        .unwrap();
+        let regions: Vec<String> = read_bed(&path)
+            .unwrap()
+            .iter()
+            .map(|region| region.to_string())
+            .collect();
+        assert_eq!(
+            regions,
+            ["sq0:1-100", "sq1:200-200", "sq1:300-300", "sq1:1-1"]
+        );
+        fs::write(&path, "sq0\t100\n").unwrap();
+        assert!(read_bed(&path).is_err());
+        fs::write(&path, "sq0\t100\t50\n").unwrap();
+        assert!(read_bed(&path).is_err());
+    }
+
+    #[test]
+    fn test_targets() {
+        let header = header();
+        let args = |regions: &[&str]| TargetArgs {
+            region: regions
+                .iter()
+                .map(|region| region.parse().unwrap())
+                .collect(),
+            bed: None,
+        };
+        // Sorted by reference sequence, overlapping and touching regions merged
+        assert_eq!(
+            args(&[
+                "sq1:10-20",
+                "sq0:500-600",
+                "sq0:100-200",
+                "sq0:150-300",
+                "sq0:301-400"
+            ])
+            .targets(&header)
+            .unwrap(),
+            [target(0, 100, 400), target(0, 500, 600), target(1, 10, 20)]
+        );
+        // Whole reference sequences, and ends clamped to their length
+        assert_eq!(
+            args(&["sq1", "sq0:900-2000"]).targets(&header).unwrap(),
+            [target(0, 900, 1000), target(1, 1, 500)]
+        );
+        assert!(args(&["sq2:1-10"]).targets(&header).is_err());
+        assert!(args(&["sq1:600-700"]).targets(&header).is_err());
+        assert_eq!(target(1, 10, 20).region(&header).to_string(), "sq1:10-20");
+    }
+}
@@ -0,0 +1,55 @@
+//! Helpers shared by the unit tests.
+//!
+//! # Structs
+//!
+//! - [`TempPath`](struct.TempPath.html): A path in the temporary directory, removed when dropped.
+//!
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A path in the system temporary directory, unique to this process, which is removed along
+/// with anything below it when dropped, so a failed assertion doesn't leave files behind.
+#[derive(Debug)]
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// The path for `name`, e.g. `index.bam`, which should be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        TempPath(env::temp_dir().join(format!("rftools_{}_{}", process::id(), name)))
+    }
+
+    /// Another path with `suffix` added to this one, e.g. the `.bai` of a BAM.
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        let mut path = self.0.clone().into_os_string();
+        path.push(suffix);
+        TempPath(PathBuf::from(path))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
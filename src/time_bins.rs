@@ -0,0 +1,301 @@
+//! Module for placing reads in time by when they started sequencing.
+//!
+//! Start times are seconds since the start of the run in a sequencing summary, but ISO 8601
+//! timestamps in the `st` tag of a BAM record and the `start_time=` field of a FASTQ header.
+//! A [`RunClock`] turns either into seconds since the start of the run, so that reads from
+//! any input land in the same time bins.
+//!
+//! # Structs
+//!
+//! - [`TimeRange`](struct.TimeRange.html): A window of the run, in seconds since its start.
+//! - [`RunClock`](struct.RunClock.html): Converts read start times to seconds since the start of the run.
+//!
+//! # Functions
+//!
+//! - [`parse_duration`](fn.parse_duration.html): Parse a duration such as `30m` from the command line.
+//! - [`parse_time_range`](fn.parse_time_range.html): Parse a window such as `0-24h` from the command line.
+//! - [`parse_timestamp`](fn.parse_timestamp.html): Parse an ISO 8601 timestamp to seconds since the Unix epoch.
+//! - [`parse_run_start`](fn.parse_run_start.html): Parse the start of the run from the command line.
+//! - [`bin_label`](fn.bin_label.html): Label a time bin, e.g. `0-1h`.
+//!
+use std::{fmt, str};
+
+/// Seconds in each unit a duration can be given in.
+const UNITS: [(char, u64); 4] = [('d', 86_400), ('h', 3_600), ('m', 60), ('s', 1)];
+
+/// Split a trailing unit from a duration, returning the number and the seconds in the unit.
+fn split_unit(s: &str) -> (&str, Option<u64>) {
+    for (unit, seconds) in UNITS {
+        if let Some(number) = s.strip_suffix(unit) {
+            return (number, Some(seconds));
+        }
+    }
+    (s, None)
+}
+
+/// Parse a number of a unit to whole seconds.
+fn to_seconds(number: &str, unit: u64) -> Option<u64> {
+    let seconds = number.trim().parse::<f64>().ok()? * unit as f64;
+    (seconds >= 0.0 && seconds.fract() == 0.0).then_some(seconds as u64)
+}
+
+/// Parse a duration, a number followed by an optional unit of `s`, `m`, `h` or `d` (seconds if
+/// there is no unit), which must come to a whole number of seconds.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// assert_eq!(parse_duration("1.5h"), Ok(5400));
+/// ```
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_unit(s.trim());
+    to_seconds(number, unit.unwrap_or(1))
+        .ok_or_else(|| format!("expected a duration such as 30m or 1h, got {}", s))
+}
+
+/// Label the time bin `bin` of width `bin_width` seconds, in the largest unit the width is a
+/// whole number of, e.g. `0-1h` for the first hour bin.
+pub fn bin_label(bin: u64, bin_width: u64) -> String {
+    let (unit, seconds) = UNITS
+        .into_iter()
+        .find(|(_, seconds)| bin_width.is_multiple_of(*seconds))
+        .unwrap_or(('s', 1));
+    let width = bin_width / seconds;
+    format!("{}-{}{}", bin * width, (bin + 1) * width, unit)
+}
+
+/// A window of the run, from `start` up to but not including `end`, in seconds since the start
+/// of the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    /// Start of the window.
+    pub start: u64,
+    /// End of the window, `None` for the end of the run.
+    pub end: Option<u64>,
+}
+
+impl TimeRange {
+    /// Whether a time is in the window.
+    pub fn contains(&self, seconds: f64) -> bool {
+        seconds >= self.start as f64 && self.end.is_none_or(|end| seconds < end as f64)
+    }
+}
+
+impl fmt::Display for TimeRange {
+    /// The window in seconds, as it can be given on the command line, e.g. `0-86400` or `43200-`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-", self.start)?;
+        match self.end {
+            Some(end) => write!(f, "{}", end),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse a window of the run as `START-END`, where both are durations and the unit of `END` is
+/// also used for `START` if it has none, e.g. `0-24h` or `30m-2h`. `END` can be left out for
+/// the rest of the run, e.g. `12h-`.
+pub fn parse_time_range(s: &str) -> Result<TimeRange, String> {
+    let invalid = || format!("expected a time range such as 0-24h or 12h-, got {}", s);
+    let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+    let (end, end_unit) = split_unit(end.trim());
+    let end_unit = end_unit.unwrap_or(1);
+    let (start, start_unit) = split_unit(start.trim());
+    let start = to_seconds(start, start_unit.unwrap_or(end_unit)).ok_or_else(invalid)?;
+    let end = match end {
+        "" => None,
+        end => Some(to_seconds(end, end_unit).ok_or_else(invalid)?),
+    };
+    if end.is_some_and(|end| end <= start) {
+        return Err(format!(
+            "the end of time range {} is not after its start",
+            s
+        ));
+    }
+    Ok(TimeRange { start, end })
+}
+
+/// Parse a number from part of a timestamp.
+fn number<T: str::FromStr>(s: &str, range: std::ops::Range<usize>) -> Option<T> {
+    s.get(range)?.parse().ok()
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parse an ISO 8601 timestamp, as written by MinKNOW and dorado, to seconds since the Unix
+/// epoch, e.g. `2024-01-01T10:00:10.500+00:00`. A timestamp without an offset is taken to be UTC.
+pub fn parse_timestamp(timestamp: &[u8]) -> Option<f64> {
+    let s = str::from_utf8(timestamp).ok()?;
+    // The parts are found by byte offset, which may not be a character boundary otherwise
+    if !s.is_ascii() {
+        return None;
+    }
+    let (date, time) = s.split_once(['T', ' '])?;
+    if date.len() != 10 || &date[4..5] != "-" || &date[7..8] != "-" {
+        return None;
+    }
+    let days = days_from_civil(
+        number(date, 0..4)?,
+        number(date, 5..7)?,
+        number(date, 8..10)?,
+    );
+    // Split the offset from the time of day, at a Z or a sign after the seconds
+    let (time, offset) = match time.find(['Z', '+', '-']) {
+        Some(idx) => time.split_at(idx),
+        None => (time, ""),
+    };
+    if time.len() < 8 || &time[2..3] != ":" || &time[5..6] != ":" {
+        return None;
+    }
+    let hours: u32 = number(time, 0..2)?;
+    let minutes: u32 = number(time, 3..5)?;
+    let seconds: f64 = time[6..].parse().ok()?;
+    let offset = match offset {
+        "" | "Z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (offset_hours, offset_minutes) = offset[1..].split_once(':')?;
+            let offset_hours: i64 = offset_hours.parse().ok()?;
+            let offset_minutes: i64 = offset_minutes.parse().ok()?;
+            sign * (offset_hours * 3_600 + offset_minutes * 60)
+        }
+    };
+    let whole = days * 86_400 + i64::from(hours) * 3_600 + i64::from(minutes) * 60 - offset;
+    Some(whole as f64 + seconds)
+}
+
+/// Parse the start of the run, an ISO 8601 timestamp, from the command line.
+pub fn parse_run_start(s: &str) -> Result<f64, String> {
+    parse_timestamp(s.as_bytes()).ok_or_else(|| {
+        format!(
+            "expected a timestamp such as 2024-01-01T10:00:00Z, got {}",
+            s
+        )
+    })
+}
+
+/// Converts the start time of a read to seconds since the start of the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunClock {
+    /// Start of the run, in seconds since the Unix epoch, needed for timestamps.
+    run_start: Option<f64>,
+}
+
+impl RunClock {
+    /// A clock for a run which started at `run_start`, in seconds since the Unix epoch.
+    pub fn new(run_start: Option<f64>) -> Self {
+        RunClock { run_start }
+    }
+
+    /// Seconds since the start of the run of a start time, which is either already in seconds
+    /// since the start of the run, or a timestamp. `None` if it is neither, or is a timestamp
+    /// and the start of the run is not known.
+    pub fn elapsed(&self, start_time: &[u8]) -> Option<f64> {
+        if let Some(seconds) = str::from_utf8(start_time)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+        {
+            return Some(seconds);
+        }
+        Some(parse_timestamp(start_time)? - self.run_start?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1_800));
+        assert_eq!(parse_duration("1.5h"), Ok(5_400));
+        assert_eq!(parse_duration("1d"), Ok(86_400));
+        assert!(parse_duration("0.5s").is_err());
+        assert!(parse_duration("-1h").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_bin_label() {
+        assert_eq!(bin_label(0, 3_600), "0-1h");
+        assert_eq!(bin_label(3, 1_800), "90-120m");
+        assert_eq!(bin_label(1, 90), "90-180s");
+        assert_eq!(bin_label(1, 172_800), "2-4d");
+    }
+
+    #[test]
+    fn test_parse_time_range() {
+        assert_eq!(
+            parse_time_range("0-24h"),
+            Ok(TimeRange {
+                start: 0,
+                end: Some(86_400)
+            })
+        );
+        assert_eq!(
+            parse_time_range("30m-2h"),
+            Ok(TimeRange {
+                start: 1_800,
+                end: Some(7_200)
+            })
+        );
+        let rest = parse_time_range("12h-").unwrap();
+        assert_eq!(
+            rest,
+            TimeRange {
+                start: 43_200,
+                end: None
+            }
+        );
+        assert_eq!(rest.to_string(), "43200-");
+        assert_eq!(
+            parse_time_range(&parse_time_range("30m-2h").unwrap().to_string()),
+            parse_time_range("30m-2h")
+        );
+        assert!(rest.contains(43_200.0));
+        assert!(!rest.contains(43_199.5));
+        assert!(!parse_time_range("0-1h").unwrap().contains(3_600.0));
+        assert!(parse_time_range("2h-1h").is_err());
+        assert!(parse_time_range("1h").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp(b"1970-01-01T00:00:00Z"), Some(0.0));
+        assert_eq!(
+            parse_timestamp(b"2024-01-01T10:00:10.500+00:00"),
+            Some(1_704_103_210.5)
+        );
+        assert_eq!(
+            parse_timestamp(b"2024-01-01T11:00:10.500+01:00"),
+            Some(1_704_103_210.5)
+        );
+        assert_eq!(
+            parse_timestamp(b"2024-03-01T00:00:00"),
+            Some(1_709_251_200.0)
+        );
+        assert_eq!(parse_timestamp(b"2024-01-01"), None);
+        assert_eq!(parse_timestamp(b"10.5"), None);
+        assert_eq!(parse_timestamp("202é01-01T00:00:00Z".as_bytes()), None);
+        assert_eq!(parse_timestamp("2024-01-01T0é:00:00Z".as_bytes()), None);
+        assert_eq!(parse_timestamp("2024-01-01T00:00:0é".as_bytes()), None);
+    }
+
+    #[test]
+    fn test_run_clock() {
+        let clock = RunClock::new(Some(parse_run_start("2024-01-01T10:00:00Z").unwrap()));
+        assert_eq!(clock.elapsed(b"10.5"), Some(10.5));
+        assert_eq!(clock.elapsed(b"2024-01-01T10:00:30.750+00:00"), Some(30.75));
+        assert_eq!(clock.elapsed(b"unknown"), None);
+        assert_eq!(RunClock::new(None).elapsed(b"2024-01-01T10:00:30Z"), None);
+    }
+}